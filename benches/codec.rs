@@ -0,0 +1,18 @@
+// encode and decode throughput of qoilib and qoi+ against the qoi crate and
+// png, on the synthetic images of `qoi bench`. Run with `cargo bench`.
+
+use qoi_viwer::bench::{measure, table_header, table_row, Codec, Synthetic};
+
+const SIZE: u32 = 1024;
+const ITERATIONS: usize = 15;
+
+fn main() {
+    println!("{}", table_header());
+    for synthetic in Synthetic::ALL {
+        let img = synthetic.generate(SIZE, SIZE);
+        for codec in Codec::ALL {
+            let m = measure(codec, &img, ITERATIONS).unwrap();
+            println!("{}", table_row(synthetic.name(), &m));
+        }
+    }
+}
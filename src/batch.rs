@@ -0,0 +1,343 @@
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::convert::{convert_bytes, convert_file};
+
+// remembers the source hashes of the last run, lives in the output dir
+pub const HASH_MANIFEST: &str = ".qoi-batch-hashes";
+
+// how to decide an existing output does not need converting again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipMode {
+    // always convert
+    Never,
+    // the output is newer than its source
+    Mtime,
+    // the source hash matches the one recorded by the last run
+    Hash,
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    // lowercase extensions of the files to convert, without the dot
+    pub extensions: Vec<String>,
+    pub skip: SkipMode,
+    // worker threads, rayon's default when None
+    pub jobs: Option<usize>,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            extensions: vec!["png".to_owned()],
+            skip: SkipMode::Mtime,
+            jobs: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Converted { input_bytes: u64, output_bytes: u64 },
+    Skipped,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct FileResult {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BatchSummary {
+    pub results: Vec<FileResult>,
+}
+
+impl BatchSummary {
+    pub fn converted(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|r| matches!(r.outcome, Outcome::Converted { .. }))
+            .count()
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|r| r.outcome == Outcome::Skipped)
+            .count()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &FileResult> {
+        self.results
+            .iter()
+            .filter(|r| matches!(r.outcome, Outcome::Failed(_)))
+    }
+
+    // total source and qoi sizes of the converted files
+    pub fn bytes(&self) -> (u64, u64) {
+        self.results
+            .iter()
+            .fold((0, 0), |(input, output), r| match r.outcome {
+                Outcome::Converted {
+                    input_bytes,
+                    output_bytes,
+                } => (input + input_bytes, output + output_bytes),
+                _ => (input, output),
+            })
+    }
+}
+
+// convert every matching file below `input` into the same place below
+// `output`, with the extension replaced by qoi
+pub fn convert_dir(
+    input: &Path,
+    output: &Path,
+    options: &BatchOptions,
+) -> std::io::Result<BatchSummary> {
+    let mut files = Vec::new();
+    collect_files(input, &options.extensions, &mut files)?;
+    files.sort();
+
+    // a.png and a.PNG, or a.png and a.jpg, would both be written to a.qoi
+    // by different threads, so sources sharing an output are not converted
+    let mut targets: HashMap<PathBuf, Vec<&PathBuf>> = HashMap::new();
+    for src in &files {
+        targets
+            .entry(output_path(input, output, src))
+            .or_default()
+            .push(src);
+    }
+    let jobs: Vec<Job> = files
+        .iter()
+        .map(|src| {
+            let dst = output_path(input, output, src);
+            let sources = &targets[&dst];
+            let clash = (sources.len() > 1).then(|| {
+                let names: Vec<_> = sources.iter().map(|p| p.display().to_string()).collect();
+                format!("{} all convert to {}", names.join(", "), dst.display())
+            });
+            let key = src.strip_prefix(input).unwrap_or(src);
+            Job {
+                key: key.to_string_lossy().into_owned(),
+                src: src.clone(),
+                dst,
+                clash,
+            }
+        })
+        .collect();
+
+    // entries of sources that are gone or clash are dropped, so the
+    // manifest does not only grow and a clash is redone once resolved
+    let manifest = if options.skip == SkipMode::Hash {
+        let mut manifest = read_manifest(&output.join(HASH_MANIFEST));
+        manifest.retain(|key, _| jobs.iter().any(|j| &j.key == key && j.clash.is_none()));
+        manifest
+    } else {
+        HashMap::new()
+    };
+
+    let work = |jobs: &[Job]| -> Vec<(FileResult, Option<(String, u64)>)> {
+        jobs.par_iter()
+            .map(|job| {
+                let (outcome, hash) = match &job.clash {
+                    Some(e) => (Outcome::Failed(e.clone()), None),
+                    None => convert_one(&job.src, &job.dst, options.skip, manifest.get(&job.key)),
+                };
+                let result = FileResult {
+                    input: job.src.clone(),
+                    output: job.dst.clone(),
+                    outcome,
+                };
+                (result, hash.map(|h| (job.key.clone(), h)))
+            })
+            .collect()
+    };
+
+    let done = match options.jobs {
+        Some(threads) => rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|e| std::io::Error::other(e.to_string()))?
+            .install(|| work(&jobs)),
+        None => work(&jobs),
+    };
+
+    let mut summary = BatchSummary::default();
+    let mut hashes = manifest;
+    for (result, hash) in done {
+        if let Some((key, hash)) = hash {
+            hashes.insert(key, hash);
+        }
+        summary.results.push(result);
+    }
+
+    if options.skip == SkipMode::Hash {
+        fs::create_dir_all(output)?;
+        write_manifest(&output.join(HASH_MANIFEST), &hashes)?;
+    }
+    Ok(summary)
+}
+
+// a file to convert, `key` is its path relative to the input dir
+struct Job {
+    key: String,
+    src: PathBuf,
+    dst: PathBuf,
+    // why it is not converted when another source has the same output
+    clash: Option<String>,
+}
+
+fn output_path(input: &Path, output: &Path, src: &Path) -> PathBuf {
+    output
+        .join(src.strip_prefix(input).unwrap_or(src))
+        .with_extension("qoi")
+}
+
+// the outcome, plus the source hash when it has to be recorded
+fn convert_one(
+    src: &Path,
+    dst: &Path,
+    skip: SkipMode,
+    recorded: Option<&u64>,
+) -> (Outcome, Option<u64>) {
+    let input_bytes = match fs::metadata(src) {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return (Outcome::Failed(e.to_string()), None),
+    };
+    // only hashing needs the contents up front, they are kept for the
+    // conversion so the file is read once
+    let data = match skip {
+        SkipMode::Hash => match fs::read(src) {
+            Ok(data) => Some(data),
+            Err(e) => return (Outcome::Failed(e.to_string()), None),
+        },
+        _ => None,
+    };
+    let hash = data.as_deref().map(fnv1a);
+    let up_to_date = dst.exists()
+        && match skip {
+            SkipMode::Never => false,
+            SkipMode::Mtime => is_newer(dst, src),
+            SkipMode::Hash => hash.as_ref() == recorded,
+        };
+    if up_to_date {
+        return (Outcome::Skipped, hash);
+    }
+
+    let converted = match data {
+        Some(data) => convert_bytes(src, data, dst),
+        None => convert_file(src, dst),
+    };
+    match converted {
+        Ok(output_bytes) => (
+            Outcome::Converted {
+                input_bytes,
+                output_bytes,
+            },
+            hash,
+        ),
+        Err(e) => (Outcome::Failed(e), None),
+    }
+}
+
+fn collect_files(
+    dir: &Path,
+    extensions: &[String],
+    files: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, extensions, files)?;
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| extensions.iter().any(|x| x.eq_ignore_ascii_case(e)))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_newer(a: &Path, b: &Path) -> bool {
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    match (modified(a), modified(b)) {
+        (Some(a), Some(b)) => a >= b,
+        _ => false,
+    }
+}
+
+// a stable hash, std's DefaultHasher may change between releases
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// one `<hash> <relative path>` per line
+fn read_manifest(path: &Path) -> HashMap<String, u64> {
+    let Ok(text) = fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+    text.lines()
+        .filter_map(|line| {
+            let (hash, rel) = line.split_once(' ')?;
+            Some((rel.to_owned(), u64::from_str_radix(hash, 16).ok()?))
+        })
+        .collect()
+}
+
+fn write_manifest(path: &Path, hashes: &HashMap<String, u64>) -> std::io::Result<()> {
+    let mut lines: Vec<_> = hashes.iter().collect();
+    lines.sort();
+    let mut out = BufWriter::new(File::create(path)?);
+    for (rel, hash) in lines {
+        writeln!(out, "{:016x} {}", hash, rel)?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qoilib::test_util::TempDir;
+
+    fn write_png(path: &Path) {
+        image::RgbImage::from_pixel(2, 2, image::Rgb([10, 20, 30]))
+            .save_with_format(path, image::ImageFormat::Png)
+            .unwrap();
+    }
+
+    #[test]
+    fn sources_with_the_same_output_fail_instead_of_racing() {
+        let root = TempDir::new("batch-clash");
+        let (input, output) = (root.join("in"), root.join("out"));
+        fs::create_dir_all(&input).unwrap();
+        for name in ["a.png", "a.PNG", "b.png", "gone.png"] {
+            write_png(&input.join(name));
+        }
+        let options = BatchOptions {
+            skip: SkipMode::Hash,
+            ..BatchOptions::default()
+        };
+        convert_dir(&input, &output, &options).unwrap();
+        fs::remove_file(input.join("gone.png")).unwrap();
+
+        let summary = convert_dir(&input, &output, &options).unwrap();
+        let failed: Vec<_> = summary.failures().map(|r| r.input.clone()).collect();
+        assert_eq!(failed, [input.join("a.PNG"), input.join("a.png")]);
+        assert!(!output.join("a.qoi").exists());
+        assert_eq!(summary.skipped(), 1);
+
+        let manifest = read_manifest(&output.join(HASH_MANIFEST));
+        let keys: Vec<_> = manifest.keys().collect();
+        assert_eq!(keys, ["b.png"]);
+    }
+}
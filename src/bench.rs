@@ -0,0 +1,294 @@
+use image::codecs::png::PngEncoder;
+use image::{ColorType, ImageEncoder, ImageFormat};
+use std::hint::black_box;
+use std::io::BufWriter;
+use std::time::{Duration, Instant};
+
+use crate::convert::LoadedImage;
+use crate::qoilib::decoder::decode_to_vec;
+use crate::qoilib::encoder::Encoder;
+use crate::qoilib::header::{qoi_channels, QoiColorspace};
+use crate::qoilib::plus::{self, best_filters, encoded_size, Filters};
+
+// the codecs a measurement can run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Qoilib,
+    // the `qoi` crate, as a reference implementation
+    QoiCrate,
+    // PNG through the image crate
+    Png,
+    // the experimental qoi+ container, with the filters that suit the image
+    // best, picked before the timings start
+    QoiPlus,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 4] = [Codec::Qoilib, Codec::QoiCrate, Codec::Png, Codec::QoiPlus];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Qoilib => "qoilib",
+            Codec::QoiPlus => "qoi+",
+            Codec::QoiCrate => "qoi",
+            Codec::Png => "png",
+        }
+    }
+}
+
+// the synthetic inputs used when no files are given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synthetic {
+    // smooth colour ramps, mostly DIFF and LUMA
+    Gradient,
+    // flat tiles, mostly RUN and INDEX
+    Tiles,
+    // random bytes, mostly RGB
+    Noise,
+}
+
+impl Synthetic {
+    pub const ALL: [Synthetic; 3] = [Synthetic::Gradient, Synthetic::Tiles, Synthetic::Noise];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Synthetic::Gradient => "gradient",
+            Synthetic::Tiles => "tiles",
+            Synthetic::Noise => "noise",
+        }
+    }
+
+    pub fn generate(&self, width: u32, height: u32) -> LoadedImage {
+        let mut seed: u32 = 0x9e37_79b9;
+        let mut pixels = Vec::with_capacity(width as usize * height as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                let px = match self {
+                    Synthetic::Gradient => [
+                        (x * 255 / width.max(1)) as u8,
+                        (y * 255 / height.max(1)) as u8,
+                        ((x + y) / 4) as u8,
+                        255,
+                    ],
+                    Synthetic::Tiles => {
+                        let tile = (x / 32) ^ (y / 32);
+                        [(tile * 40) as u8, (tile * 90) as u8, (tile * 20) as u8, 255]
+                    }
+                    Synthetic::Noise => {
+                        // xorshift32, deterministic between runs
+                        seed ^= seed << 13;
+                        seed ^= seed >> 17;
+                        seed ^= seed << 5;
+                        let [r, g, b, _] = seed.to_le_bytes();
+                        [r, g, b, 255]
+                    }
+                };
+                pixels.push(px);
+            }
+        }
+
+        LoadedImage {
+            pixels,
+            width,
+            height,
+            channels: qoi_channels::Rgb,
+            colorspace: QoiColorspace::Srgb,
+        }
+    }
+}
+
+// median times of one codec on one input
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub codec: Codec,
+    pub pixels: u64,
+    // size of the pixels in the channels the image has
+    pub raw_bytes: u64,
+    pub compressed_bytes: u64,
+    pub encode: Duration,
+    pub decode: Duration,
+}
+
+impl Measurement {
+    pub fn encode_mb_per_sec(&self) -> f64 {
+        per_sec(self.raw_bytes, self.encode) / 1e6
+    }
+
+    pub fn decode_mb_per_sec(&self) -> f64 {
+        per_sec(self.raw_bytes, self.decode) / 1e6
+    }
+
+    pub fn encode_mpixels_per_sec(&self) -> f64 {
+        per_sec(self.pixels, self.encode) / 1e6
+    }
+
+    pub fn decode_mpixels_per_sec(&self) -> f64 {
+        per_sec(self.pixels, self.decode) / 1e6
+    }
+}
+
+// run `f` once to warm up, then `iterations` times, and take the median
+pub fn time<F: FnMut()>(iterations: usize, mut f: F) -> Duration {
+    f();
+    let mut samples: Vec<Duration> = (0..iterations.max(1))
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect();
+    samples.sort();
+    samples[samples.len() / 2]
+}
+
+// encode and decode the image with `codec`, each timed on its own
+pub fn measure(codec: Codec, img: &LoadedImage, iterations: usize) -> Result<Measurement, String> {
+    let channels = img.channels.to_bytes() as usize;
+    // the layout the other codecs take, prepared outside of the timings
+    let raw: Vec<u8> = img
+        .pixels
+        .iter()
+        .flat_map(|px| px[..channels].iter().copied())
+        .collect();
+    let color = match img.channels {
+        qoi_channels::Rgb => ColorType::Rgb8,
+        qoi_channels::Rgba => ColorType::Rgba8,
+    };
+    let filters = match codec {
+        Codec::QoiPlus => {
+            best_filters(
+                &img.pixels,
+                img.width,
+                img.height,
+                img.channels,
+                Filters::all(),
+            )
+            .map_err(|_| "failed to encode".to_owned())?
+            .0
+        }
+        _ => Filters::default(),
+    };
+
+    let encode_once = || -> Result<Vec<u8>, String> {
+        match codec {
+            Codec::Qoilib => {
+                let mut buffer = BufWriter::new(Vec::new());
+                Encoder::new(
+                    &img.pixels,
+                    img.width,
+                    img.height,
+                    img.channels,
+                    img.colorspace,
+                )
+                .encode_to_buffer(&mut buffer)
+                .map_err(|_| "failed to encode".to_owned())?;
+                buffer.into_inner().map_err(|e| e.to_string())
+            }
+            Codec::QoiCrate => {
+                qoi::encode_to_vec(&raw, img.width, img.height).map_err(|e| e.to_string())
+            }
+            Codec::QoiPlus => plus::encode_to_vec(
+                &img.pixels,
+                img.width,
+                img.height,
+                img.channels,
+                img.colorspace,
+                filters,
+            )
+            .map_err(|e| e.to_string()),
+            Codec::Png => {
+                let mut out = Vec::new();
+                PngEncoder::new(&mut out)
+                    .write_image(&raw, img.width, img.height, color)
+                    .map_err(|e| e.to_string())?;
+                Ok(out)
+            }
+        }
+    };
+    let decode_once = |data: &[u8]| -> Result<(), String> {
+        match codec {
+            Codec::Qoilib => {
+                black_box(
+                    decode_to_vec(data, img.channels).map_err(|_| "failed to decode".to_owned())?,
+                );
+            }
+            Codec::QoiCrate => {
+                black_box(qoi::decode_to_vec(data).map_err(|e| e.to_string())?);
+            }
+            Codec::QoiPlus => {
+                black_box(
+                    plus::decode_to_vec(data, img.channels)
+                        .map_err(|_| "failed to decode".to_owned())?,
+                );
+            }
+            Codec::Png => {
+                black_box(
+                    image::load_from_memory_with_format(data, ImageFormat::Png)
+                        .map_err(|e| e.to_string())?,
+                );
+            }
+        }
+        Ok(())
+    };
+
+    // fail before timing anything
+    let encoded = encode_once()?;
+    decode_once(&encoded)?;
+
+    let encode = time(iterations, || {
+        black_box(encode_once().unwrap());
+    });
+    let decode = time(iterations, || decode_once(black_box(&encoded)).unwrap());
+
+    Ok(Measurement {
+        codec,
+        pixels: img.pixels.len() as u64,
+        raw_bytes: raw.len() as u64,
+        compressed_bytes: encoded.len() as u64,
+        encode,
+        decode,
+    })
+}
+
+// the qoi+ size of the image under every combination of filters, in
+// Filters::all() order, the first being plain qoi plus the container header
+pub fn filter_sizes(img: &LoadedImage) -> Result<Vec<(Filters, u64)>, String> {
+    Filters::all()
+        .map(|filters| {
+            encoded_size(&img.pixels, img.width, img.height, img.channels, filters)
+                .map(|size| (filters, size))
+                .map_err(|_| "failed to encode".to_owned())
+        })
+        .collect()
+}
+
+fn per_sec(amount: u64, duration: Duration) -> f64 {
+    let secs = duration.as_secs_f64();
+    if secs == 0.0 {
+        return f64::INFINITY;
+    }
+    amount as f64 / secs
+}
+
+// column titles matching table_row
+pub fn table_header() -> String {
+    format!(
+        "{:<24}  {:<6}  {:>12}  {:>7}  {:>10}  {:>10}  {:>10}  {:>10}",
+        "input", "codec", "size", "ratio", "enc MB/s", "enc MP/s", "dec MB/s", "dec MP/s"
+    )
+}
+
+pub fn table_row(input: &str, m: &Measurement) -> String {
+    format!(
+        "{:<24}  {:<6}  {:>12}  {:>6.1}%  {:>10.1}  {:>10.1}  {:>10.1}  {:>10.1}",
+        input,
+        m.codec.name(),
+        m.compressed_bytes,
+        m.compressed_bytes as f64 * 100.0 / m.raw_bytes.max(1) as f64,
+        m.encode_mb_per_sec(),
+        m.encode_mpixels_per_sec(),
+        m.decode_mb_per_sec(),
+        m.decode_mpixels_per_sec()
+    )
+}
@@ -0,0 +1,61 @@
+use std::path::Path;
+
+use qoi_viwer::batch::{convert_dir, BatchOptions, Outcome, SkipMode};
+
+use super::{Args, CliResult};
+
+pub fn run(args: &Args) -> CliResult {
+    let input = args.required(0, "input directory")?;
+    let output = args.required(1, "output directory")?;
+
+    let mut options = BatchOptions::default();
+    if let Some(ext) = args.value("ext") {
+        options.extensions = ext
+            .split(',')
+            .map(|e| e.trim_start_matches('.').to_lowercase())
+            .collect();
+    }
+    options.skip = match args.value("skip") {
+        None | Some("mtime") => SkipMode::Mtime,
+        Some("hash") => SkipMode::Hash,
+        Some("none") => SkipMode::Never,
+        Some(other) => {
+            return Err(format!(
+                "unknown skip mode `{}`, expected mtime, hash or none",
+                other
+            )
+            .into())
+        }
+    };
+    if let Some(jobs) = args.value("jobs") {
+        options.jobs = Some(jobs.parse().map_err(|_| "--jobs must be a number")?);
+    }
+
+    let summary = convert_dir(Path::new(input), Path::new(output), &options)?;
+
+    for failure in summary.failures() {
+        if let Outcome::Failed(e) = &failure.outcome {
+            eprintln!("failed  {}: {}", failure.input.display(), e);
+        }
+    }
+    let (input_bytes, output_bytes) = summary.bytes();
+    println!(
+        "converted {}, skipped {}, failed {}",
+        summary.converted(),
+        summary.skipped(),
+        summary.failures().count()
+    );
+    if summary.converted() > 0 {
+        println!(
+            "{} bytes -> {} bytes ({:.1}%)",
+            input_bytes,
+            output_bytes,
+            output_bytes as f64 * 100.0 / input_bytes.max(1) as f64
+        );
+    }
+
+    if summary.failures().count() > 0 {
+        return Err(format!("{} files failed to convert", summary.failures().count()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use qoi_viwer::bench::{filter_sizes, measure, table_header, table_row, Codec, Synthetic};
+use qoi_viwer::convert::{load_image, LoadedImage};
+
+use super::{Args, CliResult};
+
+pub fn run(args: &Args) -> CliResult {
+    let iterations = match args.value("iterations") {
+        Some(n) => n.parse().map_err(|_| "--iterations must be a number")?,
+        None => 10,
+    };
+    let codecs = match args.value("codec") {
+        None | Some("all") => Codec::ALL.to_vec(),
+        Some(names) => names
+            .split(',')
+            .map(|name| {
+                Codec::ALL
+                    .into_iter()
+                    .find(|c| c.name() == name)
+                    .ok_or_else(|| {
+                        format!(
+                            "unknown codec `{}`, expected qoilib, qoi, png or qoi+",
+                            name
+                        )
+                    })
+            })
+            .collect::<Result<_, _>>()?,
+    };
+
+    // the given files, or synthetic images when there are none
+    let mut inputs: Vec<(String, LoadedImage)> = Vec::new();
+    for path in args.positional() {
+        let img = load_image(path).map_err(|e| format!("{}: {}", path, e))?;
+        inputs.push((path.clone(), img));
+    }
+    if inputs.is_empty() {
+        let (width, height) = match args.value("size") {
+            Some(size) => size
+                .split_once('x')
+                .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                .ok_or("--size must look like 1024x1024")?,
+            None => (1024, 1024),
+        };
+        for synthetic in Synthetic::ALL {
+            inputs.push((
+                synthetic.name().to_owned(),
+                synthetic.generate(width, height),
+            ));
+        }
+    }
+
+    // sizes only, one row per combination of qoi+ filters
+    if args.flag("filters") {
+        println!(
+            "{:<24}  {:<14}  {:<8}  {:>12}  {:>8}",
+            "input", "color", "predictor", "size", "vs qoi"
+        );
+        for (name, img) in &inputs {
+            let sizes = filter_sizes(img).map_err(|e| format!("{}: {}", name, e))?;
+            let plain = sizes.first().map_or(1, |(_, size)| *size).max(1);
+            for (filters, size) in &sizes {
+                println!(
+                    "{:<24}  {:<14}  {:<8}  {:>12}  {:>7.1}%",
+                    name,
+                    filters.color.name(),
+                    filters.predictor.name(),
+                    size,
+                    *size as f64 * 100.0 / plain as f64
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    println!("{}", table_header());
+    for (name, img) in &inputs {
+        for codec in &codecs {
+            let m = measure(*codec, img, iterations).map_err(|e| format!("{}: {}", name, e))?;
+            println!("{}", table_row(name, &m));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,10 @@
+use qoi_viwer::convert::decode_file;
+
+use super::{Args, CliResult};
+
+pub fn run(args: &Args) -> CliResult {
+    let input = args.required(0, "input file")?;
+    let output = args.required(1, "output file")?;
+    decode_file(input, output).map_err(|e| format!("{}: {}", input, e))?;
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use qoi_viwer::convert::load_image;
+use qoi_viwer::diff::{compare, diff_image};
+
+use super::{Args, CliResult};
+
+pub fn run(args: &Args) -> CliResult {
+    let path_a = args.required(0, "first image")?;
+    let path_b = args.required(1, "second image")?;
+    let a = load_image(path_a).map_err(|e| format!("{}: {}", path_a, e))?;
+    let b = load_image(path_b).map_err(|e| format!("{}: {}", path_b, e))?;
+
+    if (a.width, a.height) != (b.width, b.height) {
+        return Err(format!(
+            "images differ in size: {}x{} and {}x{}",
+            a.width, a.height, b.width, b.height
+        )
+        .into());
+    }
+
+    let report = compare(&a.pixels, &b.pixels, a.width, a.height)?;
+    if let Some(out) = args.value("out") {
+        diff_image(&a.pixels, &b.pixels, a.width, a.height)?.save(out)?;
+    }
+
+    let total = u64::from(a.width) * u64::from(a.height);
+    println!(
+        "differing pixels  {} of {} ({:.3}%)",
+        report.differing,
+        total,
+        report.differing as f64 * 100.0 / total.max(1) as f64
+    );
+    let [r, g, b, alpha] = report.max_delta;
+    println!("max delta         r={} g={} b={} a={}", r, g, b, alpha);
+    match report.bounds {
+        Some((x, y, w, h)) => println!("changed region    {}x{} at ({},{})", w, h, x, y),
+        None => println!("changed region    none"),
+    }
+    println!("psnr              {:.3} dB", report.psnr);
+
+    if !report.is_identical() {
+        return Err("images differ".into());
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+use qoi_viwer::qoilib::disasm::{Chunk, Disassembler, EndMarker};
+use qoi_viwer::qoilib::header::qoi_header;
+use qoi_viwer::qoilib::op::QoiOp;
+
+use super::{Args, CliResult};
+
+pub fn run(args: &Args) -> CliResult {
+    let path = args.required(0, "input file")?;
+    let json = match args.value("format") {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(other) => {
+            return Err(format!("unknown format `{}`, expected text or json", other).into())
+        }
+    };
+
+    let mut disasm = Disassembler::new(BufReader::new(File::open(path)?))
+        .map_err(|_| format!("{}: not a valid qoi header", path))?;
+    let total = disasm.total_pixels();
+    let mut out = BufWriter::new(std::io::stdout().lock());
+
+    write_header(&mut out, disasm.header(), json)?;
+    loop {
+        let offset = disasm.offset();
+        match disasm.next_chunk() {
+            Ok(Some(chunk)) => write_chunk(&mut out, &chunk, total, json)?,
+            Ok(None) => break,
+            Err(_) => {
+                out.flush()?;
+                return Err(
+                    format!("{}: unexpected end of data at offset {}", path, offset).into(),
+                );
+            }
+        }
+    }
+    let end = disasm
+        .end_marker()
+        .map_err(|_| format!("{}: failed to read the end marker", path))?;
+    write_end(&mut out, &end, json)?;
+    out.flush()?;
+
+    if !end.is_valid() {
+        return Err(format!("{}: invalid end marker at offset {}", path, end.offset).into());
+    }
+    Ok(())
+}
+
+fn write_header<W: Write>(out: &mut W, header: &qoi_header, json: bool) -> std::io::Result<()> {
+    if json {
+        writeln!(
+            out,
+            "{{\"magic\":\"qoif\",\"width\":{},\"height\":{},\"channels\":{},\"colorspace\":{}}}",
+            header.width,
+            header.height,
+            header.channels().to_bytes(),
+            header.colorspace().to_bytes()
+        )
+    } else {
+        writeln!(
+            out,
+            "qoif {}x{} channels={} colorspace={}",
+            header.width,
+            header.height,
+            header.channels().to_bytes(),
+            header.colorspace().to_bytes()
+        )?;
+        writeln!(
+            out,
+            "{:<10}  {:<5}  {:<22}  {:>10}  {:>11}  color",
+            "offset", "op", "operands", "pixel", "x,y"
+        )
+    }
+}
+
+fn write_chunk<W: Write>(
+    out: &mut W,
+    chunk: &Chunk,
+    total: u64,
+    json: bool,
+) -> std::io::Result<()> {
+    let c = chunk.color;
+    // a run may claim more pixels than the image has left
+    let overrun = (chunk.pixel + u64::from(chunk.op.pixels())).saturating_sub(total);
+
+    if json {
+        let operands = match chunk.op {
+            QoiOp::Rgb { r, g, b } => format!("\"r\":{},\"g\":{},\"b\":{}", r, g, b),
+            QoiOp::Rgba { r, g, b, a } => {
+                format!("\"r\":{},\"g\":{},\"b\":{},\"a\":{}", r, g, b, a)
+            }
+            QoiOp::Index(index) => format!("\"index\":{}", index),
+            QoiOp::Diff { .. } | QoiOp::Luma { .. } => {
+                let (dr, dg, db) = chunk.op.deltas().unwrap();
+                format!("\"dr\":{},\"dg\":{},\"db\":{}", dr, dg, db)
+            }
+            QoiOp::Run(run) => format!("\"run\":{}", run),
+        };
+        write!(
+            out,
+            "{{\"offset\":{},\"op\":\"{}\",{},\"pixel\":{},\"x\":{},\"y\":{},\"color\":[{},{},{},{}]",
+            chunk.offset,
+            chunk.op.name(),
+            operands,
+            chunk.pixel,
+            chunk.x,
+            chunk.y,
+            c.r,
+            c.g,
+            c.b,
+            c.a
+        )?;
+        if overrun > 0 {
+            write!(out, ",\"overrun\":{}", overrun)?;
+        }
+        writeln!(out, "}}")
+    } else {
+        let operands = match chunk.op {
+            QoiOp::Rgb { r, g, b } => format!("r={} g={} b={}", r, g, b),
+            QoiOp::Rgba { r, g, b, a } => format!("r={} g={} b={} a={}", r, g, b, a),
+            QoiOp::Index(index) => format!("index={}", index),
+            QoiOp::Diff { .. } | QoiOp::Luma { .. } => {
+                let (dr, dg, db) = chunk.op.deltas().unwrap();
+                format!("dr={} dg={} db={}", dr, dg, db)
+            }
+            QoiOp::Run(run) => format!("run={}", run),
+        };
+        write!(
+            out,
+            "{:#010x}  {:<5}  {:<22}  {:>10}  {:>11}  #{:02x}{:02x}{:02x}{:02x}",
+            chunk.offset,
+            chunk.op.name(),
+            operands,
+            chunk.pixel,
+            format!("{},{}", chunk.x, chunk.y),
+            c.r,
+            c.g,
+            c.b,
+            c.a
+        )?;
+        if overrun > 0 {
+            write!(out, "  overruns the image by {} pixels", overrun)?;
+        }
+        writeln!(out)
+    }
+}
+
+fn write_end<W: Write>(out: &mut W, end: &EndMarker, json: bool) -> std::io::Result<()> {
+    let bytes: Vec<String> = end.bytes.iter().map(|b| b.to_string()).collect();
+    if json {
+        writeln!(
+            out,
+            "{{\"offset\":{},\"end\":[{}],\"valid\":{},\"trailing\":{}}}",
+            end.offset,
+            bytes.join(","),
+            end.is_valid(),
+            end.trailing
+        )
+    } else {
+        writeln!(
+            out,
+            "{:#010x}  END    {}  trailing={}",
+            end.offset,
+            if end.is_valid() { "ok" } else { "invalid" },
+            end.trailing
+        )
+    }
+}
@@ -0,0 +1,26 @@
+use qoi_viwer::convert::{load_hdr, save_hdr};
+use qoi_viwer::qoilib::hdr::SampleFormat;
+
+use super::{Args, CliResult};
+
+pub fn run(args: &Args) -> CliResult {
+    let input = args.required(0, "input file")?;
+    let output = args.required(1, "output file")?;
+    let img = load_hdr(input).map_err(|e| format!("{}: {}", input, e))?;
+    let written = save_hdr(&img, output).map_err(|e| format!("{}: {}", output, e))?;
+
+    let format = match img.format {
+        SampleFormat::Unorm16 => "unorm16",
+        SampleFormat::Half => "half",
+    };
+    println!(
+        "{}x{} channels={} format={} {} bytes",
+        img.width,
+        img.height,
+        img.channels.to_bytes(),
+        format,
+        written
+    );
+
+    Ok(())
+}
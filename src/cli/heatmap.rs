@@ -0,0 +1,57 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use qoi_viwer::qoilib::encoder::Encoder;
+use qoi_viwer::qoilib::heatmap::{heatmap, HeatmapKind, MAX_PIXEL_BYTES, OP_COLORS};
+use qoi_viwer::qoilib::op::OP_NAMES;
+
+use qoi_viwer::convert::{is_qoi, load_image};
+
+use super::{Args, CliResult};
+
+pub fn run(args: &Args) -> CliResult {
+    let input = args.required(0, "input file")?;
+    let output = args.required(1, "output file")?;
+    let kind = match args.value("mode") {
+        None | Some("ops") => HeatmapKind::Ops,
+        Some("bytes") => HeatmapKind::Bytes,
+        Some(other) => {
+            return Err(format!("unknown mode `{}`, expected ops or bytes", other).into())
+        }
+    };
+
+    // qoi files are mapped as they are, other images are encoded first
+    let img = if is_qoi(input)? {
+        heatmap(BufReader::new(File::open(input)?), kind)
+    } else {
+        let img = load_image(input).map_err(|e| format!("{}: {}", input, e))?;
+        let mut buffer = BufWriter::new(Vec::new());
+        Encoder::new(
+            &img.pixels,
+            img.width,
+            img.height,
+            img.channels,
+            img.colorspace,
+        )
+        .encode_to_buffer(&mut buffer)
+        .map_err(|_| format!("{}: failed to encode", input))?;
+        heatmap(&buffer.into_inner()?[..], kind)
+    }
+    .map_err(|_| format!("{}: not a valid qoi file", input))?;
+    img.save(output)?;
+
+    match kind {
+        HeatmapKind::Ops => {
+            for (name, [r, g, b]) in OP_NAMES.iter().zip(OP_COLORS.iter()) {
+                println!("{:<5}  #{:02x}{:02x}{:02x}", name, r, g, b);
+            }
+        }
+        HeatmapKind::Bytes => {
+            println!(
+                "black = 0 bytes per pixel, white = {} bytes per pixel",
+                MAX_PIXEL_BYTES
+            );
+        }
+    }
+    Ok(())
+}
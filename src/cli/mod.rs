@@ -0,0 +1,139 @@
+mod batch;
+mod bench;
+mod decode;
+mod diff;
+mod dump;
+mod hdr;
+mod heatmap;
+mod plus;
+mod stats;
+mod thumb;
+mod verify;
+mod view;
+
+use std::error::Error;
+
+pub type CliResult = Result<(), Box<dyn Error>>;
+
+const USAGE: &str = "usage: qoi <command> [options]
+
+commands:
+    view <file.qoi> [--mode=halfblock|sixel|kitty]    draw an image in the terminal
+    dump <file.qoi> [--format=text|json]              list every chunk of the data stream
+    decode <file.qoi> <out.png>                       write a qoi file as any other format, grey
+                                                      images as grey
+    stats <file> [--format=text|json] [--tolerance=N|--target-size=BYTES|--target-bpp=X]
+          [--colors=N] [--quantizer=median-cut|k-means] [--dither]
+                                                      show where the bytes of an encoding go
+    thumb <file.qoi> <out.png> [--size=N|WxH] [--filter=box|nearest]
+                                                      write a thumbnail that fits in the size,
+                                                      128 by default
+    heatmap <file> <out.png> [--mode=ops|bytes]       colour every pixel by how it was encoded
+    plus <file> <out.qoi+> [--color=auto|none|subtract-green|ycocg-r]
+         [--predictor=auto|none|sub|up|average|paeth]
+                                                      write an experimental pre-filtered qoi+ file
+    hdr <file> <out.qoih|out.exr|out.png>             convert 16 bit and float images to and
+                                                      from the experimental qoih format
+    batch <in-dir> <out-dir> [--ext=png,...] [--skip=mtime|hash|none] [--jobs=N]
+                                                      convert a directory tree in parallel
+    verify <file.qoi>... [--roundtrip] [--format=text|json]
+                                                      check files against the spec
+    diff <a> <b> [--out=diff.png]                     compare two images pixel by pixel
+    bench [files...] [--size=WxH] [--iterations=N] [--codec=qoilib,qoi,png,qoi+]
+          [--filters]
+                                                      measure encode and decode throughput,
+                                                      or qoi+ sizes per filter with --filters";
+
+pub fn run(args: &[String]) -> CliResult {
+    let (command, rest) = args.split_first().ok_or(USAGE)?;
+    let args = Args::parse(rest);
+
+    match command.as_str() {
+        "view" => view::run(&args),
+        "dump" => dump::run(&args),
+        "decode" => decode::run(&args),
+        "stats" => stats::run(&args),
+        "thumb" => thumb::run(&args),
+        "heatmap" => heatmap::run(&args),
+        "plus" => plus::run(&args),
+        "hdr" => hdr::run(&args),
+        "batch" => batch::run(&args),
+        "verify" => verify::run(&args),
+        "diff" => diff::run(&args),
+        "bench" => bench::run(&args),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("unknown command `{}`\n\n{}", command, USAGE).into()),
+    }
+}
+
+// positional arguments plus `--flag` and `--name=value` options
+pub struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+    pub fn parse(args: &[String]) -> Self {
+        let mut positional = Vec::new();
+        let mut options = Vec::new();
+
+        for arg in args {
+            match arg.strip_prefix("--") {
+                Some(option) => match option.split_once('=') {
+                    Some((name, value)) => options.push((name.to_owned(), Some(value.to_owned()))),
+                    None => options.push((option.to_owned(), None)),
+                },
+                None => positional.push(arg.clone()),
+            }
+        }
+
+        Args {
+            positional,
+            options,
+        }
+    }
+
+    pub fn positional(&self) -> &[String] {
+        &self.positional
+    }
+
+    // the n-th positional argument, `what` names it in the error message
+    pub fn required(&self, n: usize, what: &str) -> Result<&str, String> {
+        self.positional
+            .get(n)
+            .map(String::as_str)
+            .ok_or_else(|| format!("missing {}", what))
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(n, _)| n == name)
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, v)| v.as_deref())
+    }
+}
+
+// a JSON string literal
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,64 @@
+use qoi_viwer::convert::load_image;
+use qoi_viwer::qoilib::plus::{best_filters, encode_to_vec, ColorTransform, Filters, Predictor};
+
+use super::{Args, CliResult};
+
+pub fn run(args: &Args) -> CliResult {
+    let input = args.required(0, "input file")?;
+    let output = args.required(1, "output file")?;
+    let img = load_image(input).map_err(|e| format!("{}: {}", input, e))?;
+
+    let color = match args.value("color") {
+        None | Some("auto") => None,
+        Some(name) => Some(
+            ColorTransform::ALL
+                .into_iter()
+                .find(|c| c.name() == name)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown color transform `{}`, expected none, subtract-green or ycocg-r",
+                        name
+                    )
+                })?,
+        ),
+    };
+    let predictor = match args.value("predictor") {
+        None | Some("auto") => None,
+        Some(name) => Some(
+            Predictor::ALL
+                .into_iter()
+                .find(|p| p.name() == name)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown predictor `{}`, expected none, sub, up, average or paeth",
+                        name
+                    )
+                })?,
+        ),
+    };
+
+    // whatever is not given is picked by trying every combination
+    let candidates = Filters::all()
+        .filter(|f| color.is_none_or(|c| c == f.color))
+        .filter(|f| predictor.is_none_or(|p| p == f.predictor));
+    let (filters, _) = best_filters(&img.pixels, img.width, img.height, img.channels, candidates)
+        .map_err(|_| format!("{}: failed to encode", input))?;
+
+    let data = encode_to_vec(
+        &img.pixels,
+        img.width,
+        img.height,
+        img.channels,
+        img.colorspace,
+        filters,
+    )
+    .map_err(|e| format!("{}: {}", input, e))?;
+    std::fs::write(output, &data)?;
+    println!(
+        "color={} predictor={} {} bytes",
+        filters.color.name(),
+        filters.predictor.name(),
+        data.len()
+    );
+    Ok(())
+}
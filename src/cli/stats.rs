@@ -0,0 +1,237 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+use qoi_viwer::qoilib::decoder::Decoder;
+use qoi_viwer::qoilib::encoder::Encoder;
+use qoi_viwer::qoilib::op::OP_NAMES;
+use qoi_viwer::qoilib::quantize::{QuantizeMethod, Quantizer};
+use qoi_viwer::qoilib::stats::EncodeStats;
+
+use qoi_viwer::convert::{is_qoi, load_image};
+
+use super::{json_string, Args, CliResult};
+
+pub fn run(args: &Args) -> CliResult {
+    let path = args.required(0, "input file")?;
+    let json = match args.value("format") {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(other) => {
+            return Err(format!("unknown format `{}`, expected text or json", other).into())
+        }
+    };
+
+    let tolerance: Option<u8> = match args.value("tolerance") {
+        Some(n) => Some(n.parse().map_err(|_| "--tolerance must be 0 to 255")?),
+        None => None,
+    };
+
+    let target = match (args.value("target-size"), args.value("target-bpp")) {
+        (None, None) => None,
+        (Some(n), None) => Some(Target::Bytes(
+            n.parse()
+                .map_err(|_| "--target-size must be a number of bytes")?,
+        )),
+        (None, Some(n)) => match n.parse::<f64>() {
+            Ok(bpp) if bpp > 0.0 => Some(Target::BitsPerPixel(bpp)),
+            _ => return Err("--target-bpp must be a positive number".into()),
+        },
+        (Some(_), Some(_)) => {
+            return Err("--target-size and --target-bpp exclude each other".into())
+        }
+    };
+    if tolerance.is_some() && target.is_some() {
+        return Err("--tolerance cannot be combined with a target".into());
+    }
+
+    let quantizer = match args.value("colors") {
+        Some(n) => {
+            let colors = match n.parse::<usize>() {
+                Ok(colors) if (1..=256).contains(&colors) => colors,
+                _ => return Err("--colors must be 1 to 256".into()),
+            };
+            let method = match args.value("quantizer") {
+                None | Some("median-cut") => QuantizeMethod::MedianCut,
+                Some("k-means") => QuantizeMethod::KMeans(K_MEANS_ROUNDS),
+                Some(other) => {
+                    return Err(format!(
+                        "unknown quantizer `{}`, expected median-cut or k-means",
+                        other
+                    )
+                    .into())
+                }
+            };
+            Some(
+                Quantizer::new(colors)
+                    .method(method)
+                    .dither(args.flag("dither")),
+            )
+        }
+        None => None,
+    };
+
+    // qoi files are analysed as they are, other images are encoded first.
+    // a tolerance, a target or a palette re-encodes qoi files too
+    let reencode = tolerance.is_some() || target.is_some() || quantizer.is_some();
+    let (stats, tolerance) = if !reencode && is_qoi(path)? {
+        let stats = Decoder::new(BufReader::new(File::open(path)?))
+            .analyse()
+            .map_err(|_| format!("{}: not a valid qoi file", path))?;
+        (stats, None)
+    } else {
+        let mut img = load_image(path).map_err(|e| format!("{}: {}", path, e))?;
+        if let Some(quantizer) = quantizer {
+            img.pixels = quantizer.quantize(&img.pixels, img.width).pixels;
+        }
+        let encoder = Encoder::new(
+            &img.pixels,
+            img.width,
+            img.height,
+            img.channels,
+            img.colorspace,
+        );
+        let failed = |_| format!("{}: failed to encode", path);
+        match target {
+            None => {
+                let stats = encoder
+                    .tolerance(tolerance.unwrap_or(0))
+                    .stats()
+                    .map_err(failed)?;
+                (stats, tolerance)
+            }
+            Some(target) => {
+                let fit = match target {
+                    Target::Bytes(bytes) => encoder.fit_size(bytes),
+                    Target::BitsPerPixel(bpp) => encoder.fit_bits_per_pixel(bpp),
+                }
+                .map_err(failed)?
+                .ok_or_else(|| format!("{}: cannot get that small at any tolerance", path))?;
+                (fit.stats, Some(fit.tolerance))
+            }
+        }
+    };
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    if json {
+        write_json(&mut out, path, &stats, tolerance)?;
+    } else {
+        write_text(&mut out, path, &stats, tolerance)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+const K_MEANS_ROUNDS: u32 = 10;
+
+enum Target {
+    Bytes(u64),
+    BitsPerPixel(f64),
+}
+
+// `tolerance` adds the psnr, which only means something for a fresh encoding
+fn write_text<W: Write>(
+    out: &mut W,
+    path: &str,
+    stats: &EncodeStats,
+    tolerance: Option<u8>,
+) -> std::io::Result<()> {
+    let total = stats.total_bytes();
+    writeln!(
+        out,
+        "{} {}x{} channels={}",
+        path,
+        stats.width,
+        stats.height,
+        stats.channels.to_bytes()
+    )?;
+    writeln!(
+        out,
+        "{:<5}  {:>10}  {:>12}  {:>12}  {:>7}",
+        "op", "chunks", "pixels", "bytes", "bytes%"
+    )?;
+    for (name, op) in OP_NAMES.iter().zip(stats.ops.iter()) {
+        writeln!(
+            out,
+            "{:<5}  {:>10}  {:>12}  {:>12}  {:>6.2}%",
+            name,
+            op.count,
+            op.pixels,
+            op.bytes,
+            percent(op.bytes, total)
+        )?;
+    }
+    let width = u64::from(stats.width.max(1));
+    writeln!(
+        out,
+        "longest run     {} pixels at ({},{})",
+        stats.longest_run,
+        stats.longest_run_at % width,
+        stats.longest_run_at / width
+    )?;
+    writeln!(
+        out,
+        "index hit rate  {:.2}%",
+        stats.index_hit_rate() * 100.0
+    )?;
+    writeln!(out, "bits per pixel  {:.3}", stats.bits_per_pixel())?;
+    if let Some(tolerance) = tolerance {
+        writeln!(out, "tolerance       {}", tolerance)?;
+        writeln!(out, "psnr            {:.2} dB", stats.psnr())?;
+    }
+    writeln!(
+        out,
+        "size            {} bytes, raw {} bytes, ratio {:.3}:1",
+        total,
+        stats.raw_bytes(),
+        stats.compression_ratio()
+    )
+}
+
+fn write_json<W: Write>(
+    out: &mut W,
+    path: &str,
+    stats: &EncodeStats,
+    tolerance: Option<u8>,
+) -> std::io::Result<()> {
+    let ops: Vec<String> = OP_NAMES
+        .iter()
+        .zip(stats.ops.iter())
+        .map(|(name, op)| {
+            format!(
+                "\"{}\":{{\"count\":{},\"pixels\":{},\"bytes\":{}}}",
+                name, op.count, op.pixels, op.bytes
+            )
+        })
+        .collect();
+    // json has no infinity, lossless encodings and qoi files get null
+    let psnr = match stats.psnr() {
+        psnr if tolerance.is_some() && psnr.is_finite() => format!("{:.6}", psnr),
+        _ => "null".to_owned(),
+    };
+    let tolerance = tolerance.map_or("null".to_owned(), |t| t.to_string());
+    writeln!(
+        out,
+        "{{\"file\":{},\"width\":{},\"height\":{},\"channels\":{},\"ops\":{{{}}},\"longest_run\":{},\"longest_run_at\":{},\"index_hit_rate\":{:.6},\"bits_per_pixel\":{:.6},\"bytes\":{},\"raw_bytes\":{},\"compression_ratio\":{:.6},\"tolerance\":{},\"psnr\":{}}}",
+        json_string(path),
+        stats.width,
+        stats.height,
+        stats.channels.to_bytes(),
+        ops.join(","),
+        stats.longest_run,
+        stats.longest_run_at,
+        stats.index_hit_rate(),
+        stats.bits_per_pixel(),
+        stats.total_bytes(),
+        stats.raw_bytes(),
+        stats.compression_ratio(),
+        tolerance,
+        psnr
+    )
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    part as f64 * 100.0 / total as f64
+}
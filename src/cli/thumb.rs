@@ -0,0 +1,57 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use image::{DynamicImage, RgbaImage};
+use qoi_viwer::qoilib::decoder::Decoder;
+use qoi_viwer::qoilib::header::qoi_channels;
+use qoi_viwer::qoilib::thumbnail::ThumbnailFilter;
+
+use super::{Args, CliResult};
+
+const DEFAULT_SIZE: u32 = 128;
+
+pub fn run(args: &Args) -> CliResult {
+    let input = args.required(0, "input file")?;
+    let output = args.required(1, "output file")?;
+
+    // N is a square box, WxH a rectangle
+    let (max_width, max_height) = match args.value("size") {
+        None => (DEFAULT_SIZE, DEFAULT_SIZE),
+        Some(size) => match size.split_once('x') {
+            Some((w, h)) => w.parse().ok().zip(h.parse().ok()),
+            None => size.parse().ok().map(|n| (n, n)),
+        }
+        .filter(|&(w, h)| w > 0 && h > 0)
+        .ok_or("--size must look like 128 or 160x120")?,
+    };
+    let filter = match args.value("filter") {
+        None => ThumbnailFilter::default(),
+        Some(name) => ThumbnailFilter::ALL
+            .into_iter()
+            .find(|f| f.name() == name)
+            .ok_or_else(|| format!("unknown filter `{}`, expected box or nearest", name))?,
+    };
+
+    // streamed from the file, the image is never held in memory
+    let file = File::open(input).map_err(|e| format!("{}: {}", input, e))?;
+    let (thumb, header) = Decoder::new(BufReader::new(file))
+        .decode_thumbnail(max_width, max_height, filter)
+        .map_err(|_| format!("{}: not a valid qoi file", input))?;
+
+    let img = RgbaImage::from_raw(
+        thumb.width,
+        thumb.height,
+        thumb.pixels.as_flattened().to_vec(),
+    )
+    .ok_or("not enough pixels")?;
+    let img = match header.channels() {
+        qoi_channels::Rgb => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img).to_rgb8()),
+        qoi_channels::Rgba => DynamicImage::ImageRgba8(img),
+    };
+    img.save(output).map_err(|e| format!("{}: {}", output, e))?;
+    println!(
+        "{}x{} -> {}x{}",
+        header.width, header.height, thumb.width, thumb.height
+    );
+    Ok(())
+}
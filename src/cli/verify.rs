@@ -0,0 +1,111 @@
+use std::io::{BufWriter, Write};
+
+use qoi_viwer::qoilib::verify::{verify, VerifyReport};
+
+use super::{json_string, Args, CliResult};
+
+pub fn run(args: &Args) -> CliResult {
+    let files = args.positional();
+    if files.is_empty() {
+        return Err("missing input files".into());
+    }
+    let roundtrip = args.flag("roundtrip");
+    let json = match args.value("format") {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(other) => {
+            return Err(format!("unknown format `{}`, expected text or json", other).into())
+        }
+    };
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    let mut failed = 0;
+    for path in files {
+        let report = match std::fs::read(path) {
+            Ok(data) => Ok(verify(&data, roundtrip)),
+            Err(e) => Err(e.to_string()),
+        };
+        if !matches!(&report, Ok(r) if r.is_ok()) {
+            failed += 1;
+        }
+
+        if json {
+            write_json(&mut out, path, &report)?;
+        } else {
+            write_text(&mut out, path, &report)?;
+        }
+    }
+    out.flush()?;
+
+    if failed > 0 {
+        return Err(format!("{} of {} files failed verification", failed, files.len()).into());
+    }
+    Ok(())
+}
+
+fn write_text<W: Write>(
+    out: &mut W,
+    path: &str,
+    report: &Result<VerifyReport, String>,
+) -> std::io::Result<()> {
+    match report {
+        Err(e) => writeln!(out, "FAIL  {}: {}", path, e),
+        Ok(report) if report.is_ok() => {
+            let canonical = match report.canonical {
+                Some(true) => ", canonical",
+                _ => "",
+            };
+            writeln!(out, "ok    {}{}", path, canonical)
+        }
+        Ok(report) => {
+            writeln!(out, "FAIL  {}", path)?;
+            for e in &report.errors {
+                writeln!(out, "      {}", e)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn write_json<W: Write>(
+    out: &mut W,
+    path: &str,
+    report: &Result<VerifyReport, String>,
+) -> std::io::Result<()> {
+    let report = match report {
+        Err(e) => {
+            return writeln!(
+                out,
+                "{{\"file\":{},\"ok\":false,\"error\":{}}}",
+                json_string(path),
+                json_string(e)
+            )
+        }
+        Ok(report) => report,
+    };
+
+    let errors: Vec<String> = report
+        .errors
+        .iter()
+        .map(|e| {
+            format!(
+                "{{\"kind\":\"{}\",\"offset\":{},\"message\":{}}}",
+                e.kind(),
+                e.offset(),
+                json_string(&e.to_string())
+            )
+        })
+        .collect();
+    let canonical = match report.canonical {
+        Some(canonical) => canonical.to_string(),
+        None => "null".to_owned(),
+    };
+    writeln!(
+        out,
+        "{{\"file\":{},\"ok\":{},\"canonical\":{},\"errors\":[{}]}}",
+        json_string(path),
+        report.is_ok(),
+        canonical,
+        errors.join(",")
+    )
+}
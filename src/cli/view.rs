@@ -0,0 +1,21 @@
+use std::fs::File;
+use std::io::{BufReader, Write};
+
+use qoi_viwer::qoilib::decoder::Decoder;
+use qoi_viwer::viewer::{self, ViewMode};
+
+use super::{Args, CliResult};
+
+pub fn run(args: &Args) -> CliResult {
+    let path = args.required(0, "input file")?;
+    let mode: ViewMode = args.value("mode").unwrap_or("halfblock").parse()?;
+
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?));
+    let (pxs, header) = decoder
+        .decode()
+        .map_err(|_| format!("{}: not a valid qoi file", path))?;
+
+    let out = viewer::render(mode, &pxs, header.width, header.height);
+    std::io::stdout().lock().write_all(&out)?;
+    Ok(())
+}
@@ -0,0 +1,520 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+
+use half::f16;
+use image::{
+    DynamicImage, GrayAlphaImage, GrayImage, ImageBuffer, ImageFormat, Rgba, Rgba32FImage,
+};
+
+use crate::qoilib::decoder::{decode_to_vec_auto, DecodedPixels, Decoder};
+use crate::qoilib::encoder::{detect_channels, Encoder};
+use crate::qoilib::hdr::{self, HdrEncoder, SampleFormat};
+use crate::qoilib::header::{qoi_channels, QoiColorspace, QOI_MAGIC};
+use crate::qoilib::plus;
+
+// an image in the encoder's layout
+pub struct LoadedImage {
+    pub pixels: Vec<[u8; 4]>,
+    pub width: u32,
+    pub height: u32,
+    pub channels: qoi_channels,
+    pub colorspace: QoiColorspace,
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+// whether the file starts with the qoi magic
+pub fn is_qoi<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
+    let mut magic = [0; 4];
+    let read = File::open(path)?.read(&mut magic)?;
+    Ok(read == 4 && &magic == QOI_MAGIC)
+}
+
+// whether the file starts with the qoi+ magic
+pub fn is_qoi_plus<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
+    let mut magic = [0; 4];
+    let read = File::open(path)?.read(&mut magic)?;
+    Ok(read == 4 && plus::is_plus(&magic))
+}
+
+// whether the file starts with the png signature
+pub fn is_png<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
+    let mut signature = [0; 8];
+    let read = File::open(path)?.read(&mut signature)?;
+    Ok(read == 8 && signature == PNG_SIGNATURE)
+}
+
+// the colorspace a png declares: an sRGB chunk or no colour information at
+// all is sRGB, a gAMA of 1.0 without an sRGB chunk is linear
+pub fn png_colorspace<P: AsRef<Path>>(path: P) -> Result<QoiColorspace, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    read_png_colorspace(BufReader::new(file))
+}
+
+// png_colorspace() of a png coming from `reader`, only the chunks before
+// the pixels are read
+fn read_png_colorspace<R: Read>(reader: R) -> Result<QoiColorspace, String> {
+    let reader = png::Decoder::new(reader)
+        .read_info()
+        .map_err(|e| e.to_string())?;
+    let info = reader.info();
+
+    if info.srgb.is_some() {
+        return Ok(QoiColorspace::Srgb);
+    }
+    match info.gama_chunk {
+        Some(gamma) if (gamma.into_value() - 1.0).abs() < 0.01 => Ok(QoiColorspace::Linear),
+        _ => Ok(QoiColorspace::Srgb),
+    }
+}
+
+// decode a qoi file with the Decoder, a qoi+ file with plus, or anything the
+// image crate understands
+//
+// qoi and qoi+ files keep the channels and colorspace of their header. for
+// anything else channels is Rgba only when some pixel is not fully opaque,
+// and the colorspace comes from png_colorspace() for pngs and is sRGB
+// otherwise
+pub fn load_image<P: AsRef<Path>>(path: P) -> Result<LoadedImage, String> {
+    let path = path.as_ref();
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    if let Some(img) = load_container(&data)? {
+        return Ok(img);
+    }
+    let (img, colorspace) = open_image(path, &data)?;
+    Ok(LoadedImage::from_dynamic(&img, colorspace))
+}
+
+impl LoadedImage {
+    // the pixels of an image from the image crate, channels is Rgba only
+    // when some pixel is not fully opaque
+    fn from_dynamic(img: &DynamicImage, colorspace: QoiColorspace) -> Self {
+        let rgba = img.to_rgba8();
+        let pixels: Vec<[u8; 4]> = rgba.pixels().map(|p| p.0).collect();
+        let channels = detect_channels(&pixels);
+        LoadedImage {
+            pixels,
+            width: rgba.width(),
+            height: rgba.height(),
+            channels,
+            colorspace,
+        }
+    }
+}
+
+// a qoi or qoi+ file held in memory, None when it is neither
+fn load_container(data: &[u8]) -> Result<Option<LoadedImage>, String> {
+    if data.starts_with(QOI_MAGIC) {
+        let (pixels, header) = Decoder::new(data)
+            .decode()
+            .map_err(|_| "not a valid qoi file".to_owned())?;
+        return Ok(Some(LoadedImage {
+            pixels,
+            width: header.width,
+            height: header.height,
+            channels: header.channels(),
+            colorspace: header.colorspace(),
+        }));
+    }
+    if plus::is_plus(data) {
+        let (pixels, header, _) = plus::decode_to_vec(data, qoi_channels::Rgba)
+            .map_err(|_| "not a valid qoi+ file".to_owned())?;
+        return Ok(Some(LoadedImage {
+            pixels: pixels.as_chunks::<4>().0.to_vec(),
+            width: header.width,
+            height: header.height,
+            channels: header.channels(),
+            colorspace: header.colorspace(),
+        }));
+    }
+    Ok(None)
+}
+
+// the file `data` read from `path` through the image crate, which picks
+// the format by the extension like image::open(), with the colorspace
+// load_image() gives it
+fn open_image(path: &Path, data: &[u8]) -> Result<(DynamicImage, QoiColorspace), String> {
+    let mut reader = image::io::Reader::new(Cursor::new(data));
+    if let Ok(format) = ImageFormat::from_path(path) {
+        reader.set_format(format);
+    }
+    let img = reader.decode().map_err(|e| e.to_string())?;
+    let colorspace = if data.starts_with(&PNG_SIGNATURE) {
+        read_png_colorspace(data)?
+    } else {
+        QoiColorspace::Srgb
+    };
+    Ok((img, colorspace))
+}
+
+// what convert_file() hands to the encoder, grey images in their layout
+enum Converted {
+    Luma(GrayImage, QoiColorspace),
+    LumaAlpha(GrayAlphaImage, QoiColorspace),
+    Other(LoadedImage),
+}
+
+// write any image the image crate understands as qoi with the channels and
+// colorspace load_image() picked, returns the size of the qoi file. grey
+// images go to the encoder as they are, without expanding them to rgba
+pub fn convert_file<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> Result<u64, String> {
+    let data = fs::read(&input).map_err(|e| e.to_string())?;
+    convert_bytes(input, data, output)
+}
+
+// convert_file() on the contents `data` already read from `input`, whose
+// extension still picks the format
+pub fn convert_bytes<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    data: Vec<u8>,
+    output: Q,
+) -> Result<u64, String> {
+    let input = input.as_ref();
+    let output = output.as_ref();
+    let converted = match load_container(&data)? {
+        Some(img) => Converted::Other(img),
+        None => match open_image(input, &data)? {
+            (DynamicImage::ImageLuma8(img), colorspace) => Converted::Luma(img, colorspace),
+            (DynamicImage::ImageLumaA8(img), colorspace) => Converted::LumaAlpha(img, colorspace),
+            (img, colorspace) => Converted::Other(LoadedImage::from_dynamic(&img, colorspace)),
+        },
+    };
+    drop(data);
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let mut buffer = BufWriter::new(File::create(output).map_err(|e| e.to_string())?);
+    let written = match &converted {
+        Converted::Luma(img, colorspace) => {
+            Encoder::luma(img.as_raw(), img.width(), img.height(), *colorspace)
+                .encode_to_buffer(&mut buffer)
+        }
+        Converted::LumaAlpha(img, colorspace) => Encoder::luma_alpha(
+            img.as_raw().as_chunks::<2>().0,
+            img.width(),
+            img.height(),
+            *colorspace,
+        )
+        .auto_channels(true)
+        .encode_to_buffer(&mut buffer),
+        Converted::Other(img) => Encoder::new(
+            &img.pixels,
+            img.width,
+            img.height,
+            img.channels,
+            img.colorspace,
+        )
+        .encode_to_buffer(&mut buffer),
+    }
+    .map_err(|_| "failed to encode".to_owned())?;
+    buffer.flush().map_err(|e| e.to_string())?;
+    Ok(written as u64)
+}
+
+// decode a qoi file into any format the image crate writes, picked by the
+// extension of `output`. grey images come out as L8 or LA8
+pub fn decode_file<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> Result<(), String> {
+    let data = fs::read(input).map_err(|e| e.to_string())?;
+    let (pixels, header) =
+        decode_to_vec_auto(&data).map_err(|_| "not a valid qoi file".to_owned())?;
+    let (width, height) = (header.width, header.height);
+    let img = match pixels {
+        DecodedPixels::L8(px) => {
+            ImageBuffer::from_raw(width, height, px).map(DynamicImage::ImageLuma8)
+        }
+        DecodedPixels::La8(px) => {
+            ImageBuffer::from_raw(width, height, px).map(DynamicImage::ImageLumaA8)
+        }
+        DecodedPixels::Rgb8(px) => {
+            ImageBuffer::from_raw(width, height, px).map(DynamicImage::ImageRgb8)
+        }
+        DecodedPixels::Rgba8(px) => {
+            ImageBuffer::from_raw(width, height, px).map(DynamicImage::ImageRgba8)
+        }
+    }
+    .ok_or("not enough pixels")?;
+
+    if let Some(parent) = output.as_ref().parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    img.save(output).map_err(|e| e.to_string())
+}
+
+// an image in the layout of qoilib::hdr
+pub struct LoadedHdr {
+    pub pixels: Vec<[u16; 4]>,
+    pub width: u32,
+    pub height: u32,
+    pub channels: qoi_channels,
+    pub colorspace: QoiColorspace,
+    pub format: SampleFormat,
+}
+
+// whether the file starts with the qoih magic
+pub fn is_qoi_hdr<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
+    let mut magic = [0; 4];
+    let read = File::open(path)?.read(&mut magic)?;
+    Ok(read == 4 && hdr::is_hdr(&magic))
+}
+
+// decode a qoih file, or anything the image crate understands at 16 bits
+//
+// float images like exr become half floats in linear colorspace, everything
+// else 16 bit unorm with the colorspace load_image() would pick. channels
+// is Rgba only when some pixel is not fully opaque
+pub fn load_hdr<P: AsRef<Path>>(path: P) -> Result<LoadedHdr, String> {
+    let path = path.as_ref();
+    if is_qoi_hdr(path).map_err(|e| e.to_string())? {
+        let data = fs::read(path).map_err(|e| e.to_string())?;
+        let (pixels, header) =
+            hdr::decode_to_vec(&data).map_err(|_| "not a valid qoih file".to_owned())?;
+        return Ok(LoadedHdr {
+            pixels,
+            width: header.width,
+            height: header.height,
+            channels: header.channels,
+            colorspace: header.colorspace,
+            format: header.format,
+        });
+    }
+
+    let img = image::open(path).map_err(|e| e.to_string())?;
+    let (width, height) = (img.width(), img.height());
+    let (pixels, format, colorspace): (Vec<[u16; 4]>, _, _) = match img {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => (
+            img.to_rgba32f()
+                .pixels()
+                .map(|p| p.0.map(|v| f16::from_f32(v).to_bits()))
+                .collect(),
+            SampleFormat::Half,
+            QoiColorspace::Linear,
+        ),
+        _ => {
+            let colorspace = if is_png(path).map_err(|e| e.to_string())? {
+                png_colorspace(path)?
+            } else {
+                QoiColorspace::Srgb
+            };
+            (
+                img.to_rgba16().pixels().map(|p| p.0).collect(),
+                SampleFormat::Unorm16,
+                colorspace,
+            )
+        }
+    };
+    let channels = if pixels.iter().all(|px| px[3] == format.one()) {
+        qoi_channels::Rgb
+    } else {
+        qoi_channels::Rgba
+    };
+
+    Ok(LoadedHdr {
+        pixels,
+        width,
+        height,
+        channels,
+        colorspace,
+        format,
+    })
+}
+
+// write a LoadedHdr by the extension of `path`: qoih, exr as 32 bit floats,
+// anything else 16 bit unorm through the image crate, which clamps floats to
+// 0.0 to 1.0. returns the size of the file
+pub fn save_hdr<P: AsRef<Path>>(img: &LoadedHdr, path: P) -> Result<u64, String> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    let opaque = img.channels == qoi_channels::Rgb;
+
+    match extension.as_deref() {
+        Some("qoih") => {
+            let data = HdrEncoder::new(
+                &img.pixels,
+                img.width,
+                img.height,
+                img.channels,
+                img.colorspace,
+                img.format,
+            )
+            .encode_to_vec()
+            .map_err(|e| e.to_string())?;
+            fs::write(path, &data).map_err(|e| e.to_string())?;
+        }
+        Some("exr") => {
+            let floats: Vec<f32> = img
+                .pixels
+                .iter()
+                .flat_map(|px| px.map(|v| sample_to_f32(v, img.format)))
+                .collect();
+            let rgba =
+                Rgba32FImage::from_raw(img.width, img.height, floats).ok_or("not enough pixels")?;
+            if opaque {
+                DynamicImage::ImageRgba32F(rgba).to_rgb32f().save(path)
+            } else {
+                rgba.save(path)
+            }
+            .map_err(|e| e.to_string())?;
+        }
+        _ => {
+            let samples: Vec<u16> = img
+                .pixels
+                .iter()
+                .flat_map(|px| px.map(|v| sample_to_unorm16(v, img.format)))
+                .collect();
+            let rgba = ImageBuffer::<Rgba<u16>, _>::from_raw(img.width, img.height, samples)
+                .ok_or("not enough pixels")?;
+            if opaque {
+                DynamicImage::ImageRgba16(rgba).to_rgb16().save(path)
+            } else {
+                rgba.save(path)
+            }
+            .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(fs::metadata(path).map_err(|e| e.to_string())?.len())
+}
+
+fn sample_to_f32(v: u16, format: SampleFormat) -> f32 {
+    match format {
+        SampleFormat::Unorm16 => f32::from(v) / 65535.0,
+        SampleFormat::Half => f16::from_bits(v).to_f32(),
+    }
+}
+
+fn sample_to_unorm16(v: u16, format: SampleFormat) -> u16 {
+    match format {
+        SampleFormat::Unorm16 => v,
+        SampleFormat::Half => (f16::from_bits(v).to_f32().clamp(0.0, 1.0) * 65535.0).round() as u16,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qoilib::test_util::TempDir;
+
+    // load, encode to qoih, decode and save again
+    fn round_trip(input: &Path, output: &Path) -> LoadedHdr {
+        let img = load_hdr(input).unwrap();
+        let data = HdrEncoder::new(
+            &img.pixels,
+            img.width,
+            img.height,
+            img.channels,
+            img.colorspace,
+            img.format,
+        )
+        .encode_to_vec()
+        .unwrap();
+        let (pixels, header) = hdr::decode_to_vec(&data).unwrap();
+        let decoded = LoadedHdr {
+            pixels,
+            width: header.width,
+            height: header.height,
+            channels: header.channels,
+            colorspace: header.colorspace,
+            format: header.format,
+        };
+        save_hdr(&decoded, output).unwrap();
+        img
+    }
+
+    #[test]
+    fn png_16_bit_round_trip() {
+        let dir = TempDir::new("png16");
+        for opaque in [false, true] {
+            let src = ImageBuffer::<Rgba<u16>, _>::from_fn(7, 5, |x, y| {
+                let a = if opaque {
+                    u16::MAX
+                } else {
+                    (x * 9000 + y) as u16
+                };
+                Rgba([
+                    (x * 9173) as u16,
+                    (y * 13001 + 1) as u16,
+                    (x * y * 257 + 3) as u16,
+                    a,
+                ])
+            });
+            let (input, output) = (dir.join("in.png"), dir.join("out.png"));
+            src.save(&input).unwrap();
+
+            let img = round_trip(&input, &output);
+            assert_eq!(img.format, SampleFormat::Unorm16);
+            let channels = if opaque {
+                qoi_channels::Rgb
+            } else {
+                qoi_channels::Rgba
+            };
+            assert_eq!(img.channels, channels);
+
+            let back = image::open(&output).unwrap();
+            assert_eq!(back.color().has_alpha(), !opaque);
+            assert_eq!(back.to_rgba16(), src, "opaque {}", opaque);
+        }
+    }
+
+    #[test]
+    fn exr_round_trip() {
+        let dir = TempDir::new("exr");
+        // values half floats hold exactly, past 1.0 as well
+        let src = Rgba32FImage::from_fn(6, 4, |x, y| {
+            Rgba([
+                x as f32 / 8.0,
+                y as f32 * 2.5,
+                0.125 + (x * y) as f32 / 64.0,
+                1.0 - x as f32 / 16.0,
+            ])
+        });
+        let (input, output) = (dir.join("in.exr"), dir.join("out.exr"));
+        src.save(&input).unwrap();
+
+        let img = round_trip(&input, &output);
+        assert_eq!(img.format, SampleFormat::Half);
+        assert_eq!(img.colorspace, QoiColorspace::Linear);
+        assert_eq!(img.channels, qoi_channels::Rgba);
+        assert_eq!(image::open(&output).unwrap().to_rgba32f(), src);
+
+        // and through a qoih file on disk
+        let qoih = dir.join("out.qoih");
+        save_hdr(&img, &qoih).unwrap();
+        assert!(is_qoi_hdr(&qoih).unwrap());
+        assert_eq!(load_hdr(&qoih).unwrap().pixels, img.pixels);
+    }
+
+    // a 1x1 png with the given colour chunks
+    fn png_with(srgb: bool, gamma: Option<f32>) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 1, 1);
+        encoder.set_color(png::ColorType::Rgb);
+        if srgb {
+            encoder.set_srgb(png::SrgbRenderingIntent::Perceptual);
+        }
+        if let Some(gamma) = gamma {
+            encoder.set_source_gamma(png::ScaledFloat::new(gamma));
+        }
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&[1, 2, 3])
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn png_colour_chunks() {
+        let colorspace = |srgb, gamma| read_png_colorspace(Cursor::new(png_with(srgb, gamma)));
+        assert_eq!(colorspace(true, None), Ok(QoiColorspace::Srgb));
+        assert_eq!(colorspace(false, Some(1.0)), Ok(QoiColorspace::Linear));
+        assert_eq!(colorspace(false, Some(1.0 / 2.2)), Ok(QoiColorspace::Srgb));
+        // an sRGB chunk wins over a gAMA of 1.0
+        assert_eq!(colorspace(true, Some(1.0)), Ok(QoiColorspace::Srgb));
+        assert_eq!(colorspace(false, None), Ok(QoiColorspace::Srgb));
+        assert!(read_png_colorspace(Cursor::new(b"not a png".to_vec())).is_err());
+    }
+}
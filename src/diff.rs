@@ -0,0 +1,160 @@
+use image::{Rgba, RgbaImage};
+
+use crate::qoilib::stats::psnr;
+
+// how far apart two images of the same size are
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffReport {
+    pub width: u32,
+    pub height: u32,
+    // pixels with at least one channel changed
+    pub differing: u64,
+    // largest absolute change seen in each of r, g, b, a
+    pub max_delta: [u8; 4],
+    // x, y, width and height of the smallest rectangle holding every change
+    pub bounds: Option<(u32, u32, u32, u32)>,
+    // over all four channels, infinite when the images are identical
+    pub psnr: f64,
+}
+
+impl DiffReport {
+    pub fn is_identical(&self) -> bool {
+        self.differing == 0
+    }
+}
+
+// compare two images pixel by pixel, both laid out row by row. both need
+// exactly width * height pixels
+pub fn compare(
+    a: &[[u8; 4]],
+    b: &[[u8; 4]],
+    width: u32,
+    height: u32,
+) -> Result<DiffReport, String> {
+    check_sizes(a, b, width, height)?;
+    let mut differing = 0;
+    let mut max_delta = [0u8; 4];
+    let mut squared_error: u64 = 0;
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+
+    for (i, (pa, pb)) in a.iter().zip(b.iter()).enumerate() {
+        if pa == pb {
+            continue;
+        }
+        differing += 1;
+        for c in 0..4 {
+            let delta = pa[c].abs_diff(pb[c]);
+            max_delta[c] = max_delta[c].max(delta);
+            squared_error += u64::from(delta) * u64::from(delta);
+        }
+
+        let x = (i % width as usize) as u32;
+        let y = (i / width as usize) as u32;
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+    }
+
+    let samples = u64::from(width) * u64::from(height) * 4;
+
+    Ok(DiffReport {
+        width,
+        height,
+        differing,
+        max_delta,
+        bounds: (differing > 0).then(|| (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1)),
+        psnr: psnr(squared_error, samples),
+    })
+}
+
+// a dimmed grey copy of `a` with every changed pixel in red, brighter red
+// for bigger changes
+pub fn diff_image(
+    a: &[[u8; 4]],
+    b: &[[u8; 4]],
+    width: u32,
+    height: u32,
+) -> Result<RgbaImage, String> {
+    check_sizes(a, b, width, height)?;
+    let mut img = RgbaImage::new(width, height);
+
+    for (i, (pa, pb)) in a.iter().zip(b.iter()).enumerate() {
+        let x = (i % width as usize) as u32;
+        let y = (i / width as usize) as u32;
+        let color = if pa == pb {
+            let grey = (u32::from(pa[0]) * 3 + u32::from(pa[1]) * 6 + u32::from(pa[2])) / 10;
+            let grey = (grey / 3) as u8;
+            [grey, grey, grey, 255]
+        } else {
+            let delta = (0..4).map(|c| pa[c].abs_diff(pb[c])).max().unwrap_or(0);
+            [128 + delta / 2, 0, 0, 255]
+        };
+        img.put_pixel(x, y, Rgba(color));
+    }
+
+    Ok(img)
+}
+
+fn check_sizes(a: &[[u8; 4]], b: &[[u8; 4]], width: u32, height: u32) -> Result<(), String> {
+    let pixels = u64::from(width) * u64::from(height);
+    for (name, image) in [("first", a), ("second", b)] {
+        if image.len() as u64 != pixels {
+            return Err(format!(
+                "{} image has {} pixels, {}x{} needs {}",
+                name,
+                image.len(),
+                width,
+                height,
+                pixels
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_images() {
+        let pixels = [[10, 20, 30, 255]; 6];
+        let report = compare(&pixels, &pixels, 3, 2).unwrap();
+        assert!(report.is_identical());
+        assert_eq!(report.differing, 0);
+        assert_eq!(report.max_delta, [0; 4]);
+        assert_eq!(report.bounds, None);
+        assert!(report.psnr.is_infinite());
+    }
+
+    #[test]
+    fn one_changed_pixel() {
+        let a = [[10, 20, 30, 255]; 12];
+        let mut b = a;
+        // x 2, y 1 of a 4x3 image
+        b[6] = [13, 20, 25, 200];
+        let report = compare(&a, &b, 4, 3).unwrap();
+        assert!(!report.is_identical());
+        assert_eq!(report.differing, 1);
+        assert_eq!(report.max_delta, [3, 0, 5, 55]);
+        assert_eq!(report.bounds, Some((2, 1, 1, 1)));
+        assert!(report.psnr.is_finite());
+
+        let img = diff_image(&a, &b, 4, 3).unwrap();
+        assert_eq!(img.get_pixel(2, 1).0, [128 + 55 / 2, 0, 0, 255]);
+        assert_eq!(img.get_pixel(0, 0).0[0], img.get_pixel(0, 0).0[1]);
+    }
+
+    #[test]
+    fn mismatched_sizes_are_errors() {
+        let a = [[0; 4]; 6];
+        let b = [[0; 4]; 4];
+        assert!(compare(&a, &b, 3, 2).is_err());
+        assert!(compare(&b, &a, 3, 2).is_err());
+        assert!(compare(&a, &a, 2, 2).is_err());
+        assert!(compare(&a, &a, 0, 2).is_err());
+        assert!(diff_image(&a, &b, 3, 2).is_err());
+        assert!(compare(&[], &[], 0, 0).unwrap().is_identical());
+    }
+}
@@ -0,0 +1,18 @@
+// without the default `std` feature only the core of the codec in qoilib is
+// built, so it can run where there is no operating system
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod bench;
+#[cfg(feature = "std")]
+pub mod convert;
+#[cfg(feature = "std")]
+pub mod diff;
+pub mod qoilib;
+#[cfg(feature = "std")]
+pub mod viewer;
@@ -1,15 +1,26 @@
-mod qoilib;
+mod cli;
 
-use std::io::{BufReader, BufWriter, Write};
-use std::{fs::File, ops::Deref};
+use std::fs::File;
+use std::io::BufWriter;
 
-use image::{Pixel, Pixels as IMGPixels, Rgb, RgbImage, Rgba, RgbaImage};
-use qoilib::decoder::{read_u32, read_u8, Decoder};
+use image::{Pixel, RgbaImage};
+use qoi_viwer::convert::png_colorspace;
+use qoi_viwer::qoilib;
+use qoilib::decoder::Decoder;
 use qoilib::encoder::Encoder;
 
 fn main() {
-    test_encode();
-    test_decode();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() {
+        test_encode();
+        test_decode();
+        return;
+    }
+
+    if let Err(e) = cli::run(&args) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
 }
 
 fn test_encode() {
@@ -30,33 +41,31 @@ fn test_encode() {
             ]
         })
         .collect();
-    let mut encoder = Encoder::new(
+    let encoder = Encoder::new(
         img_vec.as_slice(),
         img.width(),
         img.height(),
         qoilib::header::qoi_channels::Rgb,
-        0,
-    );
-    let mut op_file = File::create("img_op.qoi").unwrap();
+        png_colorspace("img.png").unwrap(),
+    )
+    .auto_channels(true)
+    .verbose(true);
+    let op_file = File::create("img_op.qoi").unwrap();
     let mut buffer = BufWriter::new(op_file);
 
     // start encoding
-    encoder.encode_to_buffer(&mut buffer);
+    encoder.encode_to_buffer(&mut buffer).unwrap();
 }
 
 fn test_decode() {
-    let mut op_file = File::open("img_op.qoi").unwrap();
-    let mut decoder = Decoder::new(op_file);
+    let op_file = File::open("img_op.qoi").unwrap();
+    let mut decoder = Decoder::new(op_file).verbose(true);
 
     // start decoding
     let (pxs, header) = decoder.decode().unwrap();
-    let img_pxs: Vec<Rgba<u8>> = pxs.iter().map(|p| Rgba(*p)).collect();
 
-    let mut op_img = RgbaImage::new(header.width, header.height);
-    for i in 0..op_img.width() {
-        for j in 0..op_img.height() {
-            op_img.put_pixel(i, j, img_pxs[(j * op_img.width() + i) as usize]);
-        }
-    }
+    // decoded rows are already in the order the image crate stores them
+    let op_img =
+        RgbaImage::from_raw(header.width, header.height, pxs.as_flattened().to_vec()).unwrap();
     op_img.save("img_op.png").unwrap();
 }
@@ -0,0 +1,67 @@
+// conversions between the straight alpha qoi stores and premultiplied alpha
+//
+// both round to the nearest value. going premultiplied -> straight ->
+// premultiplied gives back the same bytes for every valid premultiplied
+// pixel (no channel above alpha), so premultiplied data survives an encode
+// and decode with Encoder::premultiplied and Decoder::premultiplied intact.
+// the other way round, straight -> premultiplied -> straight, is exact at
+// alpha 255 and otherwise off by at most 127 / alpha + 1 per channel, the
+// colour of a fully transparent pixel is lost
+
+// c * a / 255, rounded
+pub fn premultiply(px: [u8; 4]) -> [u8; 4] {
+    let a = u16::from(px[3]);
+    let scale = |c: u8| ((u16::from(c) * a + 127) / 255) as u8;
+    [scale(px[0]), scale(px[1]), scale(px[2]), px[3]]
+}
+
+// c * 255 / a, rounded and clamped to 255 for channels above alpha.
+// transparent black when alpha is 0
+pub fn unpremultiply(px: [u8; 4]) -> [u8; 4] {
+    let a = u32::from(px[3]);
+    if a == 0 {
+        return [0; 4];
+    }
+    let scale = |c: u8| ((u32::from(c) * 255 + a / 2) / a).min(255) as u8;
+    [scale(px[0]), scale(px[1]), scale(px[2]), px[3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn premultiplied_pixels_come_back_exactly() {
+        for a in 0..=255u8 {
+            for c in 0..=a {
+                let px = [c, a - c, c / 2, a];
+                assert_eq!(premultiply(unpremultiply(px)), px, "{:?}", px);
+            }
+        }
+    }
+
+    #[test]
+    fn straight_pixels_stay_within_the_bound() {
+        for v in 0..=255u8 {
+            let px = [v, 255 - v, v / 2, 255];
+            assert_eq!(unpremultiply(premultiply(px)), px, "{:?}", px);
+            assert_eq!(unpremultiply(premultiply([v, v, v, 0])), [0; 4]);
+        }
+        for a in 1..=255u8 {
+            let bound = 127 / a + 1;
+            for v in 0..=255u8 {
+                let back = unpremultiply(premultiply([v, v, v, a]));
+                assert_eq!(back[3], a);
+                for c in &back[..3] {
+                    assert!(
+                        c.abs_diff(v) <= bound,
+                        "value {} alpha {} came back as {}",
+                        v,
+                        a,
+                        c
+                    );
+                }
+            }
+        }
+    }
+}
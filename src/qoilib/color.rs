@@ -0,0 +1,107 @@
+use super::header::QoiColorspace;
+
+// the sRGB transfer function and its inverse, both on 0..=1
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// decoded pixels as linear floats in 0..=1. with Srgb only r, g and b go
+// through the transfer function, alpha is linear in both colorspaces
+pub fn to_linear(pixels: &[[u8; 4]], colorspace: QoiColorspace) -> Vec<[f32; 4]> {
+    // 256 powf calls instead of three per pixel
+    let table: [f32; 256] = std::array::from_fn(|v| {
+        let v = v as f32 / 255.0;
+        match colorspace {
+            QoiColorspace::Srgb => srgb_to_linear(v),
+            QoiColorspace::Linear => v,
+        }
+    });
+
+    pixels
+        .iter()
+        .map(|&[r, g, b, a]| {
+            [
+                table[usize::from(r)],
+                table[usize::from(g)],
+                table[usize::from(b)],
+                f32::from(a) / 255.0,
+            ]
+        })
+        .collect()
+}
+
+// the inverse of to_linear, ready for Encoder::new with the same colorspace.
+// values are clamped to 0..=1 and rounded to the nearest step, so a round
+// trip through to_linear gives back the same bytes
+pub fn from_linear(pixels: &[[f32; 4]], colorspace: QoiColorspace) -> Vec<[u8; 4]> {
+    let quantise = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    let encode = |v: f32| match colorspace {
+        QoiColorspace::Srgb => quantise(linear_to_srgb(v.clamp(0.0, 1.0))),
+        QoiColorspace::Linear => quantise(v),
+    };
+
+    pixels
+        .iter()
+        .map(|&[r, g, b, a]| [encode(r), encode(g), encode(b), quantise(a)])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_byte_survives_a_round_trip() {
+        let pixels: Vec<[u8; 4]> = (0..=255u8).map(|v| [v, v, v, v]).collect();
+        for colorspace in [QoiColorspace::Srgb, QoiColorspace::Linear] {
+            let linear = to_linear(&pixels, colorspace);
+            assert_eq!(from_linear(&linear, colorspace), pixels, "{:?}", colorspace);
+        }
+    }
+
+    #[test]
+    fn transfer_functions() {
+        for (srgb, linear) in [
+            (0.0, 0.0),
+            (1.0, 1.0),
+            (0.5, 0.214_041),
+            (0.04045, 0.003_130_8),
+        ] {
+            assert!((srgb_to_linear(srgb) - linear).abs() < 1e-5, "{}", srgb);
+            assert!((linear_to_srgb(linear) - srgb).abs() < 1e-5, "{}", linear);
+        }
+        for i in 0..=1000 {
+            let v = i as f32 / 1000.0;
+            assert!(
+                (linear_to_srgb(srgb_to_linear(v)) - v).abs() < 1e-5,
+                "{}",
+                v
+            );
+        }
+
+        // alpha is linear in both colorspaces, r, g and b only with Linear
+        let [r, _, _, a] = to_linear(&[[128, 0, 0, 128]], QoiColorspace::Srgb)[0];
+        assert!((r - srgb_to_linear(128.0 / 255.0)).abs() < 1e-6);
+        assert!((a - 128.0 / 255.0).abs() < 1e-6);
+        let [r, ..] = to_linear(&[[128, 0, 0, 128]], QoiColorspace::Linear)[0];
+        assert!((r - 128.0 / 255.0).abs() < 1e-6);
+
+        // out of range values are clamped
+        assert_eq!(
+            from_linear(&[[-1.0, 2.0, 0.0, 7.0]], QoiColorspace::Srgb),
+            [[0, 255, 0, 255]]
+        );
+    }
+}
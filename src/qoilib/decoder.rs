@@ -1,242 +1,1053 @@
+#[cfg(feature = "std")]
 use colored::*;
+#[cfg(feature = "std")]
 use std::io::Read;
-use std::mem::MaybeUninit;
 
-use super::header::{qoi_channels, qoi_header};
+#[cfg(feature = "std")]
+use super::alpha::premultiply;
+#[cfg(feature = "std")]
+use super::disasm::Disassembler;
+#[cfg(feature = "std")]
+use super::header::QOI_END;
+#[cfg(feature = "alloc")]
+use super::header::QOI_MAX_RUN;
+use super::header::{
+    qoi_channels, qoi_header, QOI_OP_DIFF, QOI_OP_INDEX, QOI_OP_LUMA, QOI_OP_RGB, QOI_OP_RGBA,
+    QOI_OP_RUN,
+};
+#[cfg(feature = "std")]
+use super::op::QoiOp;
+use super::simd;
+#[cfg(feature = "std")]
+use super::stats::EncodeStats;
+#[cfg(feature = "std")]
+use super::thumbnail::{Sampler, Thumbnail, ThumbnailFilter};
 use super::{PixelHashMap, Pixels};
 
+// the most pixels Decoder::decode() and decode_region() reserve room for
+// up front
+#[cfg(feature = "std")]
+const PREALLOC_PIXELS: usize = 1 << 20;
+
 // reference from: https://github.com/ChevyRay/qoi_rs/blob/457236d7e3a488d1751b175abfc6b448338898b1/src/decode.rs#L14
 
+#[cfg(feature = "std")]
 pub fn read<R: Read, const N: usize>(input: &mut R) -> Result<[u8; N], std::fmt::Error> {
-    let mut bytes: [u8; N] = unsafe { MaybeUninit::uninit().assume_init() };
-    input.read_exact(&mut bytes);
+    let mut bytes: [u8; N] = [0; N];
+    input.read_exact(&mut bytes).map_err(|_| std::fmt::Error)?;
     Ok(bytes)
 }
 
+#[cfg(feature = "std")]
 pub fn read_u8<R: Read>(input: &mut R) -> Result<u8, std::fmt::Error> {
-    Ok(read::<R, 1>(input).unwrap()[0])
+    Ok(read::<R, 1>(input)?[0])
 }
 
+#[cfg(feature = "std")]
 pub fn read_u32<R: Read>(input: &mut R) -> Result<u32, std::fmt::Error> {
-    Ok(u32::from_be_bytes(read::<R, 4>(input).unwrap()))
+    Ok(u32::from_be_bytes(read::<R, 4>(input)?))
+}
+
+#[cfg(feature = "std")]
+pub fn read_header<R: Read>(input: &mut R) -> Result<qoi_header, std::fmt::Error> {
+    qoi_header::from_bytes(&read::<R, 14>(input)?)
+}
+
+// decode a whole file held in memory into `out`, `channels` bytes per pixel
+// row by row. `out` must hold at least width * height pixels. with
+// `bottom_up` the rows are written bottom to top, as OpenGL expects
+// textures; orient::flip_vertical() does the same to a decoded buffer
+//
+// the fast path for when the file is already loaded: runs are written with
+// simd::fill_* instead of pixel by pixel
+pub fn decode_into(
+    data: &[u8],
+    out: &mut [u8],
+    channels: qoi_channels,
+    bottom_up: bool,
+) -> Result<qoi_header, core::fmt::Error> {
+    let header = qoi_header::from_bytes(data)?;
+    let (width, height) = (header.width as usize, header.height as usize);
+    let ch = channels.to_bytes() as usize;
+    if out.len() / ch < width * height {
+        return Err(core::fmt::Error);
+    }
+    let fill = match channels {
+        qoi_channels::Rgb => simd::fill_rgb,
+        qoi_channels::Rgba => simd::fill_rgba,
+    };
+
+    decode_ops(data, |mut cnt, mut len, px| {
+        if !bottom_up {
+            if len == 1 {
+                out[cnt * ch..(cnt + 1) * ch].copy_from_slice(&px[..ch]);
+            } else {
+                fill(&mut out[cnt * ch..(cnt + len) * ch], px);
+            }
+            return Ok(());
+        }
+        // runs can go on into the next row, which is stored before this one
+        while len > 0 {
+            let (y, x) = (cnt / width, cnt % width);
+            let n = len.min(width - x);
+            let start = ((height - 1 - y) * width + x) * ch;
+            fill(&mut out[start..start + n * ch], px);
+            cnt += n;
+            len -= n;
+        }
+        Ok(())
+    })
+}
+
+// the memory layouts decode_into_layout() writes. the multi-byte ones are in
+// native byte order so the buffer can be read back as [u16] or [u32]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelLayout {
+    Rgb,
+    Rgba,
+    Bgra,
+    Argb,
+    // a u16 per pixel, 5 bits red on top, 6 bits green, 5 bits blue, alpha
+    // dropped
+    Rgb565,
+    // a u32 per pixel, 0xAARRGGBB
+    U32,
+}
+
+impl PixelLayout {
+    pub const ALL: [PixelLayout; 6] = [
+        PixelLayout::Rgb,
+        PixelLayout::Rgba,
+        PixelLayout::Bgra,
+        PixelLayout::Argb,
+        PixelLayout::Rgb565,
+        PixelLayout::U32,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelLayout::Rgb => "rgb",
+            PixelLayout::Rgba => "rgba",
+            PixelLayout::Bgra => "bgra",
+            PixelLayout::Argb => "argb",
+            PixelLayout::Rgb565 => "rgb565",
+            PixelLayout::U32 => "u32",
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelLayout::Rgb => 3,
+            PixelLayout::Rgb565 => 2,
+            _ => 4,
+        }
+    }
+
+    // the bytes of one pixel, bytes_per_pixel() of them are used
+    pub fn pack(&self, [r, g, b, a]: [u8; 4]) -> [u8; 4] {
+        match self {
+            PixelLayout::Rgb | PixelLayout::Rgba => [r, g, b, a],
+            PixelLayout::Bgra => [b, g, r, a],
+            PixelLayout::Argb => [a, r, g, b],
+            PixelLayout::Rgb565 => {
+                let [lo, hi] = rgb565([r, g, b, a]).to_ne_bytes();
+                [lo, hi, 0, 0]
+            }
+            PixelLayout::U32 => argb32([r, g, b, a]).to_ne_bytes(),
+        }
+    }
+}
+
+fn rgb565([r, g, b, _]: [u8; 4]) -> u16 {
+    (u16::from(r) >> 3) << 11 | (u16::from(g) >> 2) << 5 | u16::from(b) >> 3
+}
+
+fn argb32([r, g, b, a]: [u8; 4]) -> u32 {
+    u32::from_be_bytes([a, r, g, b])
+}
+
+// decode_into() for any layout, the pixels are converted as they are decoded
+// instead of in a second pass over rgba
+pub fn decode_into_layout(
+    data: &[u8],
+    out: &mut [u8],
+    layout: PixelLayout,
+) -> Result<qoi_header, core::fmt::Error> {
+    match layout {
+        PixelLayout::Rgb => return decode_into(data, out, qoi_channels::Rgb, false),
+        PixelLayout::Rgba => return decode_into(data, out, qoi_channels::Rgba, false),
+        _ => {}
+    }
+
+    let header = qoi_header::from_bytes(data)?;
+    let pxs_write = header.width as usize * header.height as usize;
+    let bpp = layout.bytes_per_pixel();
+    if out.len() / bpp < pxs_write {
+        return Err(core::fmt::Error);
+    }
+
+    decode_ops(data, |cnt, len, px| {
+        let packed = layout.pack(px);
+        let dst = &mut out[cnt * bpp..(cnt + len) * bpp];
+        if bpp == 4 {
+            // fill_rgba only repeats 4 bytes, whatever their order
+            simd::fill_rgba(dst, packed);
+        } else {
+            for px in dst.chunks_exact_mut(bpp) {
+                px.copy_from_slice(&packed[..bpp]);
+            }
+        }
+        Ok(())
+    })
+}
+
+// PixelLayout::U32 straight into a [u32]
+pub fn decode_into_u32(data: &[u8], out: &mut [u32]) -> Result<qoi_header, core::fmt::Error> {
+    let header = qoi_header::from_bytes(data)?;
+    if out.len() < header.width as usize * header.height as usize {
+        return Err(core::fmt::Error);
+    }
+    decode_ops(data, |cnt, len, px| {
+        out[cnt..cnt + len].fill(argb32(px));
+        Ok(())
+    })
+}
+
+// PixelLayout::Rgb565 straight into a [u16]
+pub fn decode_into_rgb565(data: &[u8], out: &mut [u16]) -> Result<qoi_header, core::fmt::Error> {
+    let header = qoi_header::from_bytes(data)?;
+    if out.len() < header.width as usize * header.height as usize {
+        return Err(core::fmt::Error);
+    }
+    decode_ops(data, |cnt, len, px| {
+        out[cnt..cnt + len].fill(rgb565(px));
+        Ok(())
+    })
+}
+
+// a rectangle of the image, as the region functions take it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    // whether it lies within an image of this size
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        u64::from(self.x) + u64::from(self.width) <= u64::from(width)
+            && u64::from(self.y) + u64::from(self.height) <= u64::from(height)
+    }
+
+    // how many pixels of the image come before the end of the region, the
+    // point where decoding can stop
+    fn end(&self, image_width: u32) -> usize {
+        if self.width == 0 || self.height == 0 {
+            return 0;
+        }
+        (self.y + self.height - 1) as usize * image_width as usize + (self.x + self.width) as usize
+    }
+
+    // where the pixels cnt..cnt + len of an image `image_width` wide land in
+    // the region, as (index in the region, count) pieces
+    fn overlap(
+        &self,
+        image_width: u32,
+        mut cnt: usize,
+        mut len: usize,
+        mut piece: impl FnMut(usize, usize),
+    ) {
+        let width = image_width as usize;
+        let (left, right) = (self.x as usize, (self.x + self.width) as usize);
+        let (top, bottom) = (self.y as usize, (self.y + self.height) as usize);
+        while len > 0 {
+            let (y, x) = (cnt / width, cnt % width);
+            let n = len.min(width - x);
+            let (from, to) = (x.max(left), (x + n).min(right));
+            if (top..bottom).contains(&y) && from < to {
+                piece((y - top) * self.width as usize + from - left, to - from);
+            }
+            cnt += n;
+            len -= n;
+        }
+    }
+}
+
+// decode only the pixels inside `region` into `out`, packed row by row
+// without gaps. everything before the region still has to be decoded, as
+// every op builds on the ones before it, but nothing after its last pixel
+// is read. `out` must hold region.width * region.height pixels
+pub fn decode_region_into(
+    data: &[u8],
+    region: Region,
+    out: &mut [u8],
+    channels: qoi_channels,
+) -> Result<qoi_header, core::fmt::Error> {
+    let header = qoi_header::from_bytes(data)?;
+    let ch = channels.to_bytes() as usize;
+    if !region.fits(header.width, header.height)
+        || out.len() / ch < region.width as usize * region.height as usize
+    {
+        return Err(core::fmt::Error);
+    }
+    let fill = match channels {
+        qoi_channels::Rgb => simd::fill_rgb,
+        qoi_channels::Rgba => simd::fill_rgba,
+    };
+
+    decode_ops_until(data, region.end(header.width), |cnt, len, px| {
+        region.overlap(header.width, cnt, len, |at, n| {
+            fill(&mut out[at * ch..(at + n) * ch], px);
+        });
+        Ok(())
+    })
+}
+
+// decode a grey image into `out` without going through rgba: one byte per
+// pixel, or grey then alpha with `alpha`. fails at the first pixel whose r,
+// g and b differ
+pub fn decode_into_gray(
+    data: &[u8],
+    out: &mut [u8],
+    alpha: bool,
+) -> Result<qoi_header, core::fmt::Error> {
+    let header = qoi_header::from_bytes(data)?;
+    let pxs_write = header.width as usize * header.height as usize;
+    let ch = if alpha { 2 } else { 1 };
+    if out.len() / ch < pxs_write {
+        return Err(core::fmt::Error);
+    }
+
+    decode_ops(data, |cnt, len, [r, g, b, a]| {
+        if r != g || g != b {
+            return Err(core::fmt::Error);
+        }
+        let dst = &mut out[cnt * ch..(cnt + len) * ch];
+        if alpha {
+            for px in dst.chunks_exact_mut(2) {
+                px.copy_from_slice(&[g, a]);
+            }
+        } else {
+            dst.fill(g);
+        }
+        Ok(())
+    })
+}
+
+// run through the data stream and hand every pixel to `put` as the index of
+// the first pixel, how many pixels in a row have the value and the value.
+// runs come in one call, runs longer than the image are cut short like
+// decode() does
+#[inline(always)]
+pub(super) fn decode_ops<F>(data: &[u8], put: F) -> Result<qoi_header, core::fmt::Error>
+where
+    F: FnMut(usize, usize, [u8; 4]) -> Result<(), core::fmt::Error>,
+{
+    decode_ops_until(data, usize::MAX, put)
+}
+
+// decode_ops() that stops reading once the first `end` pixels are out
+#[inline(always)]
+fn decode_ops_until<F>(data: &[u8], end: usize, mut put: F) -> Result<qoi_header, core::fmt::Error>
+where
+    F: FnMut(usize, usize, [u8; 4]) -> Result<(), core::fmt::Error>,
+{
+    let header = qoi_header::from_bytes(data)?;
+    let pxs_write = (header.width as usize * header.height as usize).min(end);
+
+    let mut prevpx = Pixels::start_prev();
+    let mut hashmap = PixelHashMap::new();
+    let mut pos = 14;
+    let mut cnt = 0;
+
+    // every op is at most 5 bytes, bytes() errors when the file ends early
+    let bytes = |pos: usize, n: usize| data.get(pos..pos + n).ok_or(core::fmt::Error);
+
+    while cnt < pxs_write {
+        let b = bytes(pos, 1)?[0];
+        pos += 1;
+
+        match b {
+            QOI_OP_RGB => {
+                let rgb = bytes(pos, 3)?;
+                prevpx = Pixels::new(rgb[0], rgb[1], rgb[2], prevpx.a);
+                pos += 3;
+            }
+            QOI_OP_RGBA => {
+                let rgba = bytes(pos, 4)?;
+                prevpx = Pixels::new(rgba[0], rgba[1], rgba[2], rgba[3]);
+                pos += 4;
+            }
+            _ => match b >> 6 {
+                QOI_OP_INDEX => prevpx = hashmap[b],
+                QOI_OP_DIFF => {
+                    prevpx.r = prevpx.r.wrapping_add((b >> 4) & 3).wrapping_sub(2);
+                    prevpx.g = prevpx.g.wrapping_add((b >> 2) & 3).wrapping_sub(2);
+                    prevpx.b = prevpx.b.wrapping_add(b & 3).wrapping_sub(2);
+                }
+                QOI_OP_LUMA => {
+                    let b2 = bytes(pos, 1)?[0];
+                    pos += 1;
+                    let dg = (b & 0x3f).wrapping_sub(32);
+                    prevpx.r = prevpx
+                        .r
+                        .wrapping_add(dg.wrapping_sub(8).wrapping_add(b2 >> 4));
+                    prevpx.g = prevpx.g.wrapping_add(dg);
+                    prevpx.b = prevpx
+                        .b
+                        .wrapping_add(dg.wrapping_sub(8).wrapping_add(b2 & 0x0f));
+                }
+                QOI_OP_RUN => {
+                    let len = (usize::from(b & 0x3f) + 1).min(pxs_write - cnt);
+                    hashmap[prevpx.hash()] = prevpx;
+                    put(cnt, len, prevpx.to_array())?;
+                    cnt += len;
+                    continue;
+                }
+                _ => unreachable!(),
+            },
+        }
+
+        hashmap[prevpx.hash()] = prevpx;
+        put(cnt, 1, prevpx.to_array())?;
+        cnt += 1;
+    }
+
+    Ok(header)
+}
+
+// decode_into a freshly allocated buffer
+#[cfg(feature = "alloc")]
+pub fn decode_to_vec(
+    data: &[u8],
+    channels: qoi_channels,
+) -> Result<(alloc::vec::Vec<u8>, qoi_header), core::fmt::Error> {
+    let header = qoi_header::from_bytes(data)?;
+    let pixels = header.width as usize * header.height as usize;
+    if !can_hold(data, pixels) {
+        return Err(core::fmt::Error);
+    }
+    let mut out = alloc::vec![0; pixels * channels.to_bytes() as usize];
+    decode_into(data, &mut out, channels, false)?;
+    Ok((out, header))
+}
+
+// decode_into_layout a freshly allocated buffer
+#[cfg(feature = "alloc")]
+pub fn decode_to_vec_layout(
+    data: &[u8],
+    layout: PixelLayout,
+) -> Result<(alloc::vec::Vec<u8>, qoi_header), core::fmt::Error> {
+    let header = qoi_header::from_bytes(data)?;
+    let pixels = header.width as usize * header.height as usize;
+    if !can_hold(data, pixels) {
+        return Err(core::fmt::Error);
+    }
+    let mut out = alloc::vec![0; pixels * layout.bytes_per_pixel()];
+    decode_into_layout(data, &mut out, layout)?;
+    Ok((out, header))
+}
+
+// decode_region_into a freshly allocated buffer, the header is the one of
+// the whole image
+#[cfg(feature = "alloc")]
+pub fn decode_region(
+    data: &[u8],
+    region: Region,
+    channels: qoi_channels,
+) -> Result<(alloc::vec::Vec<u8>, qoi_header), core::fmt::Error> {
+    let header = qoi_header::from_bytes(data)?;
+    if !region.fits(header.width, header.height) || !can_hold(data, region.end(header.width)) {
+        return Err(core::fmt::Error);
+    }
+    let size = region.width as usize * region.height as usize * channels.to_bytes() as usize;
+    let mut out = alloc::vec![0; size];
+    decode_region_into(data, region, &mut out, channels)?;
+    Ok((out, header))
+}
+
+// whether `data`, a whole file, can be long enough for `pixels` pixels. at
+// most every byte after the header is a run of QOI_MAX_RUN, checked before
+// allocating for the size in the header so that a short file claiming a
+// huge image fails without touching the heap
+#[cfg(feature = "alloc")]
+pub(super) fn can_hold(data: &[u8], pixels: usize) -> bool {
+    data.len()
+        .saturating_sub(14)
+        .saturating_mul(usize::from(QOI_MAX_RUN))
+        >= pixels
+}
+
+// pixels in the smallest layout that holds the image
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodedPixels {
+    L8(alloc::vec::Vec<u8>),
+    // grey then alpha
+    La8(alloc::vec::Vec<u8>),
+    Rgb8(alloc::vec::Vec<u8>),
+    Rgba8(alloc::vec::Vec<u8>),
+}
+
+// decode_to_vec() into L8 or LA8 when every pixel is grey, Rgb8 or Rgba8
+// otherwise, following the channels of the header. the pixels go into the
+// grey layout until the first one in colour, then what is decoded so far is
+// spread into rgb or rgba and the same pass goes on from there
+#[cfg(feature = "alloc")]
+pub fn decode_to_vec_auto(data: &[u8]) -> Result<(DecodedPixels, qoi_header), core::fmt::Error> {
+    let header = qoi_header::from_bytes(data)?;
+    let pxs = header.width as usize * header.height as usize;
+    if !can_hold(data, pxs) {
+        return Err(core::fmt::Error);
+    }
+    let alpha = header.channels() == qoi_channels::Rgba;
+    let (gray_ch, ch) = if alpha { (2, 4) } else { (1, 3) };
+    let (gray_len, color_len) = pxs
+        .checked_mul(gray_ch)
+        .zip(pxs.checked_mul(ch))
+        .ok_or(core::fmt::Error)?;
+    let fill = if alpha {
+        simd::fill_rgba
+    } else {
+        simd::fill_rgb
+    };
+
+    let mut out = alloc::vec![0; gray_len];
+    let mut gray = true;
+    decode_ops(data, |cnt, len, [r, g, b, a]| {
+        if gray && (r != g || g != b) {
+            let mut color = alloc::vec![0; color_len];
+            for (dst, src) in color[..cnt * ch]
+                .chunks_exact_mut(ch)
+                .zip(out.chunks_exact(gray_ch))
+            {
+                dst[..3].fill(src[0]);
+                if alpha {
+                    dst[3] = src[1];
+                }
+            }
+            out = color;
+            gray = false;
+        }
+        if !gray {
+            fill(&mut out[cnt * ch..(cnt + len) * ch], [r, g, b, a]);
+        } else if alpha {
+            for px in out[cnt * 2..(cnt + len) * 2].chunks_exact_mut(2) {
+                px.copy_from_slice(&[g, a]);
+            }
+        } else {
+            out[cnt..cnt + len].fill(g);
+        }
+        Ok(())
+    })?;
+
+    let pixels = match (gray, alpha) {
+        (true, false) => DecodedPixels::L8(out),
+        (true, true) => DecodedPixels::La8(out),
+        (false, false) => DecodedPixels::Rgb8(out),
+        (false, true) => DecodedPixels::Rgba8(out),
+    };
+    Ok((pixels, header))
 }
 
+#[cfg(feature = "std")]
 pub struct Decoder<R: Read> {
     reader: R,
+    verbose: bool,
+    strict: bool,
+    premultiplied: bool,
 }
 
+#[cfg(feature = "std")]
 impl<R> Decoder<R>
 where
     R: Read,
 {
     pub fn new(reader: R) -> Self {
-        Decoder { reader }
+        Decoder {
+            reader,
+            verbose: false,
+            strict: false,
+            premultiplied: false,
+        }
+    }
+
+    // print every decoded op in the colour of the pixel it produced
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    // also fail on anything the spec forbids that decoding can get past:
+    // runs longer than the image, a missing end marker and bytes after it.
+    // verify::verify() tells which one it was
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    // hand out premultiplied pixels instead of the straight alpha qoi
+    // stores, rounded as alpha::premultiply() describes
+    pub fn premultiplied(mut self, premultiplied: bool) -> Self {
+        self.premultiplied = premultiplied;
+        self
     }
 
     pub fn decode(&mut self) -> Result<(Vec<[u8; 4]>, qoi_header), std::fmt::Error> {
         let mut prevpx = Pixels::start_prev();
         let mut hashmap = PixelHashMap::new();
 
-        let mut prevpxindex = prevpx.hash();
-
-        println!();
-        println!("DECODE START");
-        let magic = read_u32(&mut self.reader).unwrap();
-        // println!("magic: {:b}", magic);
-        let width = read_u32(&mut self.reader).unwrap() as u32;
-        // println!("w: {:b}", width);
-        let height = read_u32(&mut self.reader).unwrap() as u32;
-        // println!("h: {:b}", height);
-        let channels = read_u8(&mut self.reader).unwrap() as u8;
-        // println!("channels: {:b}", channels);
-        let colorspace = read_u8(&mut self.reader).unwrap() as u8;
-        // println!("colorspace: {:b}", colorspace);
-        let header = qoi_header::new(width, height, qoi_channels::Rgb, colorspace);
-        println!();
-
-        let pxs_write = width * height;
+        let header = read_header(&mut self.reader)?;
+
+        let pxs_write = header.width as usize * header.height as usize;
+
+        let mut run: u8 = 0;
+        // the header alone proves nothing about the stream behind it, so the
+        // buffer starts out small and grows as pixels are decoded
+        let mut rtn_data: Vec<[u8; 4]> = Vec::with_capacity(pxs_write.min(PREALLOC_PIXELS));
+        // prevpx in the layout the caller asked for
+        let mut outpx = prevpx.to_array();
+
+        for cnt in 0..pxs_write {
+            if run > 0 {
+                run -= 1;
+                rtn_data.push(outpx);
+                self.trace("RUN", cnt, prevpx);
+                continue;
+            }
+
+            let op = QoiOp::read(&mut self.reader)?;
+            if let QoiOp::Run(len) = op {
+                // the current pixel is the first one of the run
+                run = len - 1;
+            }
+            prevpx = op.apply(prevpx, &hashmap);
+
+            hashmap[prevpx.hash()] = prevpx;
+            outpx = if self.premultiplied {
+                premultiply(prevpx.to_array())
+            } else {
+                prevpx.to_array()
+            };
+            rtn_data.push(outpx);
+            self.trace(op.name(), cnt, prevpx);
+        }
+
+        if self.strict {
+            let end = read::<R, 8>(&mut self.reader)?;
+            let trailing = self.reader.read(&mut [0]).map_err(|_| std::fmt::Error)?;
+            if run > 0 || end != QOI_END || trailing != 0 {
+                return Err(std::fmt::Error);
+            }
+        }
 
+        if self.verbose {
+            println!("length: {}", rtn_data.len());
+        }
+        Ok((rtn_data, header))
+    }
+
+    // decode() of only the pixels inside `region`, see decode_region_into().
+    // reading stops after the last pixel of the region, so strict() has
+    // nothing to check and the reader is left in the middle of the file
+    pub fn decode_region(
+        &mut self,
+        region: Region,
+    ) -> Result<(Vec<[u8; 4]>, qoi_header), std::fmt::Error> {
+        let mut prevpx = Pixels::start_prev();
+        let mut hashmap = PixelHashMap::new();
+
+        let header = read_header(&mut self.reader)?;
+        if !region.fits(header.width, header.height) {
+            return Err(std::fmt::Error);
+        }
+
+        let end = region.end(header.width);
+        // as in decode(), the buffer grows with the pixels actually decoded.
+        // they reach the region in its own row order, so every piece goes at
+        // the end of what is there
+        let size = region.width as usize * region.height as usize;
+        let mut rtn_data: Vec<[u8; 4]> = Vec::with_capacity(size.min(PREALLOC_PIXELS));
+        let mut cnt = 0;
+
+        while cnt < end {
+            let op = QoiOp::read(&mut self.reader)?;
+            let len = match op {
+                QoiOp::Run(len) => usize::from(len).min(end - cnt),
+                _ => 1,
+            };
+            prevpx = op.apply(prevpx, &hashmap);
+            hashmap[prevpx.hash()] = prevpx;
+
+            let px = if self.premultiplied {
+                premultiply(prevpx.to_array())
+            } else {
+                prevpx.to_array()
+            };
+            region.overlap(header.width, cnt, len, |at, n| {
+                debug_assert_eq!(at, rtn_data.len());
+                rtn_data.resize(at + n, px);
+            });
+            for i in cnt..cnt + len {
+                self.trace(op.name(), i, prevpx);
+            }
+            cnt += len;
+        }
+
+        Ok((rtn_data, header))
+    }
+
+    // decode() into a thumbnail of at most max_width x max_height, see
+    // thumbnail::Sampler. the image itself is never held in memory, read
+    // from a file it is not even loaded
+    pub fn decode_thumbnail(
+        &mut self,
+        max_width: u32,
+        max_height: u32,
+        filter: ThumbnailFilter,
+    ) -> Result<(Thumbnail, qoi_header), std::fmt::Error> {
+        let mut prevpx = Pixels::start_prev();
+        let mut hashmap = PixelHashMap::new();
+
+        let header = read_header(&mut self.reader)?;
+        let pxs_write = header.width as usize * header.height as usize;
+        let mut sampler = Sampler::new(header.width, header.height, max_width, max_height, filter);
         let mut cnt = 0;
-        let mut run: i32 = 0;
-        let mut new_run = false;
-        let mut rtn_data: Vec<[u8; 4]> = Vec::new();
 
         while cnt < pxs_write {
-            cnt += 1;
-            run -= 1;
-            if run < 0 {
-                let byte_zero = read_u8(&mut self.reader).unwrap();
-                // print!("{:b} ", byte_zero);
-                match byte_zero as u8 {
-                    255 => {
-                        // RGBA
-                        let r = read_u8(&mut self.reader).unwrap() as u8;
-                        let g = read_u8(&mut self.reader).unwrap() as u8;
-                        let b = read_u8(&mut self.reader).unwrap() as u8;
-                        let a = read_u8(&mut self.reader).unwrap() as u8;
-                        rtn_data.push([r, g, b, a]);
-                        println!(
-                            "{}",
-                            format!("RGBA {}", cnt).on_custom_color(CustomColor::new(r, g, b)),
-                        );
-                        prevpx = Pixels::new(r, g, b, a);
-                        hashmap[prevpx.hash()] = prevpx;
-                    }
-                    254 => {
-                        // RGB
-                        let r = read_u8(&mut self.reader).unwrap() as u8;
-                        let g = read_u8(&mut self.reader).unwrap() as u8;
-                        let b = read_u8(&mut self.reader).unwrap() as u8;
-                        rtn_data.push([r, g, b, 255]);
-                        prevpx = Pixels::new(r, g, b, 255);
-                        hashmap[prevpx.hash()] = prevpx;
-                        println!(
-                            "{}",
-                            format!("RGB {}", cnt).on_custom_color(CustomColor::new(r, g, b))
-                        );
-                    }
-                    192..=253 => {
-                        // RUN
-                        run = (byte_zero - 192) as i32;
-                        cnt -= 1;
-                        new_run = true;
-                    }
-                    128..=191 => {
-                        // LUMA
-                        let dg = ((byte_zero & 0b0011_1111) as u8) - 32;
-                        let next = read_u8(&mut self.reader).unwrap();
-                        let dr_dg = (next & 0b1111_0000) >> 4;
-                        let db_dg = next & 0b0000_1111;
-
-                        let dr = dr_dg + dg - 8;
-                        let db = db_dg + dg - 8;
-
-                        let r = prevpx.r + dr;
-                        let g = prevpx.g + dg;
-                        let b = prevpx.b + db;
-
-                        rtn_data.push([r, g, b, 255]);
-                        prevpx = Pixels::new(r, g, b, 255);
-                        hashmap[prevpx.hash()] = prevpx;
-                        println!(
-                            "{}",
-                            format!("LUMA {}", cnt,).on_custom_color(CustomColor::new(r, g, b)),
-                        );
-                    }
-                    64..=127 => {
-                        // DIFF
-                        let rgb = byte_zero - 64;
-                        let dr = ((rgb & 0b0011_0000) >> 4) - 2;
-                        let dg = ((rgb & 0b0000_1100) >> 2) - 2;
-                        let db = (rgb & 0b0000_0011) - 2;
-
-                        let r = prevpx.r + dr;
-                        let g = prevpx.g + dg;
-                        let b = prevpx.b + db;
-
-                        rtn_data.push([r, g, b, 255]);
-                        prevpx = Pixels::new(r, g, b, 255);
-                        hashmap[prevpx.hash()] = prevpx;
-                        println!(
-                            "{}",
-                            format!("DIFF {}", cnt).on_custom_color(CustomColor::new(r, g, b)),
-                        );
-                    }
-                    0..=63 => {
-                        // INDEX
-                        let index = byte_zero;
-                        let px = hashmap[index];
-
-                        rtn_data.push([px.r, px.g, px.b, px.a]);
-                        prevpx = px;
-                        hashmap[prevpx.hash()] = prevpx;
-                        println!(
-                            "{}",
-                            format!("INDEX {}", cnt)
-                                .on_custom_color(CustomColor::new(px.r, px.g, px.b)),
-                        );
-                    }
-                }
+            let op = QoiOp::read(&mut self.reader)?;
+            let len = match op {
+                QoiOp::Run(len) => usize::from(len).min(pxs_write - cnt),
+                _ => 1,
+            };
+            prevpx = op.apply(prevpx, &hashmap);
+            hashmap[prevpx.hash()] = prevpx;
+
+            let px = if self.premultiplied {
+                premultiply(prevpx.to_array())
             } else {
-                if new_run {
-                    let byte_zero = read_u8(&mut self.reader).unwrap();
-                    match byte_zero as u8 {
-                        255 => {
-                            // RGBA
-                            let r = read_u8(&mut self.reader).unwrap() as u8;
-                            let g = read_u8(&mut self.reader).unwrap() as u8;
-                            let b = read_u8(&mut self.reader).unwrap() as u8;
-                            let a = read_u8(&mut self.reader).unwrap() as u8;
-                            prevpx = Pixels::new(r, g, b, a);
-                            hashmap[prevpx.hash()] = prevpx;
-                        }
-                        254 => {
-                            // RGB
-                            let r = read_u8(&mut self.reader).unwrap() as u8;
-                            let g = read_u8(&mut self.reader).unwrap() as u8;
-                            let b = read_u8(&mut self.reader).unwrap() as u8;
-                            prevpx = Pixels::new(r, g, b, 255);
-                            hashmap[prevpx.hash()] = prevpx;
-                        }
-                        192..=253 => {
-                            // RUN
-                            // more than 62
-                            run += (byte_zero - 192) as i32 + 1;
-                            new_run = true;
-                        }
-                        128..=191 => {
-                            // LUMA
-                            let dg = (byte_zero as u8) - 128 - 32;
-                            let next = read_u8(&mut self.reader).unwrap();
-                            let dr_dg = (next >> 4) - 8;
-                            let db_dg = (next & 0b0000_1111) - 8;
-
-                            let dr = dr_dg + dg;
-                            let db = db_dg + dg;
-
-                            let r = prevpx.r + dr;
-                            let g = prevpx.g + dg;
-                            let b = prevpx.b + db;
-
-                            prevpx = Pixels::new(r, g, b, 255);
-                            hashmap[prevpx.hash()] = prevpx;
-                        }
-                        64..=127 => {
-                            // DIFF
-                            let rgb = byte_zero - 64;
-                            let dr = ((rgb & 0b0011_0000) >> 4) - 2;
-                            let dg = ((rgb & 0b0000_1100) >> 2) - 2;
-                            let db = (rgb & 0b0000_0011) - 2;
-
-                            let r = prevpx.r + dr;
-                            let g = prevpx.g + dg;
-                            let b = prevpx.b + db;
-
-                            prevpx = Pixels::new(r, g, b, 255);
-                            hashmap[prevpx.hash()] = prevpx;
-                        }
-                        0..=63 => {
-                            // INDEX
-                            let index = byte_zero;
-                            let px = hashmap[index];
+                prevpx.to_array()
+            };
+            sampler.push(px, len);
+            cnt += len;
+        }
 
-                            prevpx = px;
-                            hashmap[prevpx.hash()] = prevpx;
-                        }
-                    }
-                    new_run = false;
+        Ok((sampler.finish(), header))
+    }
+
+    // walk the file like decode() does, but only count what every op costs
+    pub fn analyse(&mut self) -> Result<EncodeStats, std::fmt::Error> {
+        let mut disasm = Disassembler::new(&mut self.reader)?;
+        let mut stats = EncodeStats::new(disasm.header());
+        for chunk in disasm.by_ref() {
+            stats.record(&chunk?.op);
+        }
+        Ok(stats)
+    }
+
+    fn trace(&self, op: &str, cnt: usize, px: Pixels) {
+        if self.verbose {
+            println!(
+                "{}",
+                format!("{} {}", op, cnt).on_custom_color(CustomColor::new(px.r, px.g, px.b))
+            );
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::super::test_util::encode;
+    use super::*;
+
+    // grey rows, a run going on, then colour: the grey part has to come out
+    // the same once it is spread into rgb or rgba
+    fn image(colour_from: usize) -> Vec<[u8; 4]> {
+        (0..64usize)
+            .map(|i| {
+                let v = if (20..30).contains(&i) {
+                    90
+                } else {
+                    (i * 37) as u8
+                };
+                let a = (255 - i * 3) as u8;
+                if i >= colour_from {
+                    [v, v.wrapping_add(1), v, a]
+                } else {
+                    [v, v, v, a]
                 }
-                rtn_data.push([prevpx.r, prevpx.g, prevpx.b, prevpx.a]);
-                println!(
-                    "{}",
-                    format!("RUN {}", cnt)
-                        .on_custom_color(CustomColor::new(prevpx.r, prevpx.g, prevpx.b))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn auto_picks_the_smallest_layout_in_one_pass() {
+        for channels in [qoi_channels::Rgb, qoi_channels::Rgba] {
+            let ch = channels.to_bytes() as usize;
+            for colour_from in [0, 1, 25, 63, 64] {
+                let data = encode(&image(colour_from), 8, 8, channels);
+                let (full, _) = decode_to_vec(&data, channels).unwrap();
+                let (pixels, _) = decode_to_vec_auto(&data).unwrap();
+                let gray: Vec<u8> = full
+                    .chunks_exact(ch)
+                    .flat_map(|px| {
+                        if ch == 4 {
+                            vec![px[1], px[3]]
+                        } else {
+                            vec![px[1]]
+                        }
+                    })
+                    .collect();
+                let expected = match (colour_from == 64, ch == 4) {
+                    (true, false) => DecodedPixels::L8(gray),
+                    (true, true) => DecodedPixels::La8(gray),
+                    (false, false) => DecodedPixels::Rgb8(full),
+                    (false, true) => DecodedPixels::Rgba8(full),
+                };
+                assert_eq!(
+                    pixels, expected,
+                    "channels {} colour from {}",
+                    ch, colour_from
                 );
             }
         }
+    }
 
-        println!("length: {}", rtn_data.len());
-        println!("{:?}", rtn_data);
-        Ok((rtn_data, header))
+    #[test]
+    fn auto_refuses_more_pixels_than_the_data_holds() {
+        let mut data = encode(&image(64), 8, 8, qoi_channels::Rgba);
+        // 20000 x 20000 is within the limit, the 8x8 stream is not that long
+        data[4..12].copy_from_slice(&[0, 0, 0x4e, 0x20, 0, 0, 0x4e, 0x20]);
+        assert!(decode_to_vec_auto(&data).is_err());
+        data[4..12].copy_from_slice(&[0, 1, 0x86, 0xa0, 0, 1, 0x86, 0xa0]);
+        assert!(decode_to_vec_auto(&data).is_err());
+    }
+
+    // 13x9 with a run over the pixels 50..75, from the end of row 3 to the
+    // middle of row 5
+    const W: u32 = 13;
+    const H: u32 = 9;
+
+    fn atlas() -> Vec<[u8; 4]> {
+        (0..(W * H) as usize)
+            .map(|i| {
+                if (50..75).contains(&i) {
+                    [1, 2, 3, 255]
+                } else {
+                    [
+                        (i * 37) as u8,
+                        (i * 11 + 5) as u8,
+                        (255 - i * 2) as u8,
+                        200 + (i % 3) as u8,
+                    ]
+                }
+            })
+            .collect()
+    }
+
+    fn crop(pixels: &[[u8; 4]], r: Region) -> Vec<[u8; 4]> {
+        (r.y..r.y + r.height)
+            .flat_map(|y| (r.x..r.x + r.width).map(move |x| (y * W + x) as usize))
+            .map(|i| pixels[i])
+            .collect()
+    }
+
+    #[test]
+    fn region_is_a_crop_of_the_whole_image() {
+        let pixels = atlas();
+        let data = encode(&pixels, W, H, qoi_channels::Rgba);
+        for region in [
+            Region::new(0, 0, W, H),
+            Region::new(W - 4, H - 3, 4, 3),
+            Region::new(0, 2, 3, H - 2),
+            Region::new(0, 0, 1, 1),
+            Region::new(W - 1, H - 1, 1, 1),
+            Region::new(6, 4, 1, 1),
+            // ends in the middle of the run
+            Region::new(2, 3, 5, 2),
+        ] {
+            let expected = crop(&pixels, region);
+            let (decoded, header) = Decoder::new(&data[..]).decode_region(region).unwrap();
+            assert_eq!((header.width, header.height), (W, H));
+            assert_eq!(decoded, expected, "{:?}", region);
+
+            let (flat, _) = decode_region(&data, region, qoi_channels::Rgba).unwrap();
+            assert_eq!(flat, expected.concat(), "{:?}", region);
+        }
+    }
+
+    #[test]
+    fn region_outside_the_image_fails() {
+        let data = encode(&atlas(), W, H, qoi_channels::Rgba);
+        for region in [
+            Region::new(W - 2, 0, 3, 1),
+            Region::new(0, H, 1, 1),
+            Region::new(0, 1, 1, H),
+            Region::new(u32::MAX, 0, 2, 1),
+        ] {
+            assert!(Decoder::new(&data[..]).decode_region(region).is_err());
+            assert!(decode_region(&data, region, qoi_channels::Rgba).is_err());
+        }
+    }
+
+    #[test]
+    fn region_stops_reading_after_its_last_pixel() {
+        let data = encode(&atlas(), W, H, qoi_channels::Rgba);
+        for region in [
+            Region::new(2, 3, 5, 2),
+            Region::new(0, 0, 1, 1),
+            Region::new(1, 6, 2, 2),
+        ] {
+            let end = region.end(W) as u64;
+            // the byte after the chunk that produces the last pixel needed
+            let last = Disassembler::new(&data[..])
+                .unwrap()
+                .map(Result::unwrap)
+                .find(|c| c.pixel + u64::from(c.op.pixels()) >= end)
+                .unwrap();
+            let stop = last.offset as usize + last.op.size();
+
+            let mut rest = &data[..];
+            Decoder::new(&mut rest).decode_region(region).unwrap();
+            assert_eq!(data.len() - rest.len(), stop, "{:?}", region);
+            // nothing past it is needed either
+            assert!(Decoder::new(&data[..stop]).decode_region(region).is_ok());
+            assert!(decode_region(&data[..stop], region, qoi_channels::Rgba).is_ok());
+        }
+    }
+
+    // a header claiming a huge image and asking for a region of it, over a
+    // stream of a few bytes, fails on the missing chunks
+    #[test]
+    fn region_of_a_huge_header_is_not_allocated_up_front() {
+        let mut data = encode(&atlas(), W, H, qoi_channels::Rgba);
+        data[4..12].copy_from_slice(&[0, 0, 0x4e, 0x20, 0, 0, 0x4e, 0x20]);
+        let region = Region::new(0, 0, 20_000, 20_000);
+        assert!(Decoder::new(&data[..]).decode_region(region).is_err());
+        assert!(decode_region(&data, region, qoi_channels::Rgba).is_err());
+    }
+
+    #[test]
+    fn flipped_decode_is_the_normal_one_upside_down() {
+        let pixels = atlas();
+        for channels in [qoi_channels::Rgb, qoi_channels::Rgba] {
+            let ch = channels.to_bytes() as usize;
+            let data = encode(&pixels, W, H, channels);
+            let (mut expected, _) = decode_to_vec(&data, channels).unwrap();
+            let row = W as usize * ch;
+            expected = expected
+                .chunks_exact(row)
+                .rev()
+                .flatten()
+                .copied()
+                .collect();
+
+            let mut flipped = vec![0; expected.len()];
+            decode_into(&data, &mut flipped, channels, true).unwrap();
+            assert_eq!(flipped, expected, "channels {}", ch);
+            assert!(decode_into(&data, &mut flipped[ch..], channels, true).is_err());
+        }
+    }
+
+    #[test]
+    fn every_layout_matches_decode_into() {
+        let pixels = atlas();
+        let data = encode(&pixels, W, H, qoi_channels::Rgba);
+        let mut rgba = vec![0; pixels.len() * 4];
+        decode_into(&data, &mut rgba, qoi_channels::Rgba, false).unwrap();
+        for layout in PixelLayout::ALL {
+            let expected: Vec<u8> = rgba
+                .chunks_exact(4)
+                .flat_map(|px| {
+                    let (r, g, b, a) = (px[0], px[1], px[2], px[3]);
+                    let rgb565 =
+                        (u16::from(r) >> 3) << 11 | (u16::from(g) >> 2) << 5 | u16::from(b) >> 3;
+                    let argb =
+                        u32::from(a) << 24 | u32::from(r) << 16 | u32::from(g) << 8 | u32::from(b);
+                    match layout {
+                        PixelLayout::Rgb => vec![r, g, b],
+                        PixelLayout::Rgba => vec![r, g, b, a],
+                        PixelLayout::Bgra => vec![b, g, r, a],
+                        PixelLayout::Argb => vec![a, r, g, b],
+                        PixelLayout::Rgb565 => rgb565.to_ne_bytes().to_vec(),
+                        PixelLayout::U32 => argb.to_ne_bytes().to_vec(),
+                    }
+                })
+                .collect();
+            let mut out = vec![0; pixels.len() * layout.bytes_per_pixel()];
+            decode_into_layout(&data, &mut out, layout).unwrap();
+            assert_eq!(out, expected, "{}", layout.name());
+            assert_eq!(decode_to_vec_layout(&data, layout).unwrap().0, expected);
+            assert!(decode_into_layout(&data, &mut out[1..], layout).is_err());
+        }
+
+        let mut words = vec![0; pixels.len()];
+        decode_into_u32(&data, &mut words).unwrap();
+        let mut bytes = vec![0; pixels.len() * 4];
+        decode_into_layout(&data, &mut bytes, PixelLayout::U32).unwrap();
+        let packed: Vec<u32> = bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        assert_eq!(words, packed);
+        assert!(decode_into_u32(&data, &mut words[1..]).is_err());
+
+        let mut shorts = vec![0; pixels.len()];
+        decode_into_rgb565(&data, &mut shorts).unwrap();
+        let mut bytes = vec![0; pixels.len() * 2];
+        decode_into_layout(&data, &mut bytes, PixelLayout::Rgb565).unwrap();
+        let packed: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(shorts, packed);
+        assert!(decode_into_rgb565(&data, &mut shorts[1..]).is_err());
+    }
+
+    #[test]
+    fn u32_is_aarrggbb() {
+        let data = encode(&[[1, 2, 3, 4]], 1, 1, qoi_channels::Rgba);
+        let mut words = [0];
+        decode_into_u32(&data, &mut words).unwrap();
+        assert_eq!(words, [0x0401_0203]);
+        let mut bytes = [0; 4];
+        decode_into_layout(&data, &mut bytes, PixelLayout::U32).unwrap();
+        assert_eq!(bytes, 0x0401_0203u32.to_ne_bytes());
+        decode_into_layout(&data, &mut bytes, PixelLayout::Bgra).unwrap();
+        assert_eq!(bytes, [3, 2, 1, 4]);
+        decode_into_layout(&data, &mut bytes, PixelLayout::Argb).unwrap();
+        assert_eq!(bytes, [4, 1, 2, 3]);
+    }
+
+    // the low bits are cut, not rounded, so 0xff fills a field and 7 does
+    // not reach the first step of red or blue
+    #[test]
+    fn rgb565_packs_and_truncates() {
+        let pixels = [
+            [0xff, 0xff, 0xff, 255],
+            [0xff, 0, 0, 255],
+            [0, 0xff, 0, 255],
+            [0, 0, 0xff, 255],
+            [7, 3, 7, 255],
+            [8, 4, 8, 0],
+        ];
+        let data = encode(&pixels, 6, 1, qoi_channels::Rgba);
+        let mut out = [0; 6];
+        decode_into_rgb565(&data, &mut out).unwrap();
+        assert_eq!(
+            out,
+            [0xffff, 0x1f << 11, 0x3f << 5, 0x1f, 0, 1 << 11 | 1 << 5 | 1]
+        );
     }
 }
@@ -0,0 +1,243 @@
+use std::io::Read;
+
+use super::decoder::read_header;
+use super::header::{qoi_header, QOI_END};
+use super::op::QoiOp;
+use super::{PixelHashMap, Pixels};
+
+// a chunk of the data stream together with where it lands in the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    // byte offset of the chunk from the start of the file
+    pub offset: u64,
+    pub op: QoiOp,
+    // index of the first pixel the chunk produces, x and y are its position
+    pub pixel: u64,
+    pub x: u32,
+    pub y: u32,
+    // colour of the produced pixel(s)
+    pub color: Pixels,
+}
+
+// what follows the last chunk of the image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndMarker {
+    pub offset: u64,
+    // the bytes found where the end marker should be, at most 8
+    pub bytes: Vec<u8>,
+    // bytes left after the end marker
+    pub trailing: u64,
+}
+
+impl EndMarker {
+    pub fn is_valid(&self) -> bool {
+        self.bytes == QOI_END
+    }
+}
+
+// walks a qoi file chunk by chunk instead of pixel by pixel
+pub struct Disassembler<R: Read> {
+    reader: R,
+    header: qoi_header,
+    offset: u64,
+    pixel: u64,
+    prevpx: Pixels,
+    hashmap: PixelHashMap,
+}
+
+impl<R> Disassembler<R>
+where
+    R: Read,
+{
+    // read the header, the reader is left at the first chunk
+    pub fn new(mut reader: R) -> Result<Self, std::fmt::Error> {
+        let header = read_header(&mut reader)?;
+        Ok(Self::with_header(reader, header))
+    }
+
+    // the reader is already past a header that was parsed elsewhere
+    pub fn with_header(reader: R, header: qoi_header) -> Self {
+        Disassembler {
+            reader,
+            header,
+            offset: 14,
+            pixel: 0,
+            prevpx: Pixels::start_prev(),
+            hashmap: PixelHashMap::new(),
+        }
+    }
+
+    pub fn header(&self) -> &qoi_header {
+        &self.header
+    }
+
+    // byte offset of the next chunk
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn total_pixels(&self) -> u64 {
+        u64::from(self.header.width) * u64::from(self.header.height)
+    }
+
+    // the next chunk, or None once every pixel of the image is covered
+    pub fn next_chunk(&mut self) -> Result<Option<Chunk>, std::fmt::Error> {
+        if self.pixel >= self.total_pixels() {
+            return Ok(None);
+        }
+
+        let op = QoiOp::read(&mut self.reader)?;
+        self.prevpx = op.apply(self.prevpx, &self.hashmap);
+        self.hashmap[self.prevpx.hash()] = self.prevpx;
+
+        // an empty image never gets here, so width is not zero
+        let width = u64::from(self.header.width);
+        let chunk = Chunk {
+            offset: self.offset,
+            op,
+            pixel: self.pixel,
+            x: (self.pixel % width) as u32,
+            y: (self.pixel / width) as u32,
+            color: self.prevpx,
+        };
+
+        self.offset += op.size() as u64;
+        self.pixel += u64::from(op.pixels());
+        Ok(Some(chunk))
+    }
+
+    // read whatever is left after the last chunk
+    pub fn end_marker(mut self) -> Result<EndMarker, std::fmt::Error> {
+        let mut rest = Vec::new();
+        self.reader
+            .read_to_end(&mut rest)
+            .map_err(|_| std::fmt::Error)?;
+
+        let len = rest.len().min(QOI_END.len());
+        Ok(EndMarker {
+            offset: self.offset,
+            bytes: rest[..len].to_vec(),
+            trailing: (rest.len() - len) as u64,
+        })
+    }
+}
+
+impl<R> Iterator for Disassembler<R>
+where
+    R: Read,
+{
+    type Item = Result<Chunk, std::fmt::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::header::{qoi_channels, QoiColorspace};
+    use super::*;
+
+    // 3x2: RGB at 14, DIFF at 18, a run of 2 at 19, LUMA at 20 and INDEX at
+    // 22, then the end marker at 23
+    fn stream() -> Vec<u8> {
+        let mut data = qoi_header::new(3, 2, qoi_channels::Rgb, QoiColorspace::Srgb)
+            .to_bytes()
+            .to_vec();
+        data.extend_from_slice(&[0xfe, 10, 20, 30, 0x79, 0xc1, 0xaa, 0x88, 0x09]);
+        data.extend_from_slice(&QOI_END);
+        data
+    }
+
+    #[test]
+    fn chunks_know_where_they_are() {
+        let data = stream();
+        let mut disasm = Disassembler::new(&data[..]).unwrap();
+        assert_eq!(disasm.total_pixels(), 6);
+        let chunks: Vec<Chunk> = disasm.by_ref().map(Result::unwrap).collect();
+        let expected = [
+            (
+                14,
+                QoiOp::Rgb {
+                    r: 10,
+                    g: 20,
+                    b: 30,
+                },
+                0,
+                (0, 0),
+                [10, 20, 30],
+            ),
+            (
+                18,
+                QoiOp::Diff {
+                    dr: 1,
+                    dg: 0,
+                    db: -1,
+                },
+                1,
+                (1, 0),
+                [11, 20, 29],
+            ),
+            (19, QoiOp::Run(2), 2, (2, 0), [11, 20, 29]),
+            (
+                20,
+                QoiOp::Luma {
+                    dg: 10,
+                    dr_dg: 0,
+                    db_dg: 0,
+                },
+                4,
+                (1, 1),
+                [21, 30, 39],
+            ),
+            (22, QoiOp::Index(9), 5, (2, 1), [10, 20, 30]),
+        ];
+        assert_eq!(chunks.len(), expected.len());
+        for (chunk, (offset, op, pixel, (x, y), [r, g, b])) in chunks.iter().zip(expected) {
+            assert_eq!(
+                *chunk,
+                Chunk {
+                    offset,
+                    op,
+                    pixel,
+                    x,
+                    y,
+                    color: Pixels::new(r, g, b, 255),
+                }
+            );
+        }
+        assert_eq!(disasm.offset(), 23);
+
+        let end = disasm.end_marker().unwrap();
+        assert!(end.is_valid());
+        assert_eq!((end.offset, end.trailing), (23, 0));
+    }
+
+    #[test]
+    fn bad_end_marker() {
+        let mut data = stream();
+        *data.last_mut().unwrap() = 2;
+        data.extend_from_slice(&[7, 7]);
+        let mut disasm = Disassembler::new(&data[..]).unwrap();
+        assert_eq!(disasm.by_ref().count(), 5);
+        let end = disasm.end_marker().unwrap();
+        assert!(!end.is_valid());
+        assert_eq!(end.bytes, [0, 0, 0, 0, 0, 0, 0, 2]);
+        assert_eq!((end.offset, end.trailing), (23, 2));
+
+        // a short marker is not valid either
+        let data = stream();
+        let mut disasm = Disassembler::new(&data[..data.len() - 3]).unwrap();
+        assert_eq!(disasm.by_ref().count(), 5);
+        let end = disasm.end_marker().unwrap();
+        assert!(!end.is_valid());
+        assert_eq!(end.bytes.len(), 5);
+    }
+
+    #[test]
+    fn truncated_chunk_is_an_error() {
+        let data = stream();
+        let mut disasm = Disassembler::new(&data[..16]).unwrap();
+        assert_eq!(disasm.next(), Some(Err(std::fmt::Error)));
+    }
+}
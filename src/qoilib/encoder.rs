@@ -1,20 +1,85 @@
+#[cfg(feature = "std")]
 use colored::*;
-use image::EncodableLayout;
-use std::{env::current_dir, fmt::Error, io::Write};
+#[cfg(feature = "std")]
+use std::io::Write;
 
 use super::{
-    header::{
-        qoi_channels, qoi_header, QOI_END, QOI_OP_DIFF, QOI_OP_INDEX, QOI_OP_LUMA, QOI_OP_RGB,
-        QOI_OP_RUN,
-    },
+    alpha::unpremultiply,
+    error::EncodeError,
+    header::{qoi_channels, qoi_header, QoiColorspace, QOI_END, QOI_MAX_RUN},
+    op::QoiOp,
+    simd,
+    stats::EncodeStats,
     PixelHashMap, Pixels,
 };
 
+// pixels hashed in one go ahead of the encoding loop
+const HASH_BLOCK: usize = 256;
+// repeats counted one by one before the rest of the run goes to simd
+const SHORT_RUN: usize = 8;
+
+// the largest file an image of this size can encode to: every pixel a full
+// RGB or RGBA chunk, plus header and end marker. saturates instead of
+// overflowing, so no buffer is ever big enough for an impossible image
+pub const fn max_encoded_size(width: u32, height: u32, channels: qoi_channels) -> usize {
+    (width as usize)
+        .saturating_mul(height as usize)
+        .saturating_mul(channels as usize + 1)
+        .saturating_add(14 + QOI_END.len())
+}
+
+// Rgb when every pixel is fully opaque, Rgba otherwise
+pub fn detect_channels(data: &[[u8; 4]]) -> qoi_channels {
+    if data.iter().all(|px| px[3] == 255) {
+        qoi_channels::Rgb
+    } else {
+        qoi_channels::Rgba
+    }
+}
+
+// the pixel layouts the encoder reads
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    Rgba(&'a [[u8; 4]]),
+    // grey, expanded to r = g = b as the spec has no grey images
+    Luma(&'a [u8]),
+    LumaAlpha(&'a [[u8; 2]]),
+}
+
+impl Source<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Source::Rgba(data) => data.len(),
+            Source::Luma(data) => data.len(),
+            Source::LumaAlpha(data) => data.len(),
+        }
+    }
+
+    fn pixel(&self, i: usize) -> [u8; 4] {
+        match self {
+            Source::Rgba(data) => data[i],
+            Source::Luma(data) => [data[i], data[i], data[i], 255],
+            Source::LumaAlpha(data) => [data[i][0], data[i][0], data[i][0], data[i][1]],
+        }
+    }
+}
+
+// what Encoder::fit_size() settled on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeFit {
+    pub tolerance: u8,
+    pub stats: EncodeStats,
+}
+
 pub struct Encoder<'a> {
     // condier [u8; 4] as Rgb<u8>
     // then data is array of Rgb
-    data: &'a [[u8; 4]],
+    source: Source<'a>,
     header: qoi_header,
+    verbose: bool,
+    premultiplied: bool,
+    tolerance: u8,
+    bottom_up: bool,
 }
 
 impl<'a> Encoder<'a> {
@@ -23,78 +88,373 @@ impl<'a> Encoder<'a> {
         width: u32,
         height: u32,
         channels: qoi_channels,
-        colorspace: u8,
+        colorspace: QoiColorspace,
     ) -> Self {
         Encoder {
-            data,
+            source: Source::Rgba(data),
             header: qoi_header::new(width, height, channels, colorspace),
+            verbose: false,
+            premultiplied: false,
+            tolerance: 0,
+            bottom_up: false,
+        }
+    }
+
+    // a grey image, one byte per pixel, stored as 3 channels
+    pub fn luma(data: &'a [u8], width: u32, height: u32, colorspace: QoiColorspace) -> Self {
+        Encoder {
+            source: Source::Luma(data),
+            ..Encoder::new(&[], width, height, qoi_channels::Rgb, colorspace)
+        }
+    }
+
+    // a grey image with alpha, grey then alpha for every pixel, stored as 4
+    // channels
+    pub fn luma_alpha(
+        data: &'a [[u8; 2]],
+        width: u32,
+        height: u32,
+        colorspace: QoiColorspace,
+    ) -> Self {
+        Encoder {
+            source: Source::LumaAlpha(data),
+            ..Encoder::new(&[], width, height, qoi_channels::Rgba, colorspace)
         }
     }
 
+    // print every encoded pixel in its own colour
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    // the data is premultiplied, store it as the straight alpha qoi wants,
+    // rounded as alpha::unpremultiply() describes
+    pub fn premultiplied(mut self, premultiplied: bool) -> Self {
+        self.premultiplied = premultiplied;
+        self
+    }
+
+    // near-lossless: let every channel of a pixel end up to `tolerance`
+    // away from the source when that gives a shorter chunk. 0, the default,
+    // is lossless. the file is still plain qoi, EncodeStats::psnr() tells
+    // how much was lost
+    pub fn tolerance(mut self, tolerance: u8) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    // the rows of the source run bottom to top, as OpenGL reads them back,
+    // and are stored top to bottom like every qoi file
+    pub fn bottom_up(mut self, bottom_up: bool) -> Self {
+        self.bottom_up = bottom_up;
+        self
+    }
+
+    // ignore the channels given to new() and pick them with
+    // detect_channels(), channels() tells which one it was
+    pub fn auto_channels(mut self, auto: bool) -> Self {
+        if auto {
+            let pxs = self.header.width as usize * self.header.height as usize;
+            let channels = match self.source {
+                Source::Rgba(data) => detect_channels(&data[..pxs.min(data.len())]),
+                Source::Luma(_) => qoi_channels::Rgb,
+                Source::LumaAlpha(data) => {
+                    if data[..pxs.min(data.len())].iter().all(|px| px[1] == 255) {
+                        qoi_channels::Rgb
+                    } else {
+                        qoi_channels::Rgba
+                    }
+                }
+            };
+            self.header = qoi_header::new(
+                self.header.width,
+                self.header.height,
+                channels,
+                self.header.colorspace(),
+            );
+        }
+        self
+    }
+
+    // the channels the header will carry
+    pub fn channels(&self) -> qoi_channels {
+        self.header.channels()
+    }
+
     // return write bytes
+    #[cfg(feature = "std")]
     pub fn encode_to_buffer<W>(
         &self,
         buffer: &mut std::io::BufWriter<W>,
-    ) -> Result<usize, std::fmt::Error>
+    ) -> Result<usize, core::fmt::Error>
     where
         W: Write,
     {
-        let pxs_write = self.header.width * self.header.height;
-        let mut prevpx = Pixels::start_prev();
-        let mut hashmap = PixelHashMap::new();
+        Ok(self.encode_to_buffer_with_stats(buffer)?.total_bytes() as usize)
+    }
 
-        let mut prevpxindex = prevpx.hash();
+    // same as encode_to_buffer, but report what every op contributed
+    #[cfg(feature = "std")]
+    pub fn encode_to_buffer_with_stats<W>(
+        &self,
+        buffer: &mut std::io::BufWriter<W>,
+    ) -> Result<EncodeStats, core::fmt::Error>
+    where
+        W: Write,
+    {
+        let mut stats = EncodeStats::new(&self.header);
+        if self.verbose {
+            println!("channels: {}", self.header.channels().to_bytes());
+        }
 
         // write header into buffer
         buffer
-            .write(&self.header.to_bytes())
-            .expect("write header error");
+            .write_all(&self.header.to_bytes())
+            .map_err(|_| core::fmt::Error)?;
+
+        stats.squared_error = self.encode_ops(|op| {
+            buffer
+                .write_all(&op.to_bytes()[..op.size()])
+                .map_err(|_| core::fmt::Error)?;
+            stats.record(&op);
+            Ok(())
+        })?;
+
+        buffer.write_all(&QOI_END).map_err(|_| core::fmt::Error)?;
+
+        Ok(stats)
+    }
+
+    // encode into a buffer the caller owns, without touching the heap, and
+    // return the bytes written. a buffer of max_encoded_size() always fits,
+    // after BufferTooSmall `out` holds the start of the file
+    pub fn encode_to_slice(&self, out: &mut [u8]) -> Result<usize, EncodeError> {
+        let pxs_write = self.header.width as usize * self.header.height as usize;
+        if self.source.len() < pxs_write {
+            return Err(EncodeError::NotEnoughPixels {
+                expected: pxs_write,
+                found: self.source.len(),
+            });
+        }
+
+        // keep counting past the end of `out` to know the size to ask for
+        let mut pos = 0;
+        let mut write = |bytes: &[u8]| {
+            if let Some(dst) = out.get_mut(pos..pos + bytes.len()) {
+                dst.copy_from_slice(bytes);
+            }
+            pos += bytes.len();
+        };
+
+        write(&self.header.to_bytes());
+        // the only error is missing pixels, checked above
+        let _ = self.encode_ops(|op| {
+            write(&op.to_bytes()[..op.size()]);
+            Ok(())
+        });
+        write(&QOI_END);
+
+        if pos > out.len() {
+            return Err(EncodeError::BufferTooSmall { required: pos });
+        }
+        Ok(pos)
+    }
+
+    // encode_to_slice() into a freshly allocated buffer
+    #[cfg(feature = "alloc")]
+    pub fn encode_to_vec(&self) -> Result<alloc::vec::Vec<u8>, EncodeError> {
+        let h = self.header;
+        let mut out = alloc::vec![0; max_encoded_size(h.width, h.height, h.channels())];
+        let written = self.encode_to_slice(&mut out)?;
+        out.truncate(written);
+        Ok(out)
+    }
+
+    // collect the statistics without writing anything
+    pub fn stats(&self) -> Result<EncodeStats, core::fmt::Error> {
+        let mut stats = EncodeStats::new(&self.header);
+        stats.squared_error = self.encode_ops(|op| {
+            stats.record(&op);
+            Ok(())
+        })?;
+        Ok(stats)
+    }
+
+    // the smallest tolerance whose encoding takes at most `max_bytes`, and
+    // the stats of that encoding, None when not even 255 is enough. encode
+    // with .tolerance() set to the result to get the file
+    //
+    // a binary search over stats() passes: sizes shrink as the tolerance
+    // grows, but not strictly, so a smaller tolerance the search skipped
+    // over may sometimes fit as well
+    pub fn fit_size(&self, max_bytes: u64) -> Result<Option<SizeFit>, core::fmt::Error> {
+        let fits = |tolerance: u8| -> Result<Option<SizeFit>, core::fmt::Error> {
+            let stats = Encoder { tolerance, ..*self }.stats()?;
+            Ok((stats.total_bytes() <= max_bytes).then_some(SizeFit { tolerance, stats }))
+        };
+
+        if let Some(fit) = fits(0)? {
+            return Ok(Some(fit));
+        }
+        let Some(mut best) = fits(u8::MAX)? else {
+            return Ok(None);
+        };
+        // 0 is too big and best fits, narrow down in between
+        let (mut lo, mut hi) = (0u8, u8::MAX);
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            match fits(mid)? {
+                Some(fit) => {
+                    hi = mid;
+                    best = fit;
+                }
+                None => lo = mid,
+            }
+        }
+        Ok(Some(best))
+    }
+
+    // fit_size() for an average number of bits per pixel
+    pub fn fit_bits_per_pixel(
+        &self,
+        bits_per_pixel: f64,
+    ) -> Result<Option<SizeFit>, core::fmt::Error> {
+        let pixels = u64::from(self.header.width) * u64::from(self.header.height);
+        self.fit_size((bits_per_pixel * pixels as f64 / 8.0) as u64)
+    }
+
+    // feed every op of the data stream to `emit`, in order, and return the
+    // summed squared error of the decoded pixels against the source
+    fn encode_ops<F>(&self, mut emit: F) -> Result<u64, core::fmt::Error>
+    where
+        F: FnMut(QoiOp) -> Result<(), core::fmt::Error>,
+    {
+        if self.tolerance > 0 {
+            return self.encode_ops_lossy(emit);
+        }
+        let data = match self.source {
+            Source::Rgba(data) => data,
+            _ => return self.encode_ops_gray(emit),
+        };
+
+        let pxs_write = self.header.width as usize * self.header.height as usize;
+        if data.len() < pxs_write {
+            return Err(core::fmt::Error);
+        }
+        let pixels = &data[..pxs_write];
+        // a 3 channels image never carries alpha
+        let opaque = self.header.channels() == qoi_channels::Rgb;
+        let mut prevpx = Pixels::start_prev();
+        let mut hashmap = PixelHashMap::new();
+
+        // hashes of pixels[block_start..block_start + block_len]
+        let mut hashes = [0u8; HASH_BLOCK];
+        let (mut block_start, mut block_len) = (0, 0);
+
+        let straight = |raw: [u8; 4]| {
+            if self.premultiplied {
+                unpremultiply(raw)
+            } else {
+                raw
+            }
+        };
+        // premultiplied pixels with different alpha can share their colour
+        // after unpremultiply(), so they are compared as a whole
+        let run_ignores_alpha = opaque && !self.premultiplied;
+
+        // bottom up, only the pixels of one row follow each other in
+        // `pixels`, so runs and hash blocks stop at the end of the row
+        let width = self.header.width as usize;
+        let row_end = |cnt: usize| {
+            if self.bottom_up {
+                (cnt / width + 1) * width
+            } else {
+                pxs_write
+            }
+        };
 
         let mut run = 0;
         let mut cnt = 0;
-        let mut px = Pixels::start_prev();
 
         while cnt < pxs_write {
-            // get next px
-            px = Pixels::from(self.data[cnt as usize]);
-            let mut c = format!("{}", cnt).on_custom_color(CustomColor::new(px.r, px.g, px.b));
-            println!("{}", c);
-
-            cnt += 1;
+            let mut px = Pixels::from(straight(pixels[self.source_index(cnt)]));
+            if opaque {
+                px.a = 255;
+            }
 
-            // run != 63 and 64
             if px == prevpx {
+                self.trace(cnt, px);
                 run += 1;
-                if run == 62 {
-                    buffer.write(((QOI_OP_RUN << 6) | run).to_ne_bytes().as_ref());
-                    run = 0;
-                }
-            } else {
-                let mut new_run = false;
-                if run != 0 {
-                    new_run = true;
-                    if run == 1 {
-                        // run only count once which mean the prev px only find sequentialy one time
-                        buffer.write(((QOI_OP_INDEX << 6) | prevpx.hash()).to_ne_bytes().as_ref());
+                cnt += 1;
+                if run == SHORT_RUN {
+                    // a long run, find where it ends in one go. this only
+                    // matches the stored bytes, the loop picks up the rest
+                    let end = pxs_write.min(cnt + usize::from(QOI_MAX_RUN) - run);
+                    let len = if cnt < end {
+                        let end = end.min(row_end(cnt));
+                        let at = self.source_index(cnt);
+                        simd::run_length(
+                            &pixels[at..at + end - cnt],
+                            pixels[self.source_index(cnt - 1)],
+                            run_ignores_alpha,
+                        )
                     } else {
-                        // find a new none sequence px so write run into buffer first
-                        buffer.write(((QOI_OP_RUN << 6) | run).to_ne_bytes().as_ref());
-                        buffer.write(((QOI_OP_INDEX << 6) | prevpx.hash()).to_ne_bytes().as_ref());
+                        0
+                    };
+                    for i in cnt..cnt + len {
+                        self.trace(i, px);
                     }
+                    run += len;
+                    cnt += len;
+                }
+                if run == usize::from(QOI_MAX_RUN) || cnt == pxs_write {
+                    emit(QoiOp::Run(run as u8))?;
                     run = 0;
                 }
+                continue;
+            }
+            self.trace(cnt, px);
 
-                // get previous index
-                prevpxindex = prevpx.hash();
+            // find a new none sequence px so write run into buffer first
+            if run != 0 {
+                emit(QoiOp::Run(run as u8))?;
+                run = 0;
+            }
 
-                if px == hashmap[prevpxindex] {
-                    buffer.write(((QOI_OP_INDEX << 6) | prevpxindex).to_ne_bytes().as_ref());
+            if cnt >= block_start + block_len {
+                block_start = cnt;
+                block_len = HASH_BLOCK.min(row_end(cnt) - cnt);
+                let at = self.source_index(cnt);
+                let block = &pixels[at..at + block_len];
+                if self.premultiplied {
+                    let mut converted = [[0; 4]; HASH_BLOCK];
+                    for (px, raw) in converted.iter_mut().zip(block) {
+                        *px = unpremultiply(*raw);
+                    }
+                    simd::hash_pixels(&converted[..block_len], opaque, &mut hashes);
                 } else {
-                    // calculate pixel different
+                    simd::hash_pixels(block, opaque, &mut hashes);
+                }
+            }
+            let index = hashes[cnt - block_start];
+            if hashmap[index] == px {
+                emit(QoiOp::Index(index))?;
+            } else {
+                hashmap[index] = px;
 
-                    let diff_rgb = -2..1;
-                    let diff_rb = -8..7;
-                    let diff_g = -32..31;
+                if px.a != prevpx.a {
+                    emit(QoiOp::Rgba {
+                        r: px.r,
+                        g: px.g,
+                        b: px.b,
+                        a: px.a,
+                    })?;
+                } else {
+                    // calculate pixel different
+                    let diff_rgb = -2..=1;
+                    let diff_rb = -8..=7;
+                    let diff_g = -32..=31;
 
                     let dr = px.dr(prevpx);
                     let dg = px.dg(prevpx);
@@ -103,37 +463,571 @@ impl<'a> Encoder<'a> {
                     let db_dg = db.wrapping_sub(dg);
 
                     if diff_rgb.contains(&dr) && diff_rgb.contains(&dg) && diff_rgb.contains(&db) {
-                        let bytes_o_l = px.rgb_to_bytes(prevpx, super::DiffType::DIFF);
-                        buffer.write(((QOI_OP_DIFF << 6) | bytes_o_l[1]).to_ne_bytes().as_ref());
+                        emit(QoiOp::Diff { dr, dg, db })?;
                     } else if diff_g.contains(&dg)
                         && diff_rb.contains(&dr_dg)
                         && diff_rb.contains(&db_dg)
                     {
-                        let bytes_o_l = px.rgb_to_bytes(prevpx, super::DiffType::LUMA);
-                        buffer.write([(QOI_OP_LUMA << 6) | bytes_o_l[0], bytes_o_l[1]].as_ref());
+                        emit(QoiOp::Luma { dg, dr_dg, db_dg })?;
                     } else {
-                        buffer.write([(QOI_OP_RGB), (px.r), (px.g), (px.b)].as_ref());
+                        emit(QoiOp::Rgb {
+                            r: px.r,
+                            g: px.g,
+                            b: px.b,
+                        })?;
                     }
                 }
             }
-            hashmap[px.hash()] = px;
             prevpx = px;
+            cnt += 1;
         }
 
-        // last pixel is run and not write in
-        if run != 0 {
-            if run == 1 {
-                // run only count once which mean the prev px only find sequentialy one time
-                buffer.write(((QOI_OP_INDEX << 6) | prevpx.hash()).to_ne_bytes().as_ref());
+        Ok(0)
+    }
+
+    // the lossless encode_ops for grey sources, the same ops the rgba loop
+    // picks for the expanded pixels. r, g and b move together, the start
+    // pixel is black and unpremultiply() keeps grey grey, so a change is a
+    // DIFF, a LUMA with dr_dg = db_dg = 0 or an RGB
+    fn encode_ops_gray<F>(&self, mut emit: F) -> Result<u64, core::fmt::Error>
+    where
+        F: FnMut(QoiOp) -> Result<(), core::fmt::Error>,
+    {
+        let pxs_write = self.header.width as usize * self.header.height as usize;
+        if self.source.len() < pxs_write {
+            return Err(core::fmt::Error);
+        }
+        let opaque = self.header.channels() == qoi_channels::Rgb;
+        let mut prevpx = Pixels::start_prev();
+        let mut hashmap = PixelHashMap::new();
+        let mut run = 0;
+
+        for cnt in 0..pxs_write {
+            let raw = self.source.pixel(self.source_index(cnt));
+            let mut px = Pixels::from(if self.premultiplied {
+                unpremultiply(raw)
+            } else {
+                raw
+            });
+            if opaque {
+                px.a = 255;
+            }
+            self.trace(cnt, px);
+
+            if px == prevpx {
+                run += 1;
+                if run == QOI_MAX_RUN || cnt + 1 == pxs_write {
+                    emit(QoiOp::Run(run))?;
+                    run = 0;
+                }
+                continue;
+            }
+            if run != 0 {
+                emit(QoiOp::Run(run))?;
+                run = 0;
+            }
+
+            let index = px.hash();
+            if hashmap[index] == px {
+                emit(QoiOp::Index(index))?;
+            } else {
+                hashmap[index] = px;
+                let d = px.dg(prevpx);
+                if px.a != prevpx.a {
+                    emit(QoiOp::Rgba {
+                        r: px.r,
+                        g: px.g,
+                        b: px.b,
+                        a: px.a,
+                    })?;
+                } else if (-2..=1).contains(&d) {
+                    emit(QoiOp::Diff {
+                        dr: d,
+                        dg: d,
+                        db: d,
+                    })?;
+                } else if (-32..=31).contains(&d) {
+                    emit(QoiOp::Luma {
+                        dg: d,
+                        dr_dg: 0,
+                        db_dg: 0,
+                    })?;
+                } else {
+                    emit(QoiOp::Rgb {
+                        r: px.r,
+                        g: px.g,
+                        b: px.b,
+                    })?;
+                }
+            }
+            prevpx = px;
+        }
+
+        Ok(0)
+    }
+
+    // encode_ops with a tolerance. prevpx and hashmap hold what the decoder
+    // will have, not the source, and every choice is measured against the
+    // source pixel, so the error stays within the tolerance instead of
+    // adding up from one pixel to the next
+    //
+    // decoders disagree on whether RUN and INDEX file their pixel in the
+    // index: the reference one does, the qoi crate does not. that only
+    // matters where the pixel is not in its slot yet, the start pixel in
+    // slot 53 after a leading run and a pixel fetched from another slot
+    // than its own. such slots are left alone until an op every decoder
+    // files writes them, only slots known to hold the same pixel everywhere
+    // are indexed
+    fn encode_ops_lossy<F>(&self, mut emit: F) -> Result<u64, core::fmt::Error>
+    where
+        F: FnMut(QoiOp) -> Result<(), core::fmt::Error>,
+    {
+        let pxs_write = self.header.width as usize * self.header.height as usize;
+        if self.source.len() < pxs_write {
+            return Err(core::fmt::Error);
+        }
+        let opaque = self.header.channels() == qoi_channels::Rgb;
+        let mut prevpx = Pixels::start_prev();
+        let mut hashmap = PixelHashMap::new();
+        // bit i is set once slot i holds a pixel an op put there
+        let mut written = 0u64;
+        let mut squared_error = 0;
+        let mut run = 0;
+
+        for cnt in 0..pxs_write {
+            let raw = self.source.pixel(self.source_index(cnt));
+            let mut src = Pixels::from(if self.premultiplied {
+                unpremultiply(raw)
             } else {
-                // find a new none sequence px so write run into buffer first
-                buffer.write(((QOI_OP_RUN << 6) | run).to_ne_bytes().as_ref());
+                raw
+            });
+            if opaque {
+                src.a = 255;
+            }
+            self.trace(cnt, src);
+
+            if max_error(prevpx, src) <= self.tolerance {
+                run += 1;
+                squared_error += squared_distance(prevpx, src);
+                if run == QOI_MAX_RUN || cnt + 1 == pxs_write {
+                    emit(QoiOp::Run(run))?;
+                    run = 0;
+                }
+                continue;
+            }
+            if run != 0 {
+                emit(QoiOp::Run(run))?;
+                run = 0;
+            }
+
+            let (op, px) = self.approximate(src, prevpx, &hashmap, written);
+            emit(op)?;
+            match op {
+                QoiOp::Index(i) if i != px.hash() => written &= !(1 << px.hash()),
+                QoiOp::Index(_) => {}
+                _ => {
+                    hashmap[px.hash()] = px;
+                    written |= 1 << px.hash();
+                }
+            }
+            squared_error += squared_distance(px, src);
+            prevpx = px;
+        }
+
+        Ok(squared_error)
+    }
+
+    // the shortest chunk that gets within the tolerance of `src`, and the
+    // pixel the decoder will make of it
+    fn approximate(
+        &self,
+        src: Pixels,
+        prevpx: Pixels,
+        hashmap: &PixelHashMap,
+        written: u64,
+    ) -> (QoiOp, Pixels) {
+        let tolerance = self.tolerance;
+
+        // any written slot will do, not only the one src hashes to
+        let index = (0..64u8)
+            .filter(|i| written & (1 << i) != 0)
+            .map(|i| (max_error(hashmap[i], src), i))
+            .filter(|(error, _)| *error <= tolerance)
+            .min();
+        if let Some((_, i)) = index {
+            return (QoiOp::Index(i), hashmap[i]);
+        }
+
+        if src.a.abs_diff(prevpx.a) > tolerance {
+            let op = QoiOp::Rgba {
+                r: src.r,
+                g: src.g,
+                b: src.b,
+                a: src.a,
+            };
+            return (op, src);
+        }
+
+        // the deltas that would be exact, then clamped into what DIFF and
+        // LUMA can hold
+        let (dr, dg, db) = (src.dr(prevpx), src.dg(prevpx), src.db(prevpx));
+        let diff = QoiOp::Diff {
+            dr: dr.clamp(-2, 1),
+            dg: dg.clamp(-2, 1),
+            db: db.clamp(-2, 1),
+        };
+        let luma_dg = dg.clamp(-32, 31);
+        let luma = QoiOp::Luma {
+            dg: luma_dg,
+            dr_dg: dr.wrapping_sub(luma_dg).clamp(-8, 7),
+            db_dg: db.wrapping_sub(luma_dg).clamp(-8, 7),
+        };
+        for op in [diff, luma] {
+            let px = op.apply(prevpx, hashmap);
+            if max_error(px, src) <= tolerance {
+                return (op, px);
+            }
+        }
+
+        let px = Pixels::new(src.r, src.g, src.b, prevpx.a);
+        let op = QoiOp::Rgb {
+            r: src.r,
+            g: src.g,
+            b: src.b,
+        };
+        (op, px)
+    }
+
+    // where the cnt-th pixel of the file is in the source
+    #[inline(always)]
+    fn source_index(&self, cnt: usize) -> usize {
+        if self.bottom_up {
+            let width = self.header.width as usize;
+            (self.header.height as usize - 1 - cnt / width) * width + cnt % width
+        } else {
+            cnt
+        }
+    }
+
+    // the check stays in the loop, the printing does not
+    #[inline(always)]
+    fn trace(&self, cnt: usize, px: Pixels) {
+        if self.verbose {
+            trace(cnt, px);
+        }
+    }
+}
+
+#[cold]
+#[cfg(feature = "std")]
+fn trace(cnt: usize, px: Pixels) {
+    println!(
+        "{}",
+        format!("{}", cnt).on_custom_color(CustomColor::new(px.r, px.g, px.b))
+    );
+}
+
+// largest difference over the four channels
+fn max_error(a: Pixels, b: Pixels) -> u8 {
+    a.r.abs_diff(b.r)
+        .max(a.g.abs_diff(b.g))
+        .max(a.b.abs_diff(b.b))
+        .max(a.a.abs_diff(b.a))
+}
+
+fn squared_distance(a: Pixels, b: Pixels) -> u64 {
+    [(a.r, b.r), (a.g, b.g), (a.b, b.b), (a.a, b.a)]
+        .iter()
+        .map(|&(x, y)| u64::from(x.abs_diff(y)).pow(2))
+        .sum()
+}
+
+// nowhere to print to without std
+#[cfg(not(feature = "std"))]
+fn trace(_cnt: usize, _px: Pixels) {}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::super::test_util::Lcg;
+    use super::*;
+
+    fn encode(pixels: &[[u8; 4]], w: u32, h: u32, ch: qoi_channels, tolerance: u8) -> Vec<u8> {
+        Encoder::new(pixels, w, h, ch, QoiColorspace::Srgb)
+            .tolerance(tolerance)
+            .encode_to_vec()
+            .unwrap()
+    }
+
+    // small images of few colours near the start pixel and transparent
+    // black, so leading runs, reused slots and index hits all come up
+    fn random_image(rng: &mut Lcg, w: u32, h: u32) -> Vec<[u8; 4]> {
+        let mut next = || rng.byte();
+        let palette: Vec<[u8; 4]> = (0..8)
+            .map(|_| {
+                let base: [u8; 4] = [0, 0, 0, if next() % 2 == 0 { 0 } else { 255 }];
+                base.map(|c| c.wrapping_add(next() % 24).wrapping_sub(12))
+            })
+            .collect();
+        (0..w * h)
+            .map(|_| palette[usize::from(next() % 8)])
+            .collect()
+    }
+
+    // the lossy stream has to stay within the tolerance in decoders that
+    // do not file RUN and INDEX pixels in the index too
+    #[test]
+    fn lossy_output_decodes_within_tolerance_with_the_qoi_crate() {
+        let mut rng = Lcg(1);
+        for n in 0..150 {
+            let (w, h) = (1 + n % 13, 1 + n % 7);
+            let pixels = random_image(&mut rng, w, h);
+            for channels in [qoi_channels::Rgb, qoi_channels::Rgba] {
+                let tolerance = 1 + (n % 20) as u8;
+                let data = encode(&pixels, w, h, channels, tolerance);
+                let (_, decoded) = qoi::decode_to_vec(&data).unwrap();
+                // the decoder of this crate files them, like the reference one
+                let (ours, _) = super::super::decoder::decode_to_vec(&data, channels).unwrap();
+                assert_eq!(ours, decoded);
+                let ch = channels.to_bytes() as usize;
+                for (src, out) in pixels.iter().zip(decoded.chunks_exact(ch)) {
+                    let src = if ch == 3 { &src[..3] } else { &src[..] };
+                    let error = src.iter().zip(out).map(|(a, b)| a.abs_diff(*b)).max();
+                    assert!(
+                        error <= Some(tolerance),
+                        "image {} tolerance {} channels {}: {:?} became {:?}",
+                        n,
+                        tolerance,
+                        ch,
+                        src,
+                        out
+                    );
+                }
+            }
+        }
+    }
+
+    // runs that go on over the end of a row have to end up in the right one
+    #[test]
+    fn bottom_up_encode_decodes_to_the_flipped_source() {
+        let mut rng = Lcg(7);
+        for (w, h) in [(1, 1), (5, 3), (13, 7), (1, 9), (9, 1)] {
+            let pixels = random_image(&mut rng, w, h);
+            let mut flipped = pixels.clone();
+            super::super::orient::flip_vertical(&mut flipped, w);
+            for channels in [qoi_channels::Rgb, qoi_channels::Rgba] {
+                let encoder =
+                    Encoder::new(&pixels, w, h, channels, QoiColorspace::Srgb).bottom_up(true);
+                let out = encoder.encode_to_vec().unwrap();
+                let mut buffer = std::io::BufWriter::new(Vec::new());
+                encoder.encode_to_buffer(&mut buffer).unwrap();
+                assert_eq!(buffer.into_inner().unwrap(), out);
+
+                let (decoded, _) = super::super::decoder::decode_to_vec(&out, channels).unwrap();
+                let (expected, _) = super::super::decoder::decode_to_vec(
+                    &encode(&flipped, w, h, channels, 0),
+                    channels,
+                )
+                .unwrap();
+                assert_eq!(decoded, expected, "{}x{} channels {:?}", w, h, channels);
             }
-            run = 0;
         }
+    }
+
+    // noise where every pixel needs a full chunk: each one is new, so the
+    // index never has it, green jumps too far for DIFF and LUMA and with 4
+    // channels alpha changes every time
+    fn noise(rng: &mut Lcg, len: usize, alpha: bool) -> Vec<[u8; 4]> {
+        let mut g = 0u8;
+        (0..len)
+            .map(|i| {
+                g = g.wrapping_add(64 + rng.byte() % 128);
+                let a = if alpha { i as u8 } else { 255 };
+                [i as u8, g, (i >> 8) as u8, a]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn noise_takes_exactly_max_encoded_size() {
+        let mut rng = Lcg(3);
+        for channels in [qoi_channels::Rgb, qoi_channels::Rgba] {
+            for (w, h) in [(1, 1), (7, 5), (64, 64)] {
+                let pixels = noise(&mut rng, (w * h) as usize, channels == qoi_channels::Rgba);
+                let encoder = Encoder::new(&pixels, w, h, channels, QoiColorspace::Srgb);
+                let mut out = vec![0; max_encoded_size(w, h, channels)];
+                assert_eq!(encoder.encode_to_slice(&mut out), Ok(out.len()));
+            }
+        }
+    }
+
+    #[test]
+    fn too_small_a_buffer_reports_the_exact_size() {
+        let pixels = random_image(&mut Lcg(5), 9, 4);
+        let encoder = Encoder::new(&pixels, 9, 4, qoi_channels::Rgba, QoiColorspace::Srgb);
+        let file = encoder.encode_to_vec().unwrap();
+        let mut out = vec![0; file.len() - 1];
+        assert_eq!(
+            encoder.encode_to_slice(&mut out),
+            Err(EncodeError::BufferTooSmall {
+                required: file.len()
+            })
+        );
+        // what fitted is the start of the file
+        assert_eq!(out[..], file[..file.len() - 1]);
+        assert_eq!(
+            encoder.encode_to_slice(&mut []),
+            Err(EncodeError::BufferTooSmall {
+                required: file.len()
+            })
+        );
+    }
+
+    #[test]
+    fn not_enough_pixels() {
+        let pixels = [[1, 2, 3, 4]; 11];
+        let mut out = vec![0; max_encoded_size(4, 3, qoi_channels::Rgba)];
+        assert_eq!(
+            Encoder::new(&pixels, 4, 3, qoi_channels::Rgba, QoiColorspace::Srgb)
+                .encode_to_slice(&mut out),
+            Err(EncodeError::NotEnoughPixels {
+                expected: 12,
+                found: 11
+            })
+        );
+    }
+
+    // a noisy gradient, which a few steps of tolerance shrink a lot
+    fn gradient(rng: &mut Lcg, w: u32, h: u32) -> Vec<[u8; 4]> {
+        (0..w * h)
+            .map(|i| {
+                let (x, y) = ((i % w) as u8, (i / w) as u8);
+                [
+                    x.wrapping_mul(4).wrapping_add(rng.byte() % 8),
+                    y.wrapping_mul(4).wrapping_add(rng.byte() % 8),
+                    x.wrapping_add(y).wrapping_add(rng.byte() % 8),
+                    255,
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fit_size_finds_the_smallest_tolerance() {
+        let pixels = gradient(&mut Lcg(9), 32, 32);
+        let encoder = Encoder::new(&pixels, 32, 32, qoi_channels::Rgb, QoiColorspace::Srgb);
+        let size = |tolerance: u8| {
+            Encoder {
+                tolerance,
+                ..encoder
+            }
+            .stats()
+            .unwrap()
+            .total_bytes()
+        };
+        let lossless = size(0);
+        assert_eq!(lossless, encoder.encode_to_vec().unwrap().len() as u64);
+
+        // already met without losing anything
+        let fit = encoder.fit_size(lossless).unwrap().unwrap();
+        assert_eq!((fit.tolerance, fit.stats.total_bytes()), (0, lossless));
+
+        // not even header and end marker fit
+        assert_eq!(encoder.fit_size(21).unwrap(), None);
+
+        for max_bytes in [lossless - 1, lossless * 3 / 4, lossless / 2, size(255)] {
+            let fit = encoder.fit_size(max_bytes).unwrap().unwrap();
+            assert!(fit.tolerance > 0);
+            assert_eq!(fit.stats.total_bytes(), size(fit.tolerance));
+            assert!(fit.stats.total_bytes() <= max_bytes);
+            assert!(size(fit.tolerance - 1) > max_bytes, "{}", max_bytes);
+            let file = Encoder {
+                tolerance: fit.tolerance,
+                ..encoder
+            }
+            .encode_to_vec()
+            .unwrap();
+            assert_eq!(file.len() as u64, fit.stats.total_bytes());
+        }
+    }
+
+    #[test]
+    fn bits_per_pixel_become_bytes() {
+        let pixels = gradient(&mut Lcg(10), 16, 8);
+        let encoder = Encoder::new(&pixels, 16, 8, qoi_channels::Rgb, QoiColorspace::Srgb);
+        // 128 pixels, so 4 bits are 64 bytes and 3.3 bits 52.8, rounded down
+        for (bpp, bytes) in [(24.0, 384), (12.0, 192), (4.0, 64), (3.3, 52)] {
+            assert_eq!(
+                encoder.fit_bits_per_pixel(bpp).unwrap(),
+                encoder.fit_size(bytes).unwrap(),
+                "{} bits per pixel",
+                bpp
+            );
+        }
+        let fit = encoder.fit_bits_per_pixel(12.0).unwrap().unwrap();
+        assert!(fit.stats.bits_per_pixel() <= 12.0);
+    }
+
+    #[test]
+    fn detects_channels_from_alpha() {
+        assert_eq!(detect_channels(&[[1, 2, 3, 255]; 5]), qoi_channels::Rgb);
+        let mut pixels = [[1, 2, 3, 255]; 5];
+        pixels[4][3] = 254;
+        assert_eq!(detect_channels(&pixels), qoi_channels::Rgba);
+        pixels[4][3] = 0;
+        assert_eq!(detect_channels(&pixels), qoi_channels::Rgba);
+        // nothing to need alpha for
+        assert_eq!(detect_channels(&[]), qoi_channels::Rgb);
+    }
+
+    #[test]
+    fn auto_channels_replaces_the_given_ones() {
+        fn new(pixels: &[[u8; 4]], w: u32, h: u32, channels: qoi_channels) -> Encoder<'_> {
+            Encoder::new(pixels, w, h, channels, QoiColorspace::Linear)
+        }
+        let mut pixels = [[9, 8, 7, 255]; 6];
+        let encoder = new(&pixels, 3, 2, qoi_channels::Rgba).auto_channels(true);
+        assert_eq!(encoder.channels(), qoi_channels::Rgb);
+        assert_eq!(encoder.encode_to_vec().unwrap()[12..14], [3, 1]);
+        assert_eq!(
+            new(&pixels, 3, 2, qoi_channels::Rgba)
+                .auto_channels(false)
+                .channels(),
+            qoi_channels::Rgba
+        );
 
-        buffer.write(QOI_END.to_ne_bytes().as_ref());
+        pixels[5][3] = 100;
+        assert_eq!(
+            new(&pixels, 3, 2, qoi_channels::Rgb)
+                .auto_channels(true)
+                .channels(),
+            qoi_channels::Rgba
+        );
+        // only the pixels of the image count
+        assert_eq!(
+            new(&pixels, 5, 1, qoi_channels::Rgba)
+                .auto_channels(true)
+                .channels(),
+            qoi_channels::Rgb
+        );
+        assert_eq!(
+            new(&[], 0, 0, qoi_channels::Rgba)
+                .auto_channels(true)
+                .channels(),
+            qoi_channels::Rgb
+        );
 
-        return Ok(buffer.buffer().len());
+        let luma_alpha = |a| {
+            Encoder::luma_alpha(&[[5, 255], [6, a]], 2, 1, QoiColorspace::Srgb)
+                .auto_channels(true)
+                .channels()
+        };
+        assert_eq!(luma_alpha(255), qoi_channels::Rgb);
+        assert_eq!(luma_alpha(3), qoi_channels::Rgba);
+        assert_eq!(
+            Encoder::luma(&[1, 2], 2, 1, QoiColorspace::Srgb)
+                .auto_channels(true)
+                .channels(),
+            qoi_channels::Rgb
+        );
     }
 }
@@ -1 +1,119 @@
+use core::fmt;
 
+// the reference decoder refuses images with more pixels than this
+pub const QOI_PIXELS_MAX: u64 = 400_000_000;
+
+// a way a file can break the qoi specification, offsets are in bytes from
+// the start of the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QoiError {
+    BadMagic([u8; 4]),
+    BadChannels(u8),
+    BadColorspace(u8),
+    BadDimensions { width: u32, height: u32 },
+    // the data ends before every pixel is decoded
+    Truncated { offset: u64 },
+    // a run claims more pixels than the image has left
+    RunOverrun { offset: u64, pixels: u64 },
+    // the 8 byte end marker is missing or wrong
+    MissingPadding { offset: u64 },
+    TrailingBytes { offset: u64, count: u64 },
+    // re-encoding the pixels gives different bytes from this offset on
+    NotCanonical { offset: u64 },
+}
+
+impl QoiError {
+    // short name for machine readable reports
+    pub fn kind(&self) -> &'static str {
+        match self {
+            QoiError::BadMagic(_) => "bad_magic",
+            QoiError::BadChannels(_) => "bad_channels",
+            QoiError::BadColorspace(_) => "bad_colorspace",
+            QoiError::BadDimensions { .. } => "bad_dimensions",
+            QoiError::Truncated { .. } => "truncated",
+            QoiError::RunOverrun { .. } => "run_overrun",
+            QoiError::MissingPadding { .. } => "missing_padding",
+            QoiError::TrailingBytes { .. } => "trailing_bytes",
+            QoiError::NotCanonical { .. } => "not_canonical",
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        match self {
+            QoiError::BadMagic(_) => 0,
+            QoiError::BadDimensions { .. } => 4,
+            QoiError::BadChannels(_) => 12,
+            QoiError::BadColorspace(_) => 13,
+            QoiError::Truncated { offset }
+            | QoiError::RunOverrun { offset, .. }
+            | QoiError::MissingPadding { offset }
+            | QoiError::TrailingBytes { offset, .. }
+            | QoiError::NotCanonical { offset } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for QoiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QoiError::BadMagic(magic) => write!(f, "bad magic {:?}", magic),
+            QoiError::BadChannels(channels) => {
+                write!(f, "channels byte is {}, expected 3 or 4", channels)
+            }
+            QoiError::BadColorspace(colorspace) => {
+                write!(f, "colorspace byte is {}, expected 0 or 1", colorspace)
+            }
+            QoiError::BadDimensions { width, height } => {
+                write!(f, "unsupported image size {}x{}", width, height)
+            }
+            QoiError::Truncated { offset } => write!(f, "data ends at offset {}", offset),
+            QoiError::RunOverrun { offset, pixels } => write!(
+                f,
+                "run at offset {} overshoots the image by {} pixels",
+                offset, pixels
+            ),
+            QoiError::MissingPadding { offset } => {
+                write!(f, "missing or wrong end marker at offset {}", offset)
+            }
+            QoiError::TrailingBytes { offset, count } => {
+                write!(f, "{} trailing bytes at offset {}", count, offset)
+            }
+            QoiError::NotCanonical { offset } => {
+                write!(f, "re-encoding differs from offset {}", offset)
+            }
+        }
+    }
+}
+
+impl core::error::Error for QoiError {}
+
+// for the functions that only tell that decoding failed
+impl From<QoiError> for fmt::Error {
+    fn from(_: QoiError) -> Self {
+        fmt::Error
+    }
+}
+
+// why Encoder::encode_to_slice gave up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    // the output has to hold `required` bytes, the exact size of the file
+    BufferTooSmall { required: usize },
+    // the pixel data is shorter than width * height
+    NotEnoughPixels { expected: usize, found: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::BufferTooSmall { required } => {
+                write!(f, "output buffer too small, {} bytes needed", required)
+            }
+            EncodeError::NotEnoughPixels { expected, found } => {
+                write!(f, "expected {} pixels, got {}", expected, found)
+            }
+        }
+    }
+}
+
+impl core::error::Error for EncodeError {}
@@ -0,0 +1,507 @@
+// qoih, an experimental 16 bit per channel variant of qoi for hdr images.
+// not part of the qoi specification and not readable by other decoders
+//
+// the header is the qoi header with its own magic and one more byte:
+//
+//   magic       4 bytes  "qoih"
+//   width       4 bytes  big endian
+//   height      4 bytes  big endian
+//   channels    1 byte   3 or 4
+//   colorspace  1 byte   0 sRGB, 1 linear
+//   format      1 byte   SampleFormat
+//
+// the ops are those of qoi on 16 bit channels, with wrapping differences,
+// the same index hash and the same end marker. a run holds at most 60
+// pixels, which frees two tags for wider LUMA ops:
+//
+//   0x00..0x3f  INDEX       as in qoi
+//   0x40..0x7f  DIFF        as in qoi
+//   0x80..0xbf  LUMA        as in qoi, 2 bytes
+//   0xc0..0xfb  RUN         1 to 60 pixels
+//   0xfc        LUMA_WIDE   dg, dr - dg and db - dg, 8 bits each, 4 bytes
+//   0xfd        LUMA_HUGE   dg in 12 bits, dr - dg and db - dg in 10 bits
+//                           each, big endian after the tag, 5 bytes
+//   0xfe        RGB         3 big endian u16, 7 bytes
+//   0xff        RGBA        4 big endian u16, 9 bytes
+use core::fmt;
+
+use super::error::EncodeError;
+use super::header::{qoi_channels, size_allowed, QoiColorspace, QOI_END};
+use super::pixel::Pixels16;
+
+pub const HDR_MAGIC: [u8; 4] = *b"qoih";
+pub const HDR_HEADER_SIZE: usize = 15;
+pub const HDR_MAX_RUN: u8 = 60;
+
+const OP_INDEX: u8 = 0b00;
+const OP_DIFF: u8 = 0b01;
+const OP_LUMA: u8 = 0b10;
+const OP_RUN: u8 = 0b11;
+const OP_LUMA_WIDE: u8 = 0xfc;
+const OP_LUMA_HUGE: u8 = 0xfd;
+const OP_RGB: u8 = 0xfe;
+const OP_RGBA: u8 = 0xff;
+
+// what the 16 bits of a channel mean
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SampleFormat {
+    // 0 to 65535 for 0.0 to 1.0, like a 16 bit png
+    #[default]
+    Unorm16 = 0,
+    // the bits of an IEEE 754 half float, like an exr
+    Half = 1,
+}
+
+impl SampleFormat {
+    // the bits of 1.0, an opaque alpha
+    pub fn one(&self) -> u16 {
+        match self {
+            SampleFormat::Unorm16 => u16::MAX,
+            SampleFormat::Half => 0x3c00,
+        }
+    }
+}
+
+impl TryFrom<u8> for SampleFormat {
+    type Error = fmt::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(SampleFormat::Unorm16),
+            1 => Ok(SampleFormat::Half),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HdrHeader {
+    pub width: u32,
+    pub height: u32,
+    pub channels: qoi_channels,
+    pub colorspace: QoiColorspace,
+    pub format: SampleFormat,
+}
+
+impl HdrHeader {
+    pub fn to_bytes(&self) -> [u8; HDR_HEADER_SIZE] {
+        let mut bytes = [0; HDR_HEADER_SIZE];
+        bytes[..4].copy_from_slice(&HDR_MAGIC);
+        bytes[4..8].copy_from_slice(&self.width.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.height.to_be_bytes());
+        bytes[12] = self.channels.to_bytes();
+        bytes[13] = self.colorspace.to_bytes();
+        bytes[14] = self.format as u8;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, fmt::Error> {
+        let bytes = bytes.get(..HDR_HEADER_SIZE).ok_or(fmt::Error)?;
+        if bytes[..4] != HDR_MAGIC {
+            return Err(fmt::Error);
+        }
+        let u32_at =
+            |i: usize| u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let (width, height) = (u32_at(4), u32_at(8));
+        if !size_allowed(width, height) {
+            return Err(fmt::Error);
+        }
+        Ok(HdrHeader {
+            width,
+            height,
+            channels: bytes[12].try_into()?,
+            colorspace: bytes[13].try_into()?,
+            format: bytes[14].try_into()?,
+        })
+    }
+
+    pub fn pixels(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+pub fn is_hdr(data: &[u8]) -> bool {
+    data.starts_with(&HDR_MAGIC)
+}
+
+// the largest file an image of this size can encode to, every pixel a full
+// RGB or RGBA chunk. saturates like encoder::max_encoded_size
+pub const fn max_encoded_size(width: u32, height: u32, channels: qoi_channels) -> usize {
+    let per_pixel = match channels {
+        qoi_channels::Rgb => 7,
+        qoi_channels::Rgba => 9,
+    };
+    (width as usize)
+        .saturating_mul(height as usize)
+        .saturating_mul(per_pixel)
+        .saturating_add(HDR_HEADER_SIZE + QOI_END.len())
+}
+
+pub struct HdrEncoder<'a> {
+    data: &'a [[u16; 4]],
+    header: HdrHeader,
+}
+
+impl<'a> HdrEncoder<'a> {
+    pub fn new(
+        data: &'a [[u16; 4]],
+        width: u32,
+        height: u32,
+        channels: qoi_channels,
+        colorspace: QoiColorspace,
+        format: SampleFormat,
+    ) -> Self {
+        HdrEncoder {
+            data,
+            header: HdrHeader {
+                width,
+                height,
+                channels,
+                colorspace,
+                format,
+            },
+        }
+    }
+
+    pub fn header(&self) -> HdrHeader {
+        self.header
+    }
+
+    // encode into a buffer the caller owns and return the bytes written, a
+    // buffer of max_encoded_size() always fits. like
+    // Encoder::encode_to_slice the count goes on past the end of `out`, so
+    // BufferTooSmall tells the exact size needed
+    pub fn encode_to_slice(&self, out: &mut [u8]) -> Result<usize, EncodeError> {
+        let pxs = self.header.pixels();
+        if self.data.len() < pxs {
+            return Err(EncodeError::NotEnoughPixels {
+                expected: pxs,
+                found: self.data.len(),
+            });
+        }
+
+        let mut pos = 0;
+        let mut write = |bytes: &[u8]| {
+            if let Some(dst) = out.get_mut(pos..pos + bytes.len()) {
+                dst.copy_from_slice(bytes);
+            }
+            pos += bytes.len();
+        };
+        write(&self.header.to_bytes());
+
+        // a 3 channels image never carries alpha
+        let one = self.header.format.one();
+        let opaque = self.header.channels == qoi_channels::Rgb;
+        let mut prevpx = Pixels16::new(0, 0, 0, one);
+        let mut hashmap = [Pixels16::default(); 64];
+        let mut run = 0u8;
+
+        for (i, raw) in self.data[..pxs].iter().enumerate() {
+            let mut px = Pixels16::from(*raw);
+            if opaque {
+                px.a = one;
+            }
+
+            if px == prevpx {
+                // the decoder indexes run pixels too. that matters for the
+                // start pixel, which with half floats hashes to slot 0
+                // where the zeroed index already holds another colour
+                hashmap[usize::from(px.hash())] = px;
+                run += 1;
+                if run == HDR_MAX_RUN || i == pxs - 1 {
+                    write(&[(OP_RUN << 6) | (run - 1)]);
+                    run = 0;
+                }
+                continue;
+            }
+            if run > 0 {
+                write(&[(OP_RUN << 6) | (run - 1)]);
+                run = 0;
+            }
+
+            let index = px.hash();
+            if hashmap[usize::from(index)] == px {
+                write(&[(OP_INDEX << 6) | index]);
+            } else {
+                hashmap[usize::from(index)] = px;
+                if px.a != prevpx.a {
+                    let [r0, r1] = px.r.to_be_bytes();
+                    let [g0, g1] = px.g.to_be_bytes();
+                    let [b0, b1] = px.b.to_be_bytes();
+                    let [a0, a1] = px.a.to_be_bytes();
+                    write(&[OP_RGBA, r0, r1, g0, g1, b0, b1, a0, a1]);
+                } else {
+                    write_rgb_op(&mut write, px, prevpx);
+                }
+            }
+            prevpx = px;
+        }
+
+        write(&QOI_END);
+        if pos > out.len() {
+            return Err(EncodeError::BufferTooSmall { required: pos });
+        }
+        Ok(pos)
+    }
+}
+
+// the smallest of DIFF, LUMA, LUMA_WIDE, LUMA_HUGE and RGB for a pixel with
+// the alpha of the one before
+fn write_rgb_op<W: FnMut(&[u8])>(write: &mut W, px: Pixels16, prevpx: Pixels16) {
+    let (dr, dg, db) = (px.dr(prevpx), px.dg(prevpx), px.db(prevpx));
+    let dr_dg = dr.wrapping_sub(dg);
+    let db_dg = db.wrapping_sub(dg);
+    let fits = |v: i16, bits: u32| (-(1 << (bits - 1))..1 << (bits - 1)).contains(&v);
+
+    if fits(dr, 2) && fits(dg, 2) && fits(db, 2) {
+        write(&[(OP_DIFF << 6) | ((dr + 2) << 4 | (dg + 2) << 2 | (db + 2)) as u8]);
+    } else if fits(dg, 6) && fits(dr_dg, 4) && fits(db_dg, 4) {
+        write(&[
+            (OP_LUMA << 6) | (dg + 32) as u8,
+            ((dr_dg + 8) << 4 | (db_dg + 8)) as u8,
+        ]);
+    } else if fits(dg, 8) && fits(dr_dg, 8) && fits(db_dg, 8) {
+        write(&[OP_LUMA_WIDE, dg as u8, dr_dg as u8, db_dg as u8]);
+    } else if fits(dg, 12) && fits(dr_dg, 10) && fits(db_dg, 10) {
+        let packed =
+            ((dg as u32) & 0xfff) << 20 | ((dr_dg as u32) & 0x3ff) << 10 | ((db_dg as u32) & 0x3ff);
+        let [p0, p1, p2, p3] = packed.to_be_bytes();
+        write(&[OP_LUMA_HUGE, p0, p1, p2, p3]);
+    } else {
+        let [r0, r1] = px.r.to_be_bytes();
+        let [g0, g1] = px.g.to_be_bytes();
+        let [b0, b1] = px.b.to_be_bytes();
+        write(&[OP_RGB, r0, r1, g0, g1, b0, b1]);
+    }
+}
+
+// sign extend the low `bits` bits of v
+fn signed(v: u32, bits: u32) -> i16 {
+    ((v << (32 - bits)) as i32 >> (32 - bits)) as i16
+}
+
+// decode a whole qoih file held in memory into `out`, `channels` values per
+// pixel row by row. `out` must hold at least width * height pixels
+pub fn decode_into(
+    data: &[u8],
+    out: &mut [u16],
+    channels: qoi_channels,
+) -> Result<HdrHeader, fmt::Error> {
+    let header = HdrHeader::from_bytes(data)?;
+    let pxs = header.pixels();
+    let ch = channels.to_bytes() as usize;
+    if out.len() / ch < pxs {
+        return Err(fmt::Error);
+    }
+
+    let mut prevpx = Pixels16::new(0, 0, 0, header.format.one());
+    let mut hashmap = [Pixels16::default(); 64];
+    let mut pos = HDR_HEADER_SIZE;
+    let mut cnt = 0;
+
+    // bytes() errors when the file ends early
+    let bytes = |pos: usize, n: usize| data.get(pos..pos + n).ok_or(fmt::Error);
+    let u16_at = |b: &[u8], i: usize| u16::from_be_bytes([b[i], b[i + 1]]);
+    let add = |v: u16, d: i16| v.wrapping_add(d as u16);
+
+    while cnt < pxs {
+        let b = bytes(pos, 1)?[0];
+        pos += 1;
+
+        let mut len = 1;
+        match b {
+            OP_RGB => {
+                let rgb = bytes(pos, 6)?;
+                prevpx = Pixels16::new(u16_at(rgb, 0), u16_at(rgb, 2), u16_at(rgb, 4), prevpx.a);
+                pos += 6;
+            }
+            OP_RGBA => {
+                let rgba = bytes(pos, 8)?;
+                prevpx = Pixels16::new(
+                    u16_at(rgba, 0),
+                    u16_at(rgba, 2),
+                    u16_at(rgba, 4),
+                    u16_at(rgba, 6),
+                );
+                pos += 8;
+            }
+            OP_LUMA_WIDE => {
+                let d = bytes(pos, 3)?;
+                let (dg, dr_dg, db_dg) = (d[0] as i8 as i16, d[1] as i8 as i16, d[2] as i8 as i16);
+                prevpx.r = add(prevpx.r, dg.wrapping_add(dr_dg));
+                prevpx.g = add(prevpx.g, dg);
+                prevpx.b = add(prevpx.b, dg.wrapping_add(db_dg));
+                pos += 3;
+            }
+            OP_LUMA_HUGE => {
+                let d = bytes(pos, 4)?;
+                let packed = u32::from_be_bytes([d[0], d[1], d[2], d[3]]);
+                let dg = signed(packed >> 20, 12);
+                let dr_dg = signed(packed >> 10, 10);
+                let db_dg = signed(packed, 10);
+                prevpx.r = add(prevpx.r, dg.wrapping_add(dr_dg));
+                prevpx.g = add(prevpx.g, dg);
+                prevpx.b = add(prevpx.b, dg.wrapping_add(db_dg));
+                pos += 4;
+            }
+            _ => match b >> 6 {
+                OP_INDEX => prevpx = hashmap[usize::from(b)],
+                OP_DIFF => {
+                    prevpx.r = add(prevpx.r, i16::from((b >> 4) & 3) - 2);
+                    prevpx.g = add(prevpx.g, i16::from((b >> 2) & 3) - 2);
+                    prevpx.b = add(prevpx.b, i16::from(b & 3) - 2);
+                }
+                OP_LUMA => {
+                    let b2 = bytes(pos, 1)?[0];
+                    pos += 1;
+                    let dg = i16::from(b & 0x3f) - 32;
+                    prevpx.r = add(prevpx.r, dg + i16::from(b2 >> 4) - 8);
+                    prevpx.g = add(prevpx.g, dg);
+                    prevpx.b = add(prevpx.b, dg + i16::from(b2 & 0x0f) - 8);
+                }
+                OP_RUN => len = (usize::from(b & 0x3f) + 1).min(pxs - cnt),
+                _ => unreachable!(),
+            },
+        }
+
+        hashmap[usize::from(prevpx.hash())] = prevpx;
+        let px = prevpx.to_array();
+        for dst in out[cnt * ch..(cnt + len) * ch].chunks_exact_mut(ch) {
+            dst.copy_from_slice(&px[..ch]);
+        }
+        cnt += len;
+    }
+
+    Ok(header)
+}
+
+#[cfg(feature = "alloc")]
+impl HdrEncoder<'_> {
+    pub fn encode_to_vec(&self) -> Result<alloc::vec::Vec<u8>, EncodeError> {
+        let h = self.header;
+        let mut out = alloc::vec![0; max_encoded_size(h.width, h.height, h.channels)];
+        let written = self.encode_to_slice(&mut out)?;
+        out.truncate(written);
+        Ok(out)
+    }
+}
+
+// decode_into a freshly allocated buffer of 4 values per pixel
+#[cfg(feature = "alloc")]
+pub fn decode_to_vec(data: &[u8]) -> Result<(alloc::vec::Vec<[u16; 4]>, HdrHeader), fmt::Error> {
+    let header = HdrHeader::from_bytes(data)?;
+    // at most every byte of the data stream is a run of HDR_MAX_RUN, like
+    // decoder::can_hold()
+    let most = data
+        .len()
+        .saturating_sub(HDR_HEADER_SIZE)
+        .saturating_mul(usize::from(HDR_MAX_RUN));
+    if header.pixels() > most {
+        return Err(fmt::Error);
+    }
+    let mut out = alloc::vec![[0u16; 4]; header.pixels()];
+    decode_into(data, out.as_flattened_mut(), qoi_channels::Rgba)?;
+    Ok((out, header))
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::super::test_util::Lcg;
+    use super::*;
+
+    // a fresh qoih file has to give back exactly what went in, alpha set
+    // to one() for 3 channels
+    fn assert_roundtrip(
+        pixels: &[[u16; 4]],
+        width: u32,
+        height: u32,
+        channels: qoi_channels,
+        format: SampleFormat,
+    ) {
+        let data = HdrEncoder::new(
+            pixels,
+            width,
+            height,
+            channels,
+            QoiColorspace::Linear,
+            format,
+        )
+        .encode_to_vec()
+        .unwrap();
+        let (decoded, header) = decode_to_vec(&data).unwrap();
+        assert_eq!(
+            header,
+            HdrHeader {
+                width,
+                height,
+                channels,
+                colorspace: QoiColorspace::Linear,
+                format,
+            }
+        );
+        let expected: Vec<[u16; 4]> = pixels
+            .iter()
+            .map(|&[r, g, b, a]| match channels {
+                qoi_channels::Rgb => [r, g, b, format.one()],
+                qoi_channels::Rgba => [r, g, b, a],
+            })
+            .collect();
+        assert_eq!(decoded, expected, "{:?} {:?}", channels, format);
+    }
+
+    // steps of every size between pixels so each op comes up, with repeats
+    // for runs and earlier pixels for index hits
+    fn random_image(rng: &mut Lcg, len: usize, format: SampleFormat) -> Vec<[u16; 4]> {
+        let mut next = || rng.next_u32() >> 8;
+        let mut px = [0, 0, 0, format.one()];
+        let mut pixels: Vec<[u16; 4]> = Vec::with_capacity(len);
+        while pixels.len() < len {
+            match next() % 10 {
+                0 => {}
+                1 if !pixels.is_empty() => px = pixels[next() as usize % pixels.len()],
+                2 => px[3] = next() as u16,
+                _ => {
+                    let bits = [2, 3, 5, 9, 12, 16][next() as usize % 6];
+                    let step = |r: u32| (r as u16 & ((1u32 << bits) - 1) as u16) >> 1;
+                    let dg = step(next()).wrapping_sub(1 << (bits - 2));
+                    for (i, c) in px[..3].iter_mut().enumerate() {
+                        let d = if i == 1 { 0 } else { step(next()) >> 2 };
+                        *c = c.wrapping_add(dg).wrapping_add(d);
+                    }
+                }
+            }
+            pixels.extend(core::iter::repeat_n(px, 1 + next() as usize % 3));
+        }
+        pixels.truncate(len);
+        pixels
+    }
+
+    #[test]
+    fn images_decode_to_what_was_encoded() {
+        let mut rng = Lcg(1);
+        for format in [SampleFormat::Unorm16, SampleFormat::Half] {
+            for channels in [qoi_channels::Rgb, qoi_channels::Rgba] {
+                for (width, height) in [(1, 1), (7, 3), (64, 40), (200, 1)] {
+                    let pixels = random_image(&mut rng, width as usize * height as usize, format);
+                    assert_roundtrip(&pixels, width, height, channels, format);
+                }
+            }
+        }
+    }
+
+    // the start pixel hashes to slot 0 with half floats, where the zeroed
+    // index holds another colour until a run or a pixel puts it there
+    #[test]
+    fn runs_of_the_start_pixel() {
+        for format in [SampleFormat::Unorm16, SampleFormat::Half] {
+            let start = [0, 0, 0, format.one()];
+            let pixels = [start, start, [0; 4], start, [0; 4], [5, 6, 7, 8], start];
+            for channels in [qoi_channels::Rgb, qoi_channels::Rgba] {
+                assert_roundtrip(&pixels, 7, 1, channels, format);
+                assert_roundtrip(&[start; 130], 13, 10, channels, format);
+            }
+        }
+    }
+}
@@ -1,3 +1,7 @@
+use super::error::{QoiError, QOI_PIXELS_MAX};
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum qoi_channels {
     Rgb = 3,
     Rgba = 4,
@@ -12,43 +16,108 @@ impl qoi_channels {
     }
 }
 
+impl TryFrom<u8> for qoi_channels {
+    type Error = core::fmt::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            3 => Ok(Self::Rgb),
+            4 => Ok(Self::Rgba),
+            _ => Err(core::fmt::Error),
+        }
+    }
+}
+
+// how the rgb channels of an image are to be read, alpha is always linear
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QoiColorspace {
+    // sRGB transfer function on r, g and b
+    #[default]
+    Srgb = 0,
+    // every channel linear
+    Linear = 1,
+}
+
+impl QoiColorspace {
+    pub fn to_bytes(&self) -> u8 {
+        match self {
+            Self::Srgb => 0,
+            Self::Linear => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for QoiColorspace {
+    type Error = core::fmt::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Srgb),
+            1 => Ok(Self::Linear),
+            _ => Err(core::fmt::Error),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct qoi_header {
     magic: [u8; 4],
     pub width: u32,
     pub height: u32,
     channels: qoi_channels,
-    colorspace: u8,
+    colorspace: QoiColorspace,
 }
 
 impl qoi_header {
-    pub fn new(width: u32, height: u32, channels: qoi_channels, colorspace: u8) -> Self {
+    pub fn new(width: u32, height: u32, channels: qoi_channels, colorspace: QoiColorspace) -> Self {
         qoi_header {
-            magic: QOI_MAGIC.to_owned(),
+            magic: *QOI_MAGIC,
             width,
             height,
             channels,
             colorspace,
         }
     }
-    pub fn to_bytes(&self) -> [u8; 14] {
-        let mut header: [u8; 14] = [0; 14];
-
-        for i in 0..4 {
-            header[i] = self.magic[i];
-        }
+    // parse the 14 bytes at the start of a file
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, core::fmt::Error> {
+        Ok(Self::parse(bytes)?)
+    }
 
-        let w = self.width();
-        for i in 4..8 {
-            header[i] = w[i - 4];
+    // from_bytes() telling what is wrong. images of more than
+    // QOI_PIXELS_MAX pixels are refused here, before anything gets allocated
+    // for them
+    pub fn parse(bytes: &[u8]) -> Result<Self, QoiError> {
+        let bytes: &[u8; 14] =
+            bytes
+                .get(..14)
+                .and_then(|b| b.try_into().ok())
+                .ok_or(QoiError::Truncated {
+                    offset: bytes.len() as u64,
+                })?;
+        let magic = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if &magic != QOI_MAGIC {
+            return Err(QoiError::BadMagic(magic));
         }
-
-        let h = self.height();
-        for i in 8..12 {
-            header[i] = h[i - 8];
+        let width = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        let height = u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+        if !size_allowed(width, height) {
+            return Err(QoiError::BadDimensions { width, height });
         }
+        let channels =
+            qoi_channels::try_from(bytes[12]).map_err(|_| QoiError::BadChannels(bytes[12]))?;
+        let colorspace =
+            QoiColorspace::try_from(bytes[13]).map_err(|_| QoiError::BadColorspace(bytes[13]))?;
+        Ok(qoi_header::new(width, height, channels, colorspace))
+    }
+    pub fn to_bytes(&self) -> [u8; 14] {
+        let mut header: [u8; 14] = [0; 14];
 
+        header[0..4].copy_from_slice(&self.magic);
+        header[4..8].copy_from_slice(&self.width());
+        header[8..12].copy_from_slice(&self.height());
         header[12] = self.channels.to_bytes();
-        header[13] = self.colorspace;
+        header[13] = self.colorspace.to_bytes();
 
         header
     }
@@ -66,6 +135,19 @@ impl qoi_header {
         let byte4: u8 = ((self.height) & 0xff) as u8;
         [byte1, byte2, byte3, byte4]
     }
+    pub fn channels(&self) -> qoi_channels {
+        self.channels
+    }
+    pub fn colorspace(&self) -> QoiColorspace {
+        self.colorspace
+    }
+}
+
+// whether an image of this size stays within QOI_PIXELS_MAX
+pub fn size_allowed(width: u32, height: u32) -> bool {
+    u64::from(width)
+        .checked_mul(u64::from(height))
+        .is_some_and(|pixels| pixels <= QOI_PIXELS_MAX)
 }
 
 pub(crate) const QOI_MAGIC: &[u8; 4] = b"qoif";
@@ -88,9 +170,82 @@ pub(crate) const QOI_OP_DIFF: u8 = 1;
 // range from 128..191
 pub(crate) const QOI_OP_LUMA: u8 = 2;
 
-// (QOI_OP_RUN << 6) | run
-// range from 192..253
+// (QOI_OP_RUN << 6) | (run - 1)
+// range from 192..253, so a single run covers at most 62 pixels
 pub(crate) const QOI_OP_RUN: u8 = 3;
+pub(crate) const QOI_MAX_RUN: u8 = 62;
+
+// bytes stream end: seven 0x00 followed by a single 0x01
+pub(crate) const QOI_END: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-// bytes stream end
-pub(crate) const QOI_END: u16 = 1 << 8;
+    fn header_bytes(width: u32, height: u32) -> [u8; 14] {
+        qoi_header::new(width, height, qoi_channels::Rgba, QoiColorspace::Srgb).to_bytes()
+    }
+
+    #[test]
+    fn refuses_images_over_the_pixel_limit() {
+        assert_eq!(
+            qoi_header::parse(&header_bytes(100_000, 100_000)),
+            Err(QoiError::BadDimensions {
+                width: 100_000,
+                height: 100_000
+            })
+        );
+        assert_eq!(
+            qoi_header::parse(&header_bytes(u32::MAX, u32::MAX)),
+            Err(QoiError::BadDimensions {
+                width: u32::MAX,
+                height: u32::MAX
+            })
+        );
+        assert!(qoi_header::parse(&header_bytes(20_000, 20_000)).is_ok());
+        assert!(qoi_header::parse(&header_bytes(0, 7)).is_ok());
+    }
+
+    #[test]
+    fn channels_and_colorspace_bytes() {
+        assert_eq!(QoiColorspace::try_from(0), Ok(QoiColorspace::Srgb));
+        assert_eq!(QoiColorspace::try_from(1), Ok(QoiColorspace::Linear));
+        assert!(QoiColorspace::try_from(2).is_err());
+        assert!(QoiColorspace::try_from(255).is_err());
+        for colorspace in [QoiColorspace::Srgb, QoiColorspace::Linear] {
+            assert_eq!(
+                QoiColorspace::try_from(colorspace.to_bytes()),
+                Ok(colorspace)
+            );
+        }
+        assert_eq!(qoi_channels::try_from(3), Ok(qoi_channels::Rgb));
+        assert_eq!(qoi_channels::try_from(4), Ok(qoi_channels::Rgba));
+        assert!(qoi_channels::try_from(5).is_err());
+    }
+
+    #[test]
+    fn tells_what_is_wrong() {
+        let mut bytes = header_bytes(1, 1);
+        assert_eq!(
+            qoi_header::parse(&bytes[..10]),
+            Err(QoiError::Truncated { offset: 10 })
+        );
+        bytes[12] = 5;
+        assert_eq!(qoi_header::parse(&bytes), Err(QoiError::BadChannels(5)));
+        bytes[12] = 3;
+        bytes[13] = 2;
+        assert_eq!(qoi_header::parse(&bytes), Err(QoiError::BadColorspace(2)));
+        bytes[0] = b'x';
+        assert_eq!(qoi_header::parse(&bytes), Err(QoiError::BadMagic(*b"xoif")));
+    }
+
+    // a file too short for the image its header claims fails before the
+    // pixels are allocated
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn short_file_with_a_huge_header() {
+        let mut data = header_bytes(20_000, 20_000).to_vec();
+        data.extend_from_slice(&QOI_END);
+        assert!(super::super::decoder::decode_to_vec(&data, qoi_channels::Rgba).is_err());
+    }
+}
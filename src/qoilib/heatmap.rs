@@ -0,0 +1,118 @@
+use image::RgbImage;
+use std::io::Read;
+
+use super::disasm::Disassembler;
+use super::op::QoiOp;
+
+// colour of every op in the op map, indexed like op::OP_NAMES
+pub const OP_COLORS: [[u8; 3]; 6] = [
+    [46, 204, 64], // INDEX green
+    [0, 200, 220], // DIFF cyan
+    [40, 90, 230], // LUMA blue
+    [70, 70, 70],  // RUN grey
+    [255, 140, 0], // RGB orange
+    [230, 30, 40], // RGBA red
+];
+
+// the most a single pixel can cost, a full RGBA chunk
+pub const MAX_PIXEL_BYTES: f64 = 5.0;
+
+// stops of the byte cost gradient, from free to MAX_PIXEL_BYTES
+const GRADIENT: [[u8; 3]; 5] = [
+    [0, 0, 0],
+    [60, 10, 140],
+    [200, 40, 60],
+    [255, 190, 0],
+    [255, 255, 255],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeatmapKind {
+    // colour every pixel by the op that produced it
+    Ops,
+    // colour every pixel by the bytes it cost, a run spreads its byte over
+    // all of its pixels
+    Bytes,
+}
+
+// an image the size of the encoded one showing how each pixel was stored
+pub fn heatmap<R: Read>(reader: R, kind: HeatmapKind) -> Result<RgbImage, std::fmt::Error> {
+    let mut disasm = Disassembler::new(reader)?;
+    let width = disasm.header().width;
+    let height = disasm.header().height;
+    let total = disasm.total_pixels();
+    // the header alone proves nothing about the stream behind it, so the
+    // pixels are pushed as chunks are read and the image built at the end
+    let mut raw: Vec<u8> = Vec::new();
+
+    for chunk in disasm.by_ref() {
+        let chunk = chunk?;
+        let color = match kind {
+            HeatmapKind::Ops => OP_COLORS[chunk.op.slot()],
+            HeatmapKind::Bytes => gradient(pixel_cost(&chunk.op) / MAX_PIXEL_BYTES),
+        };
+
+        // a run may claim more pixels than the image has left
+        let end = (chunk.pixel + u64::from(chunk.op.pixels())).min(total);
+        for _ in chunk.pixel..end {
+            raw.extend_from_slice(&color);
+        }
+    }
+
+    let img = RgbImage::from_raw(width, height, raw).ok_or(std::fmt::Error)?;
+    Ok(img)
+}
+
+// bytes spent on each pixel produced by the op
+pub fn pixel_cost(op: &QoiOp) -> f64 {
+    op.size() as f64 / f64::from(op.pixels())
+}
+
+// map 0..=1 onto the gradient stops
+fn gradient(t: f64) -> [u8; 3] {
+    let t = t.clamp(0.0, 1.0) * (GRADIENT.len() - 1) as f64;
+    let i = (t as usize).min(GRADIENT.len() - 2);
+    let f = t - i as f64;
+
+    let mut color = [0; 3];
+    for (c, (a, b)) in color
+        .iter_mut()
+        .zip(GRADIENT[i].iter().zip(GRADIENT[i + 1].iter()))
+    {
+        *c = (f64::from(*a) + (f64::from(*b) - f64::from(*a)) * f).round() as u8;
+    }
+    color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qoilib::header::{qoi_channels, qoi_header, QoiColorspace, QOI_END};
+    use image::Rgb;
+
+    // a header claiming a huge image over a stream of a few bytes fails on
+    // the missing chunks instead of allocating the image up front
+    #[test]
+    fn short_file_with_a_huge_header() {
+        let mut data = qoi_header::new(20_000, 20_000, qoi_channels::Rgb, QoiColorspace::Srgb)
+            .to_bytes()
+            .to_vec();
+        data.extend_from_slice(&[0xfd, 0xfd, 0xfd, 0xfd]);
+        data.extend_from_slice(&QOI_END);
+        assert!(heatmap(&data[..], HeatmapKind::Ops).is_err());
+    }
+
+    #[test]
+    fn runs_cover_their_pixels() {
+        let mut data = qoi_header::new(3, 2, qoi_channels::Rgb, QoiColorspace::Srgb)
+            .to_bytes()
+            .to_vec();
+        // a run of 5, then one rgb pixel
+        data.extend_from_slice(&[0xc4, 0xfe, 1, 2, 3]);
+        data.extend_from_slice(&QOI_END);
+        let img = heatmap(&data[..], HeatmapKind::Ops).unwrap();
+        assert_eq!(img.dimensions(), (3, 2));
+        assert_eq!(img.get_pixel(1, 1), &Rgb(OP_COLORS[3]));
+        assert_eq!(img.get_pixel(2, 1), &Rgb(OP_COLORS[4]));
+    }
+}
@@ -1,6 +1,32 @@
+// alpha, header, orient, pixel, op, simd, stats and error and the slice
+// functions of decoder, encoder, hdr and plus build without std, quantize and
+// thumbnail need `alloc` and the rest needs the `std` feature. color needs it
+// for powf
+pub mod alpha;
+#[cfg(feature = "std")]
+pub mod color;
 pub mod decoder;
+#[cfg(feature = "std")]
+pub mod disasm;
 pub mod encoder;
+pub mod error;
+pub mod hdr;
 pub mod header;
+#[cfg(feature = "std")]
+pub mod heatmap;
+pub mod op;
+pub mod orient;
 pub mod pixel;
+pub mod plus;
+#[cfg(feature = "alloc")]
+pub mod quantize;
+pub mod simd;
+pub mod stats;
+#[cfg(test)]
+pub(crate) mod test_util;
+#[cfg(feature = "alloc")]
+pub mod thumbnail;
+#[cfg(feature = "std")]
+pub mod verify;
 
 pub use pixel::*;
@@ -0,0 +1,175 @@
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io::Read;
+
+#[cfg(feature = "std")]
+use super::decoder::read_u8;
+use super::header::{QOI_OP_DIFF, QOI_OP_INDEX, QOI_OP_LUMA, QOI_OP_RGB, QOI_OP_RGBA, QOI_OP_RUN};
+use super::{PixelHashMap, Pixels};
+
+// every op of the format, see QoiOp::slot
+pub const OP_NAMES: [&str; 6] = ["INDEX", "DIFF", "LUMA", "RUN", "RGB", "RGBA"];
+
+// one chunk of the qoi data stream with its operands decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QoiOp {
+    Rgb { r: u8, g: u8, b: u8 },
+    Rgba { r: u8, g: u8, b: u8, a: u8 },
+    Index(u8),
+    // each delta in -2..=1
+    Diff { dr: i8, dg: i8, db: i8 },
+    // dg in -32..=31, dr_dg and db_dg in -8..=7
+    Luma { dg: i8, dr_dg: i8, db_dg: i8 },
+    // the real run length in 1..=62, without the bias of the stored byte
+    Run(u8),
+}
+
+impl QoiOp {
+    // read the next chunk from the stream
+    #[cfg(feature = "std")]
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, fmt::Error> {
+        let mut bytes = [0; 5];
+        bytes[0] = read_u8(reader)?;
+        let size = Self::size_of(bytes[0]);
+        reader
+            .read_exact(&mut bytes[1..size])
+            .map_err(|_| fmt::Error)?;
+        Self::from_bytes(&bytes[..size])
+    }
+
+    // decode the chunk at the start of `bytes`, size() tells how many bytes
+    // it took
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, fmt::Error> {
+        let byte_zero = *bytes.first().ok_or(fmt::Error)?;
+        let bytes = bytes.get(..Self::size_of(byte_zero)).ok_or(fmt::Error)?;
+        let op = match byte_zero {
+            QOI_OP_RGBA => QoiOp::Rgba {
+                r: bytes[1],
+                g: bytes[2],
+                b: bytes[3],
+                a: bytes[4],
+            },
+            QOI_OP_RGB => QoiOp::Rgb {
+                r: bytes[1],
+                g: bytes[2],
+                b: bytes[3],
+            },
+            _ => match byte_zero >> 6 {
+                QOI_OP_INDEX => QoiOp::Index(byte_zero & 0b0011_1111),
+                QOI_OP_DIFF => QoiOp::Diff {
+                    dr: ((byte_zero >> 4) & 0b11) as i8 - 2,
+                    dg: ((byte_zero >> 2) & 0b11) as i8 - 2,
+                    db: (byte_zero & 0b11) as i8 - 2,
+                },
+                QOI_OP_LUMA => QoiOp::Luma {
+                    dg: (byte_zero & 0b0011_1111) as i8 - 32,
+                    dr_dg: (bytes[1] >> 4) as i8 - 8,
+                    db_dg: (bytes[1] & 0b0000_1111) as i8 - 8,
+                },
+                QOI_OP_RUN => QoiOp::Run((byte_zero & 0b0011_1111) + 1),
+                _ => unreachable!(),
+            },
+        };
+        Ok(op)
+    }
+
+    // size() of the chunk starting with `byte_zero`
+    fn size_of(byte_zero: u8) -> usize {
+        match byte_zero {
+            QOI_OP_RGBA => 5,
+            QOI_OP_RGB => 4,
+            _ if byte_zero >> 6 == QOI_OP_LUMA => 2,
+            _ => 1,
+        }
+    }
+
+    // the encoded chunk, only the first `size()` bytes are used
+    pub fn to_bytes(&self) -> [u8; 5] {
+        match *self {
+            QoiOp::Rgb { r, g, b } => [QOI_OP_RGB, r, g, b, 0],
+            QoiOp::Rgba { r, g, b, a } => [QOI_OP_RGBA, r, g, b, a],
+            QoiOp::Index(index) => [(QOI_OP_INDEX << 6) | index, 0, 0, 0, 0],
+            QoiOp::Diff { dr, dg, db } => [
+                (QOI_OP_DIFF << 6)
+                    | (((dr + 2) as u8) << 4)
+                    | (((dg + 2) as u8) << 2)
+                    | ((db + 2) as u8),
+                0,
+                0,
+                0,
+                0,
+            ],
+            QoiOp::Luma { dg, dr_dg, db_dg } => [
+                (QOI_OP_LUMA << 6) | ((dg + 32) as u8),
+                (((dr_dg + 8) as u8) << 4) | ((db_dg + 8) as u8),
+                0,
+                0,
+                0,
+            ],
+            QoiOp::Run(run) => [(QOI_OP_RUN << 6) | (run - 1), 0, 0, 0, 0],
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        OP_NAMES[self.slot()]
+    }
+
+    // position of the op in OP_NAMES
+    pub fn slot(&self) -> usize {
+        match self {
+            QoiOp::Index(_) => 0,
+            QoiOp::Diff { .. } => 1,
+            QoiOp::Luma { .. } => 2,
+            QoiOp::Run(_) => 3,
+            QoiOp::Rgb { .. } => 4,
+            QoiOp::Rgba { .. } => 5,
+        }
+    }
+
+    // number of bytes the chunk takes in the stream
+    pub fn size(&self) -> usize {
+        match self {
+            QoiOp::Rgb { .. } => 4,
+            QoiOp::Rgba { .. } => 5,
+            QoiOp::Luma { .. } => 2,
+            QoiOp::Index(_) | QoiOp::Diff { .. } | QoiOp::Run(_) => 1,
+        }
+    }
+
+    // number of pixels the chunk produces
+    pub fn pixels(&self) -> u32 {
+        match self {
+            QoiOp::Run(run) => u32::from(*run),
+            _ => 1,
+        }
+    }
+
+    // the rgb deltas against the previous pixel, for DIFF and LUMA only
+    pub fn deltas(&self) -> Option<(i8, i8, i8)> {
+        match *self {
+            QoiOp::Diff { dr, dg, db } => Some((dr, dg, db)),
+            QoiOp::Luma { dg, dr_dg, db_dg } => Some((dg + dr_dg, dg, dg + db_dg)),
+            _ => None,
+        }
+    }
+
+    // the pixel produced by the chunk, the caller still has to store it in
+    // the hashmap
+    pub fn apply(&self, prevpx: Pixels, hashmap: &PixelHashMap) -> Pixels {
+        match *self {
+            QoiOp::Rgb { r, g, b } => Pixels::new(r, g, b, prevpx.a),
+            QoiOp::Rgba { r, g, b, a } => Pixels::new(r, g, b, a),
+            QoiOp::Index(index) => hashmap[index],
+            QoiOp::Run(_) => prevpx,
+            QoiOp::Diff { .. } | QoiOp::Luma { .. } => {
+                let (dr, dg, db) = self.deltas().unwrap();
+                Pixels::new(
+                    prevpx.r.wrapping_add(dr as u8),
+                    prevpx.g.wrapping_add(dg as u8),
+                    prevpx.b.wrapping_add(db as u8),
+                    prevpx.a,
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+// flips, rotations and transposes of decoded pixel buffers, row by row and
+// top to bottom like decode_into() writes them. work on any pixel type: [u8;
+// 4], [u8; 3], the u32 and u16 of decoder::PixelLayout or the [u16; 4] of hdr
+
+// the eight ways to put a rectangle back onto itself, less the identity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    FlipHorizontal,
+    FlipVertical,
+    // clockwise
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    // rows become columns, top left stays where it is
+    Transpose,
+    // rows become columns, top left goes to the bottom right
+    Transverse,
+}
+
+impl Transform {
+    pub const ALL: [Transform; 7] = [
+        Transform::FlipHorizontal,
+        Transform::FlipVertical,
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+        Transform::Transpose,
+        Transform::Transverse,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transform::FlipHorizontal => "flip-horizontal",
+            Transform::FlipVertical => "flip-vertical",
+            Transform::Rotate90 => "rotate-90",
+            Transform::Rotate180 => "rotate-180",
+            Transform::Rotate270 => "rotate-270",
+            Transform::Transpose => "transpose",
+            Transform::Transverse => "transverse",
+        }
+    }
+
+    // width and height of the result
+    pub fn size(&self, width: u32, height: u32) -> (u32, u32) {
+        if self.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    pub fn swaps_axes(&self) -> bool {
+        matches!(
+            self,
+            Transform::Rotate90
+                | Transform::Rotate270
+                | Transform::Transpose
+                | Transform::Transverse
+        )
+    }
+
+    // write the transformed image of `src` into `out`, both width * height
+    // pixels long. returns the size of the result, size() tells it ahead
+    pub fn apply<T: Copy>(
+        &self,
+        src: &[T],
+        width: u32,
+        height: u32,
+        out: &mut [T],
+    ) -> Result<(u32, u32), core::fmt::Error> {
+        let (w, h) = (width as usize, height as usize);
+        let pxs = w * h;
+        if src.len() < pxs || out.len() < pxs {
+            return Err(core::fmt::Error);
+        }
+        let (src, out) = (&src[..pxs], &mut out[..pxs]);
+
+        if !self.swaps_axes() {
+            out.copy_from_slice(src);
+            self.apply_in_place(out, width, height)?;
+            return Ok((width, height));
+        }
+
+        // out is h wide and w high, (x, y) of src lands on (ox, oy)
+        for y in 0..h {
+            for x in 0..w {
+                let (ox, oy) = match self {
+                    Transform::Rotate90 => (h - 1 - y, x),
+                    Transform::Rotate270 => (y, w - 1 - x),
+                    Transform::Transpose => (y, x),
+                    _ => (h - 1 - y, w - 1 - x),
+                };
+                out[oy * h + ox] = src[y * w + x];
+            }
+        }
+        Ok((height, width))
+    }
+
+    // the transforms that keep the size, without a second buffer
+    pub fn apply_in_place<T: Copy>(
+        &self,
+        pixels: &mut [T],
+        width: u32,
+        height: u32,
+    ) -> Result<(), core::fmt::Error> {
+        let pxs = width as usize * height as usize;
+        if pixels.len() < pxs || self.swaps_axes() {
+            return Err(core::fmt::Error);
+        }
+        let pixels = &mut pixels[..pxs];
+        match self {
+            Transform::FlipHorizontal => flip_horizontal(pixels, width),
+            Transform::FlipVertical => flip_vertical(pixels, width),
+            _ => pixels.reverse(),
+        }
+        Ok(())
+    }
+}
+
+// mirror every row
+pub fn flip_horizontal<T>(pixels: &mut [T], width: u32) {
+    if width > 0 {
+        for row in pixels.chunks_exact_mut(width as usize) {
+            row.reverse();
+        }
+    }
+}
+
+// swap the rows top to bottom, the order OpenGL wants
+pub fn flip_vertical<T>(pixels: &mut [T], width: u32) {
+    let width = width as usize;
+    if width == 0 {
+        return;
+    }
+    let rows = pixels.len() / width;
+    let (top, rest) = pixels[..rows * width].split_at_mut(rows / 2 * width);
+    // the middle row of an odd height stays put
+    let bottom = &mut rest[rows % 2 * width..];
+    for (a, b) in top
+        .chunks_exact_mut(width)
+        .zip(bottom.chunks_exact_mut(width).rev())
+    {
+        a.swap_with_slice(b);
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    // 5x3, every pixel its own index
+    const W: u32 = 5;
+    const H: u32 = 3;
+
+    fn image() -> Vec<u32> {
+        (0..W * H).collect()
+    }
+
+    fn apply(t: Transform, src: &[u32], width: u32, height: u32) -> (Vec<u32>, u32, u32) {
+        let mut out = vec![0; src.len()];
+        let (w, h) = t.apply(src, width, height, &mut out).unwrap();
+        assert_eq!((w, h), t.size(width, height));
+        (out, w, h)
+    }
+
+    #[test]
+    fn rotate_90_is_clockwise() {
+        let (out, w, h) = apply(Transform::Rotate90, &image(), W, H);
+        assert_eq!((w, h), (3, 5));
+        // the left column read bottom to top becomes the top row
+        assert_eq!(&out[..3], &[10, 5, 0]);
+        assert_eq!(&out[12..], &[14, 9, 4]);
+    }
+
+    #[test]
+    fn four_quarter_turns_are_the_identity() {
+        let src = image();
+        let (mut px, mut w, mut h) = (src.clone(), W, H);
+        for turn in 1..=4 {
+            (px, w, h) = apply(Transform::Rotate90, &px, w, h);
+            assert_eq!(px == src, turn == 4, "after {} turns", turn);
+        }
+        assert_eq!((w, h), (W, H));
+    }
+
+    #[test]
+    fn transforms_that_undo_themselves() {
+        let src = image();
+        for t in [
+            Transform::Transpose,
+            Transform::Transverse,
+            Transform::FlipHorizontal,
+            Transform::FlipVertical,
+            Transform::Rotate180,
+        ] {
+            let (once, w, h) = apply(t, &src, W, H);
+            assert_ne!(once, src, "{}", t.name());
+            let (twice, w, h) = apply(t, &once, w, h);
+            assert_eq!((twice, w, h), (src.clone(), W, H), "{}", t.name());
+        }
+        let (turned, w, h) = apply(Transform::Rotate90, &src, W, H);
+        assert_eq!(apply(Transform::Rotate270, &turned, w, h), (src, W, H));
+    }
+
+    #[test]
+    fn in_place_refuses_to_swap_axes() {
+        let mut px = image();
+        assert!(Transform::Transpose.apply_in_place(&mut px, W, H).is_err());
+        Transform::FlipVertical
+            .apply_in_place(&mut px, W, H)
+            .unwrap();
+        assert_eq!(&px[..5], &[10, 11, 12, 13, 14]);
+        assert_eq!(&px[5..10], &[5, 6, 7, 8, 9]);
+    }
+}
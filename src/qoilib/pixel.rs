@@ -1,7 +1,3 @@
-use std::num::Wrapping;
-
-use super::header::QOI_OP_RGB;
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Pixels {
     pub r: u8,
@@ -11,8 +7,8 @@ pub struct Pixels {
 }
 
 pub enum DiffType {
-    DIFF,
-    LUMA,
+    Diff,
+    Luma,
 }
 
 pub struct PixelHashMap([Pixels; 64]);
@@ -30,8 +26,15 @@ impl Pixels {
         }
     }
 
+    // 256 is a multiple of 64, so wrapping u8 arithmetic gives the same index
+    // as the spec's (r * 3 + g * 5 + b * 7 + a * 11) % 64
     pub fn hash(&self) -> u8 {
-        (self.r * 3 + self.g * 5 + self.b * 7 + self.a * 11) % 64
+        self.r
+            .wrapping_mul(3)
+            .wrapping_add(self.g.wrapping_mul(5))
+            .wrapping_add(self.b.wrapping_mul(7))
+            .wrapping_add(self.a.wrapping_mul(11))
+            % 64
     }
 
     pub fn dr(&self, rhs: Pixels) -> i8 {
@@ -45,25 +48,29 @@ impl Pixels {
     }
     pub fn rgb_to_bytes(&self, prev: Pixels, diff_type: DiffType) -> [u8; 2] {
         match diff_type {
-            DiffType::DIFF => {
-                let dr = (self.dr(prev) + 2) as u8;
-                let dg = (self.dg(prev) + 2) as u8;
-                let db = (self.db(prev) + 2) as u8;
+            DiffType::Diff => {
+                let dr = self.dr(prev).wrapping_add(2) as u8;
+                let dg = self.dg(prev).wrapping_add(2) as u8;
+                let db = self.db(prev).wrapping_add(2) as u8;
                 [0, ((dr << 4) | (dg << 2) | (db))]
             }
-            DiffType::LUMA => {
+            DiffType::Luma => {
                 let dr = (self.dr(prev)) as u8;
                 let dg = (self.dg(prev)) as u8;
                 let db = (self.db(prev)) as u8;
                 let dr_dg = dr.wrapping_sub(dg);
                 let db_dg = db.wrapping_sub(dg);
                 [
-                    ((dg + 32) & 0b0011_1111),
-                    ((((dr_dg + 8) & 0b0000_1111) << 4) | (db_dg + 8) & 0b0000_1111),
+                    (dg.wrapping_add(32) & 0b0011_1111),
+                    (((dr_dg.wrapping_add(8) & 0b0000_1111) << 4)
+                        | (db_dg.wrapping_add(8) & 0b0000_1111)),
                 ]
             }
         }
     }
+    pub fn to_array(&self) -> [u8; 4] {
+        [self.r, self.g, self.b, self.a]
+    }
 }
 
 impl From<[u8; 4]> for Pixels {
@@ -83,7 +90,13 @@ impl PixelHashMap {
     }
 }
 
-impl std::ops::Index<u8> for PixelHashMap {
+impl Default for PixelHashMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::ops::Index<u8> for PixelHashMap {
     type Output = Pixels;
 
     fn index(&self, index: u8) -> &Self::Output {
@@ -91,8 +104,58 @@ impl std::ops::Index<u8> for PixelHashMap {
     }
 }
 
-impl std::ops::IndexMut<u8> for PixelHashMap {
+impl core::ops::IndexMut<u8> for PixelHashMap {
     fn index_mut(&mut self, index: u8) -> &mut Self::Output {
         &mut self.0[usize::from(index)]
     }
 }
+
+// a pixel of the 16 bit variant in hdr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Pixels16 {
+    pub r: u16,
+    pub g: u16,
+    pub b: u16,
+    pub a: u16,
+}
+
+impl Pixels16 {
+    pub fn new(r: u16, g: u16, b: u16, a: u16) -> Self {
+        Pixels16 { r, g, b, a }
+    }
+
+    // the same hash as Pixels, 65536 is a multiple of 64 as well
+    pub fn hash(&self) -> u8 {
+        (self
+            .r
+            .wrapping_mul(3)
+            .wrapping_add(self.g.wrapping_mul(5))
+            .wrapping_add(self.b.wrapping_mul(7))
+            .wrapping_add(self.a.wrapping_mul(11))
+            % 64) as u8
+    }
+
+    pub fn dr(&self, rhs: Pixels16) -> i16 {
+        self.r.wrapping_sub(rhs.r) as i16
+    }
+    pub fn dg(&self, rhs: Pixels16) -> i16 {
+        self.g.wrapping_sub(rhs.g) as i16
+    }
+    pub fn db(&self, rhs: Pixels16) -> i16 {
+        self.b.wrapping_sub(rhs.b) as i16
+    }
+    pub fn to_array(&self) -> [u16; 4] {
+        [self.r, self.g, self.b, self.a]
+    }
+}
+
+impl From<[u16; 4]> for Pixels16 {
+    fn from(value: [u16; 4]) -> Self {
+        Pixels16 {
+            r: value[0],
+            g: value[1],
+            b: value[2],
+            a: value[3],
+        }
+    }
+}
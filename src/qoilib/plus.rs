@@ -0,0 +1,471 @@
+// qoi+, an experimental container for reversible pre-filters. not part of
+// the qoi specification and not readable by other decoders
+//
+//   magic      4 bytes  "qoi+"
+//   version    1 byte   PLUS_VERSION
+//   color      1 byte   ColorTransform
+//   predictor  1 byte   Predictor
+//   a complete qoif file of the filtered pixels
+//
+// the colour transform runs first and the predictor on its output, decoding
+// undoes them the other way round. both only touch r, g and b and work
+// modulo 256, so every image comes back exactly. alpha is left alone, an
+// opaque image stays opaque and keeps its 3 channels
+use core::fmt;
+
+use super::decoder;
+use super::header::{qoi_channels, qoi_header};
+
+pub const PLUS_MAGIC: [u8; 4] = *b"qoi+";
+pub const PLUS_VERSION: u8 = 1;
+// magic, version, color and predictor
+pub const PLUS_HEADER_SIZE: usize = 7;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorTransform {
+    #[default]
+    None = 0,
+    // r - g and b - g, g as it is
+    SubtractGreen = 1,
+    // lossless YCoCg-R lifting, stored as co, y, cg so that y takes the
+    // place of g, which DIFF and LUMA track most closely
+    YCoCgR = 2,
+}
+
+// png style, every channel minus a prediction from pixels already seen.
+// a missing neighbour on the top row or left column counts as 0
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Predictor {
+    #[default]
+    None = 0,
+    // the pixel to the left
+    Sub = 1,
+    // the pixel above
+    Up = 2,
+    // (left + up) / 2
+    Average = 3,
+    // whichever of left, up and up left is closest to left + up - up left
+    Paeth = 4,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Filters {
+    pub color: ColorTransform,
+    pub predictor: Predictor,
+}
+
+impl ColorTransform {
+    pub const ALL: [ColorTransform; 3] = [
+        ColorTransform::None,
+        ColorTransform::SubtractGreen,
+        ColorTransform::YCoCgR,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorTransform::None => "none",
+            ColorTransform::SubtractGreen => "subtract-green",
+            ColorTransform::YCoCgR => "ycocg-r",
+        }
+    }
+
+    fn forward(&self, px: &mut [u8]) {
+        let (r, g, b) = (px[0], px[1], px[2]);
+        match self {
+            ColorTransform::None => {}
+            ColorTransform::SubtractGreen => {
+                px[0] = r.wrapping_sub(g);
+                px[2] = b.wrapping_sub(g);
+            }
+            ColorTransform::YCoCgR => {
+                let co = r.wrapping_sub(b);
+                let t = b.wrapping_add(half(co));
+                let cg = g.wrapping_sub(t);
+                let y = t.wrapping_add(half(cg));
+                px[..3].copy_from_slice(&[co, y, cg]);
+            }
+        }
+    }
+
+    fn inverse(&self, px: &mut [u8]) {
+        match self {
+            ColorTransform::None => {}
+            ColorTransform::SubtractGreen => {
+                px[0] = px[0].wrapping_add(px[1]);
+                px[2] = px[2].wrapping_add(px[1]);
+            }
+            ColorTransform::YCoCgR => {
+                let (co, y, cg) = (px[0], px[1], px[2]);
+                let t = y.wrapping_sub(half(cg));
+                let g = cg.wrapping_add(t);
+                let b = t.wrapping_sub(half(co));
+                let r = co.wrapping_add(b);
+                px[..3].copy_from_slice(&[r, g, b]);
+            }
+        }
+    }
+}
+
+impl TryFrom<u8> for ColorTransform {
+    type Error = fmt::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        ColorTransform::ALL
+            .into_iter()
+            .find(|c| *c as u8 == value)
+            .ok_or(fmt::Error)
+    }
+}
+
+impl Predictor {
+    pub const ALL: [Predictor; 5] = [
+        Predictor::None,
+        Predictor::Sub,
+        Predictor::Up,
+        Predictor::Average,
+        Predictor::Paeth,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Predictor::None => "none",
+            Predictor::Sub => "sub",
+            Predictor::Up => "up",
+            Predictor::Average => "average",
+            Predictor::Paeth => "paeth",
+        }
+    }
+
+    fn predict(&self, left: u8, up: u8, up_left: u8) -> u8 {
+        match self {
+            Predictor::None => 0,
+            Predictor::Sub => left,
+            Predictor::Up => up,
+            Predictor::Average => ((u16::from(left) + u16::from(up)) / 2) as u8,
+            Predictor::Paeth => {
+                let p = i16::from(left) + i16::from(up) - i16::from(up_left);
+                let (pa, pb, pc) = (
+                    (p - i16::from(left)).abs(),
+                    (p - i16::from(up)).abs(),
+                    (p - i16::from(up_left)).abs(),
+                );
+                if pa <= pb && pa <= pc {
+                    left
+                } else if pb <= pc {
+                    up
+                } else {
+                    up_left
+                }
+            }
+        }
+    }
+
+    // the prediction for r, g and b of pixel `i`
+    fn prediction(&self, data: &[u8], i: usize, width: usize, stride: usize) -> [u8; 3] {
+        let at = |j: usize, c: usize| data[j * stride + c];
+        let (x, y) = (i % width, i / width);
+        core::array::from_fn(|c| {
+            let left = if x > 0 { at(i - 1, c) } else { 0 };
+            let up = if y > 0 { at(i - width, c) } else { 0 };
+            let up_left = if x > 0 && y > 0 {
+                at(i - width - 1, c)
+            } else {
+                0
+            };
+            self.predict(left, up, up_left)
+        })
+    }
+}
+
+impl TryFrom<u8> for Predictor {
+    type Error = fmt::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Predictor::ALL
+            .into_iter()
+            .find(|p| *p as u8 == value)
+            .ok_or(fmt::Error)
+    }
+}
+
+impl Filters {
+    pub fn new(color: ColorTransform, predictor: Predictor) -> Self {
+        Filters { color, predictor }
+    }
+
+    // every combination, Filters::default() first
+    pub fn all() -> impl Iterator<Item = Filters> {
+        ColorTransform::ALL.into_iter().flat_map(|color| {
+            Predictor::ALL
+                .into_iter()
+                .map(move |predictor| Filters { color, predictor })
+        })
+    }
+
+    // filter pixels of `stride` bytes in place, row by row, `width` pixels
+    // to a row. the predictor runs from the last pixel back to the first, so
+    // it always predicts from pixels that are not filtered yet
+    pub fn apply(&self, data: &mut [u8], width: u32, stride: usize) {
+        let width = width.max(1) as usize;
+        for px in data.chunks_exact_mut(stride) {
+            self.color.forward(px);
+        }
+        if self.predictor == Predictor::None {
+            return;
+        }
+        for i in (0..data.len() / stride).rev() {
+            let prediction = self.predictor.prediction(data, i, width, stride);
+            for (c, p) in prediction.iter().enumerate() {
+                data[i * stride + c] = data[i * stride + c].wrapping_sub(*p);
+            }
+        }
+    }
+
+    // undo apply(), the predictor from the first pixel on so it predicts
+    // from pixels that are already restored
+    pub fn revert(&self, data: &mut [u8], width: u32, stride: usize) {
+        let width = width.max(1) as usize;
+        if self.predictor != Predictor::None {
+            for i in 0..data.len() / stride {
+                let prediction = self.predictor.prediction(data, i, width, stride);
+                for (c, p) in prediction.iter().enumerate() {
+                    data[i * stride + c] = data[i * stride + c].wrapping_add(*p);
+                }
+            }
+        }
+        for px in data.chunks_exact_mut(stride) {
+            self.color.inverse(px);
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; PLUS_HEADER_SIZE] {
+        let [m0, m1, m2, m3] = PLUS_MAGIC;
+        [
+            m0,
+            m1,
+            m2,
+            m3,
+            PLUS_VERSION,
+            self.color as u8,
+            self.predictor as u8,
+        ]
+    }
+
+    // the filters of a qoi+ file, checking magic and version
+    pub fn from_bytes(data: &[u8]) -> Result<Self, fmt::Error> {
+        let header = data.get(..PLUS_HEADER_SIZE).ok_or(fmt::Error)?;
+        if header[..4] != PLUS_MAGIC || header[4] != PLUS_VERSION {
+            return Err(fmt::Error);
+        }
+        Ok(Filters {
+            color: header[5].try_into()?,
+            predictor: header[6].try_into()?,
+        })
+    }
+}
+
+// halve a wrapped difference as the signed value it stands for
+fn half(v: u8) -> u8 {
+    ((v as i8) >> 1) as u8
+}
+
+pub fn is_plus(data: &[u8]) -> bool {
+    data.starts_with(&PLUS_MAGIC)
+}
+
+// decode a qoi+ file held in memory into `out`, like decoder::decode_into
+pub fn decode_into(
+    data: &[u8],
+    out: &mut [u8],
+    channels: qoi_channels,
+) -> Result<(qoi_header, Filters), fmt::Error> {
+    let filters = Filters::from_bytes(data)?;
+    let header = decoder::decode_into(&data[PLUS_HEADER_SIZE..], out, channels, false)?;
+    let size = header.width as usize * header.height as usize * channels.to_bytes() as usize;
+    filters.revert(&mut out[..size], header.width, channels.to_bytes() as usize);
+    Ok((header, filters))
+}
+
+#[cfg(feature = "alloc")]
+pub use self::alloc_fns::*;
+
+#[cfg(feature = "alloc")]
+mod alloc_fns {
+    use alloc::vec::Vec;
+
+    use super::super::decoder::can_hold;
+    use super::super::encoder::{max_encoded_size, Encoder};
+    use super::super::error::EncodeError;
+    use super::super::header::{qoi_channels, qoi_header, QoiColorspace};
+    use super::{Filters, PLUS_HEADER_SIZE};
+
+    // decode_into a freshly allocated buffer
+    pub fn decode_to_vec(
+        data: &[u8],
+        channels: qoi_channels,
+    ) -> Result<(Vec<u8>, qoi_header, Filters), core::fmt::Error> {
+        let stream = data.get(PLUS_HEADER_SIZE..).unwrap_or(&[]);
+        let header = qoi_header::from_bytes(stream)?;
+        let pixels = header.width as usize * header.height as usize;
+        if !can_hold(stream, pixels) {
+            return Err(core::fmt::Error);
+        }
+        let mut out = alloc::vec![0; pixels * channels.to_bytes() as usize];
+        let (header, filters) = super::decode_into(data, &mut out, channels)?;
+        Ok((out, header, filters))
+    }
+
+    // filter a copy of the pixels and wrap their qoif encoding in a qoi+
+    // file
+    pub fn encode_to_vec(
+        data: &[[u8; 4]],
+        width: u32,
+        height: u32,
+        channels: qoi_channels,
+        colorspace: QoiColorspace,
+        filters: Filters,
+    ) -> Result<Vec<u8>, EncodeError> {
+        let pxs = width as usize * height as usize;
+        let mut filtered = data[..pxs.min(data.len())].to_vec();
+        filters.apply(filtered.as_flattened_mut(), width, 4);
+
+        let mut out = alloc::vec![0; PLUS_HEADER_SIZE + max_encoded_size(width, height, channels)];
+        out[..PLUS_HEADER_SIZE].copy_from_slice(&filters.to_bytes());
+        let written = Encoder::new(&filtered, width, height, channels, colorspace)
+            .encode_to_slice(&mut out[PLUS_HEADER_SIZE..])?;
+        out.truncate(PLUS_HEADER_SIZE + written);
+        Ok(out)
+    }
+
+    // size of the qoi+ file these filters give, without writing it
+    pub fn encoded_size(
+        data: &[[u8; 4]],
+        width: u32,
+        height: u32,
+        channels: qoi_channels,
+        filters: Filters,
+    ) -> Result<u64, core::fmt::Error> {
+        best_filters(data, width, height, channels, [filters]).map(|(_, size)| size)
+    }
+
+    // whichever of `candidates` gives the smallest file, and that size. the
+    // earliest wins a tie, so with Filters::all() plain qoi is kept unless a
+    // filter actually helps
+    pub fn best_filters<I: IntoIterator<Item = Filters>>(
+        data: &[[u8; 4]],
+        width: u32,
+        height: u32,
+        channels: qoi_channels,
+        candidates: I,
+    ) -> Result<(Filters, u64), core::fmt::Error> {
+        let pxs = width as usize * height as usize;
+        let data = &data[..pxs.min(data.len())];
+        let mut best: Option<(Filters, u64)> = None;
+        let mut filtered = Vec::with_capacity(data.len());
+        for filters in candidates {
+            filtered.clear();
+            filtered.extend_from_slice(data);
+            filters.apply(filtered.as_flattened_mut(), width, 4);
+            let size = Encoder::new(&filtered, width, height, channels, QoiColorspace::Srgb)
+                .stats()?
+                .total_bytes()
+                + PLUS_HEADER_SIZE as u64;
+            if best.is_none_or(|(_, b)| size < b) {
+                best = Some((filters, size));
+            }
+        }
+        best.ok_or(core::fmt::Error)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::super::header::QoiColorspace;
+    use super::super::test_util::Lcg;
+    use super::*;
+
+    // smooth enough for the predictors to have something to predict, with
+    // noise so that every wrapping path comes up
+    fn image(rng: &mut Lcg, w: u32, h: u32, alpha: bool) -> Vec<[u8; 4]> {
+        (0..w * h)
+            .map(|i| {
+                let (x, y) = ((i % w) as u8, (i / w) as u8);
+                let a = if alpha { rng.byte() } else { 255 };
+                [
+                    x.wrapping_mul(9).wrapping_add(rng.byte() % 4),
+                    y.wrapping_mul(13),
+                    rng.byte(),
+                    a,
+                ]
+            })
+            .collect()
+    }
+
+    const SIZES: [(u32, u32); 6] = [(1, 1), (1, 9), (9, 1), (7, 5), (13, 6), (2, 2)];
+
+    #[test]
+    fn apply_then_revert_gives_back_the_pixels() {
+        let mut rng = Lcg(11);
+        for (w, h) in SIZES {
+            let bytes: Vec<u8> = (0..w * h * 4).map(|_| rng.byte()).collect();
+            for filters in Filters::all() {
+                for stride in [3, 4] {
+                    let src = &bytes[..(w * h) as usize * stride];
+                    let mut data = src.to_vec();
+                    filters.apply(&mut data, w, stride);
+                    filters.revert(&mut data, w, stride);
+                    assert_eq!(data, src, "{}x{} {:?} stride {}", w, h, filters, stride);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn files_round_trip_with_every_filter() {
+        let mut rng = Lcg(12);
+        for channels in [qoi_channels::Rgb, qoi_channels::Rgba] {
+            let ch = channels.to_bytes() as usize;
+            for (w, h) in SIZES {
+                let pixels = image(&mut rng, w, h, channels == qoi_channels::Rgba);
+                let expected: Vec<u8> = pixels.iter().flat_map(|px| px[..ch].to_vec()).collect();
+                for filters in Filters::all() {
+                    let data = encode_to_vec(&pixels, w, h, channels, QoiColorspace::Srgb, filters)
+                        .unwrap();
+                    assert!(is_plus(&data));
+                    let (decoded, header, found) = decode_to_vec(&data, channels).unwrap();
+                    assert_eq!((header.width, header.height), (w, h));
+                    assert_eq!(found, filters);
+                    assert_eq!(decoded, expected, "{}x{} {:?} {}", w, h, filters, ch);
+                    assert_eq!(
+                        encoded_size(&pixels, w, h, channels, filters).unwrap(),
+                        data.len() as u64
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn header_bytes_are_checked() {
+        let filters = Filters::new(ColorTransform::YCoCgR, Predictor::Paeth);
+        let bytes = filters.to_bytes();
+        assert_eq!(Filters::from_bytes(&bytes), Ok(filters));
+        assert!(Filters::from_bytes(&bytes[..6]).is_err());
+        for (at, value) in [(0, b'x'), (4, PLUS_VERSION + 1), (5, 3), (6, 5)] {
+            let mut bad = bytes;
+            bad[at] = value;
+            assert!(
+                Filters::from_bytes(&bad).is_err(),
+                "byte {} = {}",
+                at,
+                value
+            );
+        }
+
+        assert!(is_plus(b"qoi+\x01\x00\x00"));
+        assert!(!is_plus(b"qoif"));
+        assert!(!is_plus(b"qoi"));
+    }
+}
@@ -0,0 +1,452 @@
+// palette reduction ahead of the encoder. an image with few colours hits
+// the 64 slot index over and over, so it encodes much smaller
+//
+// the palette comes from median cut, optionally refined with k-means, and
+// pixels map to their nearest palette colour, optionally with
+// floyd-steinberg dithering. all four channels take part, so alpha is
+// quantised like the rest
+use alloc::vec::Vec;
+
+use super::pixel::Pixels;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuantizeMethod {
+    #[default]
+    MedianCut,
+    // median cut followed by this many rounds of k-means
+    KMeans(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quantized {
+    pub palette: Vec<[u8; 4]>,
+    // the image with every pixel replaced by a palette colour
+    pub pixels: Vec<[u8; 4]>,
+    // summed over every channel of every pixel, against the input
+    pub squared_error: u64,
+}
+
+pub struct Quantizer {
+    colors: usize,
+    method: QuantizeMethod,
+    dither: bool,
+    spread_hashes: bool,
+}
+
+impl Quantizer {
+    // at most `colors` colours, clamped to 1..=256
+    pub fn new(colors: usize) -> Self {
+        Quantizer {
+            colors: colors.clamp(1, 256),
+            method: QuantizeMethod::default(),
+            dither: false,
+            spread_hashes: true,
+        }
+    }
+
+    pub fn method(mut self, method: QuantizeMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    // floyd-steinberg error diffusion. hides banding but breaks up runs, so
+    // the encoding gets bigger
+    pub fn dither(mut self, dither: bool) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    // nudge palette colours by a few steps so they land in distinct slots of
+    // the encoder's index and don't evict each other. on by default
+    pub fn spread_hashes(mut self, spread: bool) -> Self {
+        self.spread_hashes = spread;
+        self
+    }
+
+    // the palette alone. images that already have few enough colours keep
+    // them exactly, unless spread_hashes moves some
+    pub fn palette(&self, pixels: &[[u8; 4]]) -> Vec<[u8; 4]> {
+        let colors = histogram(pixels);
+        let mut palette = if colors.len() <= self.colors {
+            colors.iter().map(|c| c.px).collect()
+        } else {
+            let mut palette = median_cut(colors.clone(), self.colors);
+            if let QuantizeMethod::KMeans(rounds) = self.method {
+                k_means(&colors, &mut palette, rounds);
+            }
+            palette
+        };
+        if self.spread_hashes {
+            spread_hashes(&mut palette, &colors);
+        }
+        palette
+    }
+
+    // `width` is needed for dithering, pixels are row after row
+    pub fn quantize(&self, pixels: &[[u8; 4]], width: u32) -> Quantized {
+        let palette = self.palette(pixels);
+        let out = if self.dither {
+            dither(pixels, width as usize, &palette)
+        } else {
+            map_nearest(pixels, &palette)
+        };
+        let squared_error = pixels
+            .iter()
+            .zip(out.iter())
+            .map(|(a, b)| distance(*a, *b))
+            .sum();
+        Quantized {
+            palette,
+            pixels: out,
+            squared_error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Color {
+    px: [u8; 4],
+    count: u64,
+}
+
+// distinct colours with how often they occur
+fn histogram(pixels: &[[u8; 4]]) -> Vec<Color> {
+    let mut packed: Vec<u32> = pixels.iter().map(|px| u32::from_be_bytes(*px)).collect();
+    packed.sort_unstable();
+    let mut colors: Vec<Color> = Vec::new();
+    for value in packed {
+        match colors.last_mut() {
+            Some(last) if u32::from_be_bytes(last.px) == value => last.count += 1,
+            _ => colors.push(Color {
+                px: value.to_be_bytes(),
+                count: 1,
+            }),
+        }
+    }
+    colors
+}
+
+fn distance(a: [u8; 4], b: [u8; 4]) -> u64 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| {
+            let d = i64::from(*x) - i64::from(*y);
+            (d * d) as u64
+        })
+        .sum()
+}
+
+fn nearest(palette: &[[u8; 4]], px: [u8; 4]) -> usize {
+    let mut best = (u64::MAX, 0);
+    for (i, entry) in palette.iter().enumerate() {
+        let d = distance(px, *entry);
+        if d < best.0 {
+            best = (d, i);
+        }
+    }
+    best.1
+}
+
+// count weighted mean of a set of colours
+fn mean(colors: &[Color]) -> [u8; 4] {
+    let mut sum = [0u64; 4];
+    let mut total = 0u64;
+    for color in colors {
+        for (s, c) in sum.iter_mut().zip(color.px.iter()) {
+            *s += u64::from(*c) * color.count;
+        }
+        total += color.count;
+    }
+    sum.map(|s| ((s + total / 2) / total.max(1)) as u8)
+}
+
+// channel with the widest spread, and that spread
+fn widest_channel(colors: &[Color]) -> (usize, u8) {
+    let mut lo = [u8::MAX; 4];
+    let mut hi = [0u8; 4];
+    for color in colors {
+        for c in 0..4 {
+            lo[c] = lo[c].min(color.px[c]);
+            hi[c] = hi[c].max(color.px[c]);
+        }
+    }
+    (0..4)
+        .map(|c| (c, hi[c] - lo[c]))
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+// split the box with the widest channel at its count weighted median until
+// there are `n` boxes, every box gives its mean
+fn median_cut(colors: Vec<Color>, n: usize) -> Vec<[u8; 4]> {
+    let mut boxes = alloc::vec![colors];
+    while boxes.len() < n {
+        let Some((i, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| (i, widest_channel(b)))
+            .max_by_key(|(_, (_, range))| *range)
+            .map(|(i, (channel, _))| (i, channel))
+        else {
+            break;
+        };
+        let mut colors = boxes.swap_remove(i);
+        colors.sort_unstable_by_key(|c| c.px[channel]);
+        let total: u64 = colors.iter().map(|c| c.count).sum();
+        let mut seen = 0;
+        let mut split = colors.len() - 1;
+        for (j, color) in colors.iter().enumerate() {
+            seen += color.count;
+            if seen * 2 >= total {
+                split = j + 1;
+                break;
+            }
+        }
+        // both halves keep at least one colour
+        let split = split.clamp(1, colors.len() - 1);
+        let upper = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|b| mean(b)).collect()
+}
+
+// move every palette colour to the mean of the colours nearest to it
+fn k_means(colors: &[Color], palette: &mut [[u8; 4]], rounds: u32) {
+    let mut sums = alloc::vec![([0u64; 4], 0u64); palette.len()];
+    for _ in 0..rounds {
+        sums.iter_mut().for_each(|s| *s = ([0; 4], 0));
+        for color in colors {
+            let (sum, total) = &mut sums[nearest(palette, color.px)];
+            for (s, c) in sum.iter_mut().zip(color.px.iter()) {
+                *s += u64::from(*c) * color.count;
+            }
+            *total += color.count;
+        }
+        let mut moved = false;
+        for (entry, (sum, total)) in palette.iter_mut().zip(sums.iter()) {
+            // an entry nothing maps to stays where it is
+            if *total == 0 {
+                continue;
+            }
+            let next = sum.map(|s| ((s + total / 2) / total) as u8);
+            moved |= next != *entry;
+            *entry = next;
+        }
+        if !moved {
+            break;
+        }
+    }
+}
+
+// the encoder's index holds one colour per hash slot, two palette colours
+// in the same slot keep pushing each other out. the most used colours keep
+// their slot, the others move to the closest colour within a few steps
+// that hashes to a free slot, for as long as free slots are left
+fn spread_hashes(palette: &mut [[u8; 4]], colors: &[Color]) {
+    const REACH: i16 = 3;
+
+    let mut usage = alloc::vec![0u64; palette.len()];
+    for color in colors {
+        usage[nearest(palette, color.px)] += color.count;
+    }
+    let mut order: Vec<usize> = (0..palette.len()).collect();
+    order.sort_by_key(|i| core::cmp::Reverse(usage[*i]));
+
+    let hash = |px: [u8; 4]| usize::from(Pixels::from(px).hash());
+    let mut taken = [false; 64];
+    let mut free = 64;
+    for i in order {
+        let px = palette[i];
+        if !taken[hash(px)] {
+            taken[hash(px)] = true;
+            free -= 1;
+            continue;
+        }
+        if free == 0 {
+            continue;
+        }
+        // alpha stays put so opaque palettes remain opaque
+        let mut best: Option<(u64, [u8; 4])> = None;
+        for dr in -REACH..=REACH {
+            for dg in -REACH..=REACH {
+                for db in -REACH..=REACH {
+                    let step = |c: u8, d: i16| (i16::from(c) + d).clamp(0, 255) as u8;
+                    let moved = [step(px[0], dr), step(px[1], dg), step(px[2], db), px[3]];
+                    if taken[hash(moved)] {
+                        continue;
+                    }
+                    let d = distance(px, moved);
+                    if best.is_none_or(|(b, _)| d < b) {
+                        best = Some((d, moved));
+                    }
+                }
+            }
+        }
+        if let Some((_, moved)) = best {
+            palette[i] = moved;
+            taken[hash(moved)] = true;
+            free -= 1;
+        }
+    }
+}
+
+// every pixel to its nearest palette colour, looked up once per distinct
+// colour
+fn map_nearest(pixels: &[[u8; 4]], palette: &[[u8; 4]]) -> Vec<[u8; 4]> {
+    let colors = histogram(pixels);
+    let mapped: Vec<[u8; 4]> = colors
+        .iter()
+        .map(|c| palette[nearest(palette, c.px)])
+        .collect();
+    pixels
+        .iter()
+        .map(|px| {
+            let i = colors
+                .binary_search_by_key(&u32::from_be_bytes(*px), |c| u32::from_be_bytes(c.px))
+                .unwrap_or(0);
+            mapped[i]
+        })
+        .collect()
+}
+
+// floyd-steinberg, left to right on every row. errors are kept in
+// sixteenths so the diffusion stays in integers
+fn dither(pixels: &[[u8; 4]], width: usize, palette: &[[u8; 4]]) -> Vec<[u8; 4]> {
+    let width = width.max(1);
+    let mut out = Vec::with_capacity(pixels.len());
+    // one slot of padding on both sides of the row
+    let mut current = alloc::vec![[0i32; 4]; width + 2];
+    let mut next = alloc::vec![[0i32; 4]; width + 2];
+
+    for row in pixels.chunks(width) {
+        for (x, px) in row.iter().enumerate() {
+            let err = current[x + 1];
+            let wanted: [u8; 4] =
+                core::array::from_fn(|c| (i32::from(px[c]) + err[c] / 16).clamp(0, 255) as u8);
+            let chosen = palette[nearest(palette, wanted)];
+            out.push(chosen);
+            for c in 0..4 {
+                let e = i32::from(wanted[c]) - i32::from(chosen[c]);
+                current[x + 2][c] += e * 7;
+                next[x][c] += e * 3;
+                next[x + 1][c] += e * 5;
+                next[x + 2][c] += e;
+            }
+        }
+        core::mem::swap(&mut current, &mut next);
+        next.iter_mut().for_each(|e| *e = [0; 4]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::super::test_util::Lcg;
+    use super::*;
+
+    const METHODS: [QuantizeMethod; 3] = [
+        QuantizeMethod::MedianCut,
+        QuantizeMethod::KMeans(1),
+        QuantizeMethod::KMeans(4),
+    ];
+
+    // a noisy gradient with hundreds of colours and a little alpha
+    fn image(rng: &mut Lcg, w: u32, h: u32) -> Vec<[u8; 4]> {
+        (0..w * h)
+            .map(|i| {
+                let (x, y) = ((i % w) as u8, (i / w) as u8);
+                let a = if rng.byte() < 32 { 128 } else { 255 };
+                [
+                    x.wrapping_mul(8).wrapping_add(rng.byte() % 16),
+                    y.wrapping_mul(8),
+                    rng.byte(),
+                    a,
+                ]
+            })
+            .collect()
+    }
+
+    fn hash(px: [u8; 4]) -> u8 {
+        Pixels::from(px).hash()
+    }
+
+    #[test]
+    fn palette_has_at_most_the_colours_asked_for() {
+        let pixels = image(&mut Lcg(21), 24, 16);
+        for method in METHODS {
+            for colors in [1, 2, 7, 16, 64, 256] {
+                for spread in [false, true] {
+                    let quantizer = Quantizer::new(colors).method(method).spread_hashes(spread);
+                    let palette = quantizer.palette(&pixels);
+                    assert!(!palette.is_empty());
+                    assert!(palette.len() <= colors, "{:?} {}", method, colors);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn few_colours_are_kept_exactly() {
+        let colors = [
+            [10, 20, 30, 255],
+            [10, 20, 31, 255],
+            [200, 0, 5, 128],
+            [0, 0, 0, 0],
+        ];
+        let pixels: Vec<[u8; 4]> = (0..60).map(|i| colors[i * 7 % 4]).collect();
+        for method in METHODS {
+            let quantizer = Quantizer::new(4).method(method).spread_hashes(false);
+            let mut palette = quantizer.palette(&pixels);
+            palette.sort();
+            let mut expected = colors.to_vec();
+            expected.sort();
+            assert_eq!(palette, expected, "{:?}", method);
+
+            let quantized = quantizer.quantize(&pixels, 6);
+            assert_eq!(quantized.pixels, pixels);
+            assert_eq!(quantized.squared_error, 0);
+        }
+    }
+
+    #[test]
+    fn spread_palettes_use_distinct_index_slots() {
+        let pixels = image(&mut Lcg(22), 24, 16);
+        for method in METHODS {
+            for colors in [8, 32, 64] {
+                let palette = Quantizer::new(colors).method(method).palette(&pixels);
+                let mut slots: Vec<u8> = palette.iter().map(|px| hash(*px)).collect();
+                slots.sort();
+                slots.dedup();
+                assert_eq!(slots.len(), palette.len(), "{:?} {}", method, colors);
+            }
+        }
+    }
+
+    #[test]
+    fn output_only_uses_palette_colours() {
+        let pixels = image(&mut Lcg(23), 24, 16);
+        for method in METHODS {
+            for dither in [false, true] {
+                let quantized = Quantizer::new(12)
+                    .method(method)
+                    .dither(dither)
+                    .quantize(&pixels, 24);
+                assert_eq!(quantized.pixels.len(), pixels.len());
+                assert!(quantized
+                    .pixels
+                    .iter()
+                    .all(|px| quantized.palette.contains(px)));
+                let error: u64 = pixels
+                    .iter()
+                    .zip(&quantized.pixels)
+                    .map(|(a, b)| distance(*a, *b))
+                    .sum();
+                assert_eq!(quantized.squared_error, error);
+            }
+        }
+    }
+}
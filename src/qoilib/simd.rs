@@ -0,0 +1,380 @@
+// vectorised versions of the per-pixel hot paths
+//
+// every function picks the widest implementation the cpu supports at
+// runtime: AVX2, then SSE2, then the portable code in `scalar`. All of them
+// give exactly the same results as `scalar`.
+
+// hash every pixel like Pixels::hash does, `opaque` hashes them as if alpha
+// were 255. `out` must be at least as long as `pixels`
+pub fn hash_pixels(pixels: &[[u8; 4]], opaque: bool, out: &mut [u8]) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if avx2() {
+            // SAFETY: the cpu supports avx2
+            return unsafe { x86::hash_pixels_avx2(pixels, opaque, out) };
+        }
+        // SAFETY: sse2 is part of x86_64
+        return unsafe { x86::hash_pixels_sse2(pixels, opaque, out) };
+    }
+    #[allow(unreachable_code)]
+    scalar::hash_pixels(pixels, opaque, out)
+}
+
+// how many pixels at the start of `pixels` equal `px`, `opaque` ignores alpha
+pub fn run_length(pixels: &[[u8; 4]], px: [u8; 4], opaque: bool) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if avx2() {
+            // SAFETY: the cpu supports avx2
+            return unsafe { x86::run_length_avx2(pixels, px, opaque) };
+        }
+        // SAFETY: sse2 is part of x86_64
+        return unsafe { x86::run_length_sse2(pixels, px, opaque) };
+    }
+    #[allow(unreachable_code)]
+    scalar::run_length(pixels, px, opaque)
+}
+
+// repeat the pixel over `out` as RGBA, `out.len()` must be a multiple of 4
+pub fn fill_rgba(out: &mut [u8], px: [u8; 4]) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        // SAFETY: sse2 is part of x86_64
+        return unsafe { x86::fill_rgba_sse2(out, px) };
+    }
+    #[allow(unreachable_code)]
+    scalar::fill_rgba(out, px)
+}
+
+// repeat the pixel over `out` as RGB, `out.len()` must be a multiple of 3
+pub fn fill_rgb(out: &mut [u8], px: [u8; 4]) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        // SAFETY: sse2 is part of x86_64
+        return unsafe { x86::fill_rgb_sse2(out, px) };
+    }
+    #[allow(unreachable_code)]
+    scalar::fill_rgb(out, px)
+}
+
+// without std there is no runtime detection, only what the build targets
+#[cfg(target_arch = "x86_64")]
+fn avx2() -> bool {
+    #[cfg(feature = "std")]
+    return is_x86_feature_detected!("avx2");
+    #[cfg(not(feature = "std"))]
+    return cfg!(target_feature = "avx2");
+}
+
+// the reference implementations, also used for the tails the vector code
+// leaves over
+pub mod scalar {
+    use super::super::Pixels;
+
+    pub fn hash_pixels(pixels: &[[u8; 4]], opaque: bool, out: &mut [u8]) {
+        for (px, hash) in pixels.iter().zip(out.iter_mut()) {
+            let mut px = Pixels::from(*px);
+            if opaque {
+                px.a = 255;
+            }
+            *hash = px.hash();
+        }
+    }
+
+    pub fn run_length(pixels: &[[u8; 4]], px: [u8; 4], opaque: bool) -> usize {
+        let same = |p: &[u8; 4]| p[..3] == px[..3] && (opaque || p[3] == px[3]);
+        pixels.iter().take_while(|p| same(p)).count()
+    }
+
+    pub fn fill_rgba(out: &mut [u8], px: [u8; 4]) {
+        for chunk in out.chunks_exact_mut(4) {
+            chunk.copy_from_slice(&px);
+        }
+    }
+
+    pub fn fill_rgb(out: &mut [u8], px: [u8; 4]) {
+        for chunk in out.chunks_exact_mut(3) {
+            chunk.copy_from_slice(&px[..3]);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+pub mod x86 {
+    use core::arch::x86_64::*;
+
+    use super::scalar;
+
+    // weights of r, g, b, a in the hash, for two pixels unpacked to 16 bits
+    const WEIGHTS: [i16; 8] = [3, 5, 7, 11, 3, 5, 7, 11];
+    // alpha byte of a little endian pixel read as u32
+    const ALPHA: i32 = 0xff00_0000u32 as i32;
+
+    // four pixels in, their four hashes in the low 4 bytes of the result
+    #[inline(always)]
+    unsafe fn hash4_sse2(v: __m128i, weights: __m128i) -> u32 {
+        let zero = _mm_setzero_si128();
+        // [r*3 + g*5, b*7 + a*11] for pixels 0, 1 and 2, 3
+        let lo = _mm_madd_epi16(_mm_unpacklo_epi8(v, zero), weights);
+        let hi = _mm_madd_epi16(_mm_unpackhi_epi8(v, zero), weights);
+        let lo = _mm_shuffle_epi32::<0b11_01_10_00>(lo);
+        let hi = _mm_shuffle_epi32::<0b11_01_10_00>(hi);
+        let sum = _mm_add_epi32(_mm_unpacklo_epi64(lo, hi), _mm_unpackhi_epi64(lo, hi));
+        let sum = _mm_and_si128(sum, _mm_set1_epi32(63));
+        let packed = _mm_packus_epi16(_mm_packs_epi32(sum, sum), zero);
+        _mm_cvtsi128_si32(packed) as u32
+    }
+
+    /// # Safety
+    /// the cpu must support sse2
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn hash_pixels_sse2(pixels: &[[u8; 4]], opaque: bool, out: &mut [u8]) {
+        let len = pixels.len().min(out.len());
+        let weights = _mm_loadu_si128(WEIGHTS.as_ptr() as *const __m128i);
+        let alpha = _mm_set1_epi32(if opaque { ALPHA } else { 0 });
+
+        let mut i = 0;
+        while i + 4 <= len {
+            let v = _mm_loadu_si128(pixels.as_ptr().add(i) as *const __m128i);
+            let hashes = hash4_sse2(_mm_or_si128(v, alpha), weights);
+            out[i..i + 4].copy_from_slice(&hashes.to_le_bytes());
+            i += 4;
+        }
+        scalar::hash_pixels(&pixels[i..len], opaque, &mut out[i..len]);
+    }
+
+    /// # Safety
+    /// the cpu must support avx2
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn hash_pixels_avx2(pixels: &[[u8; 4]], opaque: bool, out: &mut [u8]) {
+        let len = pixels.len().min(out.len());
+        let weights =
+            _mm256_broadcastsi128_si256(_mm_loadu_si128(WEIGHTS.as_ptr() as *const __m128i));
+        let alpha = _mm256_set1_epi32(if opaque { ALPHA } else { 0 });
+        let zero = _mm256_setzero_si256();
+
+        let mut i = 0;
+        while i + 8 <= len {
+            let v = _mm256_loadu_si256(pixels.as_ptr().add(i) as *const __m256i);
+            let v = _mm256_or_si256(v, alpha);
+            // the same steps as hash4_sse2, in each 128 bit lane
+            let lo = _mm256_madd_epi16(_mm256_unpacklo_epi8(v, zero), weights);
+            let hi = _mm256_madd_epi16(_mm256_unpackhi_epi8(v, zero), weights);
+            let lo = _mm256_shuffle_epi32::<0b11_01_10_00>(lo);
+            let hi = _mm256_shuffle_epi32::<0b11_01_10_00>(hi);
+            let sum =
+                _mm256_add_epi32(_mm256_unpacklo_epi64(lo, hi), _mm256_unpackhi_epi64(lo, hi));
+            let sum = _mm256_and_si256(sum, _mm256_set1_epi32(63));
+            let packed = _mm256_packus_epi16(_mm256_packs_epi32(sum, sum), zero);
+            let first = _mm_cvtsi128_si32(_mm256_castsi256_si128(packed)) as u32;
+            let second = _mm_cvtsi128_si32(_mm256_extracti128_si256::<1>(packed)) as u32;
+            out[i..i + 4].copy_from_slice(&first.to_le_bytes());
+            out[i + 4..i + 8].copy_from_slice(&second.to_le_bytes());
+            i += 8;
+        }
+        scalar::hash_pixels(&pixels[i..len], opaque, &mut out[i..len]);
+    }
+
+    /// # Safety
+    /// the cpu must support sse2
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn run_length_sse2(pixels: &[[u8; 4]], px: [u8; 4], opaque: bool) -> usize {
+        let mask = _mm_set1_epi32(if opaque { !ALPHA } else { -1 });
+        let target = _mm_and_si128(_mm_set1_epi32(i32::from_le_bytes(px)), mask);
+
+        let mut i = 0;
+        while i + 4 <= pixels.len() {
+            let v = _mm_loadu_si128(pixels.as_ptr().add(i) as *const __m128i);
+            let eq = _mm_cmpeq_epi32(_mm_and_si128(v, mask), target);
+            let bits = _mm_movemask_ps(_mm_castsi128_ps(eq)) as u32;
+            if bits != 0b1111 {
+                return i + bits.trailing_ones() as usize;
+            }
+            i += 4;
+        }
+        i + scalar::run_length(&pixels[i..], px, opaque)
+    }
+
+    /// # Safety
+    /// the cpu must support avx2
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn run_length_avx2(pixels: &[[u8; 4]], px: [u8; 4], opaque: bool) -> usize {
+        let mask = _mm256_set1_epi32(if opaque { !ALPHA } else { -1 });
+        let target = _mm256_and_si256(_mm256_set1_epi32(i32::from_le_bytes(px)), mask);
+
+        let mut i = 0;
+        while i + 8 <= pixels.len() {
+            let v = _mm256_loadu_si256(pixels.as_ptr().add(i) as *const __m256i);
+            let eq = _mm256_cmpeq_epi32(_mm256_and_si256(v, mask), target);
+            let bits = _mm256_movemask_ps(_mm256_castsi256_ps(eq)) as u32;
+            if bits != 0xff {
+                return i + bits.trailing_ones() as usize;
+            }
+            i += 8;
+        }
+        i + scalar::run_length(&pixels[i..], px, opaque)
+    }
+
+    /// # Safety
+    /// the cpu must support sse2
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn fill_rgba_sse2(out: &mut [u8], px: [u8; 4]) {
+        let v = _mm_set1_epi32(i32::from_le_bytes(px));
+
+        let mut i = 0;
+        while i + 16 <= out.len() {
+            _mm_storeu_si128(out.as_mut_ptr().add(i) as *mut __m128i, v);
+            i += 16;
+        }
+        scalar::fill_rgba(&mut out[i..], px);
+    }
+
+    /// # Safety
+    /// the cpu must support sse2
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn fill_rgb_sse2(out: &mut [u8], px: [u8; 4]) {
+        // 16 pixels of 3 bytes line up with three 16 byte stores
+        let mut pattern = [0u8; 48];
+        scalar::fill_rgb(&mut pattern, px);
+        let a = _mm_loadu_si128(pattern.as_ptr() as *const __m128i);
+        let b = _mm_loadu_si128(pattern.as_ptr().add(16) as *const __m128i);
+        let c = _mm_loadu_si128(pattern.as_ptr().add(32) as *const __m128i);
+
+        let mut i = 0;
+        while i + 48 <= out.len() {
+            let dst = out.as_mut_ptr().add(i);
+            _mm_storeu_si128(dst as *mut __m128i, a);
+            _mm_storeu_si128(dst.add(16) as *mut __m128i, b);
+            _mm_storeu_si128(dst.add(32) as *mut __m128i, c);
+            i += 48;
+        }
+        scalar::fill_rgb(&mut out[i..], px);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::Lcg;
+    use super::*;
+
+    const LEN: usize = 100;
+
+    // pixels from a few colours that differ in one channel at most, so runs
+    // of all lengths come up and some of them only break on alpha
+    fn random_pixels(rng: &mut Lcg) -> [[u8; 4]; LEN] {
+        let mut next = || rng.byte();
+        let mut pixels = [[0; 4]; LEN];
+        let mut px = [next(), next(), next(), next()];
+        for p in pixels.iter_mut() {
+            match next() % 8 {
+                0 => px[usize::from(next() % 4)] = next(),
+                1 => px[3] ^= 1,
+                _ => {}
+            }
+            *p = px;
+        }
+        pixels
+    }
+
+    // every length from 0 to LEN at every start, so the vector loops and
+    // the tails they leave to scalar are both covered, unaligned as well
+    fn each_slice(pixels: &[[u8; 4]], mut check: impl FnMut(&[[u8; 4]])) {
+        for start in 0..LEN {
+            for end in start..=LEN.min(start + 40) {
+                check(&pixels[start..end]);
+            }
+        }
+    }
+
+    #[test]
+    fn hash_pixels_matches_scalar() {
+        let mut rng = Lcg(1);
+        for _ in 0..20 {
+            let pixels = random_pixels(&mut rng);
+            for opaque in [false, true] {
+                each_slice(&pixels, |px| {
+                    let mut expected = [0xff; LEN];
+                    scalar::hash_pixels(px, opaque, &mut expected);
+                    let mut out = [0xff; LEN];
+                    hash_pixels(px, opaque, &mut out);
+                    assert_eq!(out, expected, "{:?} opaque {}", px, opaque);
+
+                    #[cfg(target_arch = "x86_64")]
+                    {
+                        let mut out = [0xff; LEN];
+                        // SAFETY: sse2 is part of x86_64
+                        unsafe { x86::hash_pixels_sse2(px, opaque, &mut out) };
+                        assert_eq!(out, expected, "sse2 {:?} opaque {}", px, opaque);
+                        if avx2() {
+                            let mut out = [0xff; LEN];
+                            // SAFETY: the cpu supports avx2
+                            unsafe { x86::hash_pixels_avx2(px, opaque, &mut out) };
+                            assert_eq!(out, expected, "avx2 {:?} opaque {}", px, opaque);
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn run_length_matches_scalar() {
+        let mut rng = Lcg(2);
+        for _ in 0..20 {
+            let pixels = random_pixels(&mut rng);
+            for opaque in [false, true] {
+                each_slice(&pixels, |px| {
+                    let Some(&first) = px.first() else {
+                        assert_eq!(run_length(px, [0; 4], opaque), 0);
+                        return;
+                    };
+                    // the first pixel, and one that only matches it opaque
+                    for target in [first, [first[0], first[1], first[2], !first[3]]] {
+                        let expected = scalar::run_length(px, target, opaque);
+                        assert_eq!(run_length(px, target, opaque), expected);
+
+                        #[cfg(target_arch = "x86_64")]
+                        {
+                            // SAFETY: sse2 is part of x86_64
+                            let sse2 = unsafe { x86::run_length_sse2(px, target, opaque) };
+                            assert_eq!(sse2, expected, "sse2 {:?} opaque {}", px, opaque);
+                            if avx2() {
+                                // SAFETY: the cpu supports avx2
+                                let avx2 = unsafe { x86::run_length_avx2(px, target, opaque) };
+                                assert_eq!(avx2, expected, "avx2 {:?} opaque {}", px, opaque);
+                            }
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn fills_match_scalar() {
+        let mut rng = Lcg(3);
+        let pixels = random_pixels(&mut rng);
+        for (fill, reference, ch) in [
+            (
+                fill_rgba as fn(&mut [u8], [u8; 4]),
+                scalar::fill_rgba as fn(&mut [u8], [u8; 4]),
+                4,
+            ),
+            (fill_rgb, scalar::fill_rgb, 3),
+        ] {
+            for px in pixels.iter().take(8) {
+                // starts off any alignment, lengths over several vector
+                // stores and every tail they can leave
+                for start in 0..16 {
+                    for n in 0..40 {
+                        let mut expected = [0x55; LEN * 4];
+                        reference(&mut expected[start..start + n * ch], *px);
+                        let mut out = [0x55; LEN * 4];
+                        fill(&mut out[start..start + n * ch], *px);
+                        assert_eq!(out, expected, "{} channels {} pixels at {}", ch, n, start);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use super::header::{qoi_channels, qoi_header, QOI_END};
+use super::op::{QoiOp, OP_NAMES};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpStats {
+    // number of chunks
+    pub count: u64,
+    // bytes those chunks take in the stream
+    pub bytes: u64,
+    // pixels those chunks produce
+    pub pixels: u64,
+}
+
+// where the bytes of an encoded image went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodeStats {
+    pub width: u32,
+    pub height: u32,
+    pub channels: qoi_channels,
+    // indexed like op::OP_NAMES
+    pub ops: [OpStats; 6],
+    // longest stretch of repeated pixels covered by back to back RUN chunks,
+    // and the index of its first repeated pixel
+    pub longest_run: u64,
+    pub longest_run_at: u64,
+    // summed over every channel of every pixel, 0 unless
+    // Encoder::tolerance() allowed a loss
+    pub squared_error: u64,
+    current_run: u64,
+    pixel: u64,
+}
+
+impl EncodeStats {
+    pub fn new(header: &qoi_header) -> Self {
+        EncodeStats {
+            width: header.width,
+            height: header.height,
+            channels: header.channels(),
+            ops: [OpStats::default(); 6],
+            longest_run: 0,
+            longest_run_at: 0,
+            squared_error: 0,
+            current_run: 0,
+            pixel: 0,
+        }
+    }
+
+    // account for the next op of the data stream
+    pub fn record(&mut self, op: &QoiOp) {
+        let pixels = u64::from(op.pixels());
+        let stats = &mut self.ops[op.slot()];
+        stats.count += 1;
+        stats.bytes += op.size() as u64;
+        stats.pixels += pixels;
+
+        if let QoiOp::Run(_) = op {
+            self.current_run += pixels;
+            if self.current_run > self.longest_run {
+                self.longest_run = self.current_run;
+                self.longest_run_at = self.pixel + pixels - self.current_run;
+            }
+        } else {
+            self.current_run = 0;
+        }
+        self.pixel += pixels;
+    }
+
+    pub fn op(&self, name: &str) -> OpStats {
+        OP_NAMES
+            .iter()
+            .position(|n| *n == name)
+            .map_or(OpStats::default(), |i| self.ops[i])
+    }
+
+    pub fn pixels(&self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
+    }
+
+    pub fn chunks(&self) -> u64 {
+        self.ops.iter().map(|op| op.count).sum()
+    }
+
+    // header, chunks and end marker
+    pub fn total_bytes(&self) -> u64 {
+        14 + self.ops.iter().map(|op| op.bytes).sum::<u64>() + QOI_END.len() as u64
+    }
+
+    // size of the pixels without any compression
+    pub fn raw_bytes(&self) -> u64 {
+        self.pixels() * u64::from(self.channels.to_bytes())
+    }
+
+    // how often a pixel that was not part of a run was found in the index
+    pub fn index_hit_rate(&self) -> f64 {
+        let candidates = self.chunks() - self.op("RUN").count;
+        if candidates == 0 {
+            return 0.0;
+        }
+        self.op("INDEX").count as f64 / candidates as f64
+    }
+
+    pub fn bits_per_pixel(&self) -> f64 {
+        if self.pixels() == 0 {
+            return 0.0;
+        }
+        (self.total_bytes() * 8) as f64 / self.pixels() as f64
+    }
+
+    // peak signal to noise ratio over the channels the image has, infinite
+    // when nothing was lost
+    #[cfg(feature = "std")]
+    pub fn psnr(&self) -> f64 {
+        let samples = self.pixels() * u64::from(self.channels.to_bytes());
+        psnr(self.squared_error, samples)
+    }
+
+    // raw size over encoded size, higher is better
+    pub fn compression_ratio(&self) -> f64 {
+        self.raw_bytes() as f64 / self.total_bytes() as f64
+    }
+}
+
+// peak signal to noise ratio in dB of 8 bit samples, from the sum of their
+// squared errors. infinite when nothing changed
+#[cfg(feature = "std")]
+pub fn psnr(squared_error: u64, samples: u64) -> f64 {
+    if squared_error == 0 {
+        return f64::INFINITY;
+    }
+    let mse = squared_error as f64 / samples as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::io::BufWriter;
+
+    use super::super::decoder::{decode_to_vec, Decoder};
+    use super::super::encoder::Encoder;
+    use super::super::header::QoiColorspace;
+    use super::super::test_util::Lcg;
+    use super::*;
+
+    fn image(rng: &mut Lcg, len: usize) -> Vec<[u8; 4]> {
+        let mut px = [0, 0, 0, 255];
+        (0..len)
+            .map(|_| {
+                match rng.byte() % 6 {
+                    0 => px = [rng.byte(), rng.byte(), rng.byte(), 255],
+                    1 => px[0] = px[0].wrapping_add(1),
+                    2 => px[1] = px[1].wrapping_add(rng.byte() % 16),
+                    3 => px = [10, 20, 30, 255],
+                    _ => {}
+                }
+                px
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encoder_and_analyse_agree() {
+        let pixels = image(&mut Lcg(31), 30 * 20);
+        for tolerance in [0, 3] {
+            let encoder = Encoder::new(&pixels, 30, 20, qoi_channels::Rgb, QoiColorspace::Srgb)
+                .tolerance(tolerance);
+            let mut buffer = BufWriter::new(Vec::new());
+            let mut stats = encoder.encode_to_buffer_with_stats(&mut buffer).unwrap();
+            let data = buffer.into_inner().unwrap();
+            assert_eq!(stats.total_bytes(), data.len() as u64);
+            assert_eq!(encoder.stats().unwrap(), stats);
+
+            // the file alone cannot tell what was lost
+            stats.squared_error = 0;
+            assert_eq!(Decoder::new(&data[..]).analyse().unwrap(), stats);
+        }
+    }
+
+    #[test]
+    fn counts_bytes_and_rates() {
+        let header = qoi_header::new(11, 1, qoi_channels::Rgb, QoiColorspace::Srgb);
+        let mut stats = EncodeStats::new(&header);
+        for op in [
+            QoiOp::Rgb { r: 1, g: 2, b: 3 },
+            QoiOp::Diff {
+                dr: 1,
+                dg: 0,
+                db: 0,
+            },
+            QoiOp::Run(2),
+            QoiOp::Luma {
+                dg: 5,
+                dr_dg: 0,
+                db_dg: 0,
+            },
+            QoiOp::Index(4),
+            QoiOp::Run(3),
+            QoiOp::Run(2),
+        ] {
+            stats.record(&op);
+        }
+        let op = |count, bytes, pixels| OpStats {
+            count,
+            bytes,
+            pixels,
+        };
+        assert_eq!(stats.op("INDEX"), op(1, 1, 1));
+        assert_eq!(stats.op("DIFF"), op(1, 1, 1));
+        assert_eq!(stats.op("LUMA"), op(1, 2, 1));
+        assert_eq!(stats.op("RUN"), op(3, 3, 7));
+        assert_eq!(stats.op("RGB"), op(1, 4, 1));
+        assert_eq!(stats.op("RGBA"), op(0, 0, 0));
+        assert_eq!(stats.op("NOPE"), op(0, 0, 0));
+        assert_eq!(stats.chunks(), 7);
+        // the last two runs go back to back from pixel 6 on
+        assert_eq!((stats.longest_run, stats.longest_run_at), (5, 6));
+
+        assert_eq!(stats.total_bytes(), 14 + 11 + 8);
+        assert_eq!(stats.raw_bytes(), 33);
+        // one INDEX out of the four chunks that are not runs
+        assert_eq!(stats.index_hit_rate(), 0.25);
+        assert_eq!(stats.bits_per_pixel(), 24.0);
+        assert_eq!(stats.psnr(), f64::INFINITY);
+    }
+
+    #[test]
+    fn psnr_of_the_loss() {
+        assert_eq!(psnr(0, 100), f64::INFINITY);
+        // every sample one step off
+        assert!((psnr(100, 100) - 48.130_803_6).abs() < 1e-6);
+        // every sample as far off as it gets
+        assert_eq!(psnr(255 * 255 * 7, 7), 0.0);
+
+        let pixels = image(&mut Lcg(32), 16 * 16);
+        let encoder =
+            Encoder::new(&pixels, 16, 16, qoi_channels::Rgb, QoiColorspace::Srgb).tolerance(4);
+        let stats = encoder.stats().unwrap();
+        let (decoded, _) =
+            decode_to_vec(&encoder.encode_to_vec().unwrap(), qoi_channels::Rgb).unwrap();
+        let error: u64 = pixels
+            .iter()
+            .zip(decoded.chunks_exact(3))
+            .flat_map(|(a, b)| {
+                a[..3]
+                    .iter()
+                    .zip(b)
+                    .map(|(x, y)| u64::from(x.abs_diff(*y)).pow(2))
+            })
+            .sum();
+        assert!(error > 0);
+        assert_eq!(stats.squared_error, error);
+        assert_eq!(stats.psnr(), psnr(error, 16 * 16 * 3));
+    }
+}
@@ -0,0 +1,56 @@
+// fixtures the test modules share
+
+#[cfg(feature = "alloc")]
+use super::encoder::Encoder;
+#[cfg(feature = "alloc")]
+use super::header::{qoi_channels, QoiColorspace};
+
+// a whole srgb file of the image
+#[cfg(feature = "alloc")]
+pub(crate) fn encode(pixels: &[[u8; 4]], w: u32, h: u32, ch: qoi_channels) -> alloc::vec::Vec<u8> {
+    Encoder::new(pixels, w, h, ch, QoiColorspace::Srgb)
+        .encode_to_vec()
+        .unwrap()
+}
+
+// a linear congruential generator, so random test images come out the same
+// on every run
+pub(crate) struct Lcg(pub u32);
+
+impl Lcg {
+    pub(crate) fn next_u32(&mut self) -> u32 {
+        self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        self.0
+    }
+
+    pub(crate) fn byte(&mut self) -> u8 {
+        (self.next_u32() >> 16) as u8
+    }
+}
+
+// a directory of its own under the system temp dir, emptied first and
+// removed again when dropped, also when the test fails
+#[cfg(feature = "std")]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(feature = "std")]
+impl TempDir {
+    // `name` tells the tests apart, the process id separate runs
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("qoi-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn join(&self, name: &str) -> std::path::PathBuf {
+        self.0.join(name)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
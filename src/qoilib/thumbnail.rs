@@ -0,0 +1,368 @@
+// downscaled decoding: pixels are folded into the thumbnail as they come out
+// of the decoder, so only the thumbnail and one row of sums are ever held,
+// whatever the size of the image
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::decoder::decode_ops;
+use super::header::qoi_header;
+
+// how the pixels of the image are turned into those of the thumbnail
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThumbnailFilter {
+    // the average of every pixel a thumbnail pixel covers, colours weighted
+    // by their alpha
+    #[default]
+    Box,
+    // the one pixel in the middle of what a thumbnail pixel covers
+    Nearest,
+}
+
+impl ThumbnailFilter {
+    pub const ALL: [ThumbnailFilter; 2] = [ThumbnailFilter::Box, ThumbnailFilter::Nearest];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThumbnailFilter::Box => "box",
+            ThumbnailFilter::Nearest => "nearest",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    // rgba, row by row
+    pub pixels: Vec<[u8; 4]>,
+}
+
+// the largest size within max_width x max_height with the aspect ratio of
+// the image, rounded, at least 1x1. images that already fit keep their size
+pub fn thumbnail_size(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    if width == 0 || height == 0 {
+        return (0, 0);
+    }
+    let (max_width, max_height) = (max_width.max(1), max_height.max(1));
+    if width <= max_width && height <= max_height {
+        return (width, height);
+    }
+    let (w, h) = (u64::from(width), u64::from(height));
+    let (mw, mh) = (u64::from(max_width), u64::from(max_height));
+    if w * mh >= h * mw {
+        // the width is what limits
+        (max_width, ((h * mw + w / 2) / w).max(1) as u32)
+    } else {
+        (((w * mh + h / 2) / h).max(1) as u32, max_height)
+    }
+}
+
+// decode a whole file held in memory into a thumbnail of at most
+// max_width x max_height. the header is the one of the image
+pub fn decode_thumbnail(
+    data: &[u8],
+    max_width: u32,
+    max_height: u32,
+    filter: ThumbnailFilter,
+) -> Result<(Thumbnail, qoi_header), core::fmt::Error> {
+    let header = qoi_header::from_bytes(data)?;
+    let mut sampler = Sampler::new(header.width, header.height, max_width, max_height, filter);
+    decode_ops(data, |_, len, px| {
+        sampler.push(px, len);
+        Ok(())
+    })?;
+    Ok((sampler.finish(), header))
+}
+
+// takes the pixels of an image in order and builds the thumbnail. image
+// pixel x of a row goes to thumbnail column x * width / image width, rows
+// alike, so every thumbnail pixel covers a box of the same size give or
+// take one
+pub struct Sampler {
+    filter: ThumbnailFilter,
+    image_width: u64,
+    image_height: u64,
+    thumb: Thumbnail,
+    // where the next pixel of the image is
+    x: u64,
+    y: u64,
+    // the thumbnail pixel it goes to and the first image column of the
+    // next one
+    column: u64,
+    next_edge: u64,
+    row: u64,
+    // box: r * a, g * a, b * a, a and the pixel count per column of the
+    // thumbnail row being built
+    sums: Vec<[u64; 5]>,
+}
+
+impl Sampler {
+    pub fn new(
+        image_width: u32,
+        image_height: u32,
+        max_width: u32,
+        max_height: u32,
+        filter: ThumbnailFilter,
+    ) -> Self {
+        let (width, height) = thumbnail_size(image_width, image_height, max_width, max_height);
+        let sums = match filter {
+            ThumbnailFilter::Box => vec![[0; 5]; width as usize],
+            ThumbnailFilter::Nearest => Vec::new(),
+        };
+        let mut sampler = Sampler {
+            filter,
+            image_width: u64::from(image_width),
+            image_height: u64::from(image_height),
+            thumb: Thumbnail {
+                width,
+                height,
+                pixels: vec![[0; 4]; width as usize * height as usize],
+            },
+            x: 0,
+            y: 0,
+            column: 0,
+            next_edge: 0,
+            row: 0,
+            sums,
+        };
+        sampler.start_row();
+        sampler
+    }
+
+    // the next `len` pixels of the image are all `px`
+    pub fn push(&mut self, px: [u8; 4], mut len: usize) {
+        let width = u64::from(self.thumb.width);
+        while len > 0 && self.y < self.image_height {
+            // the stretch of the row that stays in the current column
+            let n = (len as u64).min(self.next_edge - self.x);
+            match self.filter {
+                ThumbnailFilter::Box => {
+                    let a = u64::from(px[3]);
+                    let sum = &mut self.sums[self.column as usize];
+                    for (s, c) in sum.iter_mut().zip(&px[..3]) {
+                        *s += u64::from(*c) * a * n;
+                    }
+                    sum[3] += a * n;
+                    sum[4] += n;
+                }
+                ThumbnailFilter::Nearest => {
+                    let center = (self.edge(self.column) + self.next_edge - 1) / 2;
+                    if self.y == self.center_row() && (self.x..self.x + n).contains(&center) {
+                        self.thumb.pixels[(self.row * width + self.column) as usize] = px;
+                    }
+                }
+            }
+            self.x += n;
+            len -= n as usize;
+
+            if self.x == self.next_edge {
+                self.column += 1;
+                self.next_edge = self.edge(self.column + 1);
+            }
+            if self.x == self.image_width {
+                self.end_row();
+            }
+        }
+    }
+
+    // the thumbnail, pixels the image never got to stay transparent black
+    pub fn finish(self) -> Thumbnail {
+        self.thumb
+    }
+
+    // the first image column of thumbnail column `column`. an empty image
+    // has an empty thumbnail and never gets here with pixels
+    fn edge(&self, column: u64) -> u64 {
+        (column * self.image_width).div_ceil(u64::from(self.thumb.width.max(1)))
+    }
+
+    // the image row in the middle of the current thumbnail row
+    fn center_row(&self) -> u64 {
+        let height = u64::from(self.thumb.height);
+        let top = (self.row * self.image_height).div_ceil(height);
+        let bottom = ((self.row + 1) * self.image_height).div_ceil(height);
+        (top + bottom - 1) / 2
+    }
+
+    fn start_row(&mut self) {
+        self.x = 0;
+        self.column = 0;
+        self.next_edge = self.edge(1);
+    }
+
+    fn end_row(&mut self) {
+        self.y += 1;
+        let height = u64::from(self.thumb.height);
+        let next_row = self.y * height / self.image_height;
+        if next_row != self.row || self.y == self.image_height {
+            if self.filter == ThumbnailFilter::Box {
+                self.flush_row();
+            }
+            self.row = next_row;
+        }
+        self.start_row();
+    }
+
+    // average the sums into the thumbnail row and clear them
+    fn flush_row(&mut self) {
+        let start = (self.row * u64::from(self.thumb.width)) as usize;
+        for (out, sum) in self.thumb.pixels[start..].iter_mut().zip(&mut self.sums) {
+            let [r, g, b, a, count] = *sum;
+            let average = |total: u64, of: u64| ((total + of / 2) / of) as u8;
+            *out = if a == 0 {
+                [0; 4]
+            } else {
+                [
+                    average(r, a),
+                    average(g, a),
+                    average(b, a),
+                    average(a, count),
+                ]
+            };
+            *sum = [0; 5];
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::super::decoder::Decoder;
+    use super::super::header::qoi_channels;
+    use super::super::test_util;
+    use super::*;
+
+    fn encode(pixels: &[[u8; 4]], w: u32, h: u32) -> Vec<u8> {
+        test_util::encode(pixels, w, h, qoi_channels::Rgba)
+    }
+
+    fn image(w: u32, h: u32) -> Vec<[u8; 4]> {
+        (0..(w * h) as usize)
+            .map(|i| {
+                [
+                    (i * 37) as u8,
+                    (i * 11 + 5) as u8,
+                    (i * 7 + 3) as u8,
+                    1 + (i * 53 % 255) as u8,
+                ]
+            })
+            .collect()
+    }
+
+    // the thumbnail worked out from the whole image: pixel x of a row goes
+    // to column x * tw / w, rows alike
+    fn reference(
+        pixels: &[[u8; 4]],
+        w: u32,
+        h: u32,
+        tw: u32,
+        th: u32,
+        filter: ThumbnailFilter,
+    ) -> Vec<[u8; 4]> {
+        let (w, h, tw, th) = (w as u64, h as u64, tw as u64, th as u64);
+        let covered =
+            |t: u64, size: u64, tsize: u64| (0..size).filter(move |&i| i * tsize / size == t);
+        let mut out = Vec::new();
+        for ty in 0..th {
+            for tx in 0..tw {
+                let xs: Vec<u64> = covered(tx, w, tw).collect();
+                let ys: Vec<u64> = covered(ty, h, th).collect();
+                out.push(match filter {
+                    ThumbnailFilter::Nearest => {
+                        let x = (xs[0] + xs[xs.len() - 1]) / 2;
+                        let y = (ys[0] + ys[ys.len() - 1]) / 2;
+                        pixels[(y * w + x) as usize]
+                    }
+                    ThumbnailFilter::Box => {
+                        let mut sum = [0u64; 4];
+                        for &y in &ys {
+                            for &x in &xs {
+                                let px = pixels[(y * w + x) as usize];
+                                let a = u64::from(px[3]);
+                                for c in 0..3 {
+                                    sum[c] += u64::from(px[c]) * a;
+                                }
+                                sum[3] += a;
+                            }
+                        }
+                        let count = (xs.len() * ys.len()) as u64;
+                        let average = |total: u64, of: u64| ((total + of / 2) / of) as u8;
+                        [
+                            average(sum[0], sum[3]),
+                            average(sum[1], sum[3]),
+                            average(sum[2], sum[3]),
+                            average(sum[3], count),
+                        ]
+                    }
+                });
+            }
+        }
+        out
+    }
+
+    // decode_thumbnail() and Decoder::decode_thumbnail() against the reference
+    fn check(w: u32, h: u32, max_width: u32, max_height: u32, size: (u32, u32)) {
+        let pixels = image(w, h);
+        let data = encode(&pixels, w, h);
+        for filter in ThumbnailFilter::ALL {
+            let expected = reference(&pixels, w, h, size.0, size.1, filter);
+            let (thumb, _) = decode_thumbnail(&data, max_width, max_height, filter).unwrap();
+            assert_eq!(
+                (thumb.width, thumb.height),
+                size,
+                "{}x{} {:?}",
+                w,
+                h,
+                filter
+            );
+            assert_eq!(thumb.pixels, expected, "{}x{} {:?}", w, h, filter);
+            let (streamed, _) = Decoder::new(&data[..])
+                .decode_thumbnail(max_width, max_height, filter)
+                .unwrap();
+            assert_eq!(streamed, thumb);
+        }
+    }
+
+    #[test]
+    fn scales_by_non_integer_factors() {
+        // 2.5 across, 7 / 3 down
+        check(10, 7, 4, 4, (4, 3));
+        check(23, 17, 5, 9, (5, 4));
+        check(9, 30, 7, 7, (2, 7));
+    }
+
+    #[test]
+    fn never_scales_up() {
+        check(5, 3, 64, 64, (5, 3));
+        check(6, 6, 6, 100, (6, 6));
+        let pixels = image(5, 3);
+        let (thumb, _) =
+            decode_thumbnail(&encode(&pixels, 5, 3), 64, 64, ThumbnailFilter::Box).unwrap();
+        assert_eq!(thumb.pixels, pixels);
+    }
+
+    #[test]
+    fn one_pixel_wide_sources() {
+        check(1, 50, 8, 8, (1, 8));
+        check(50, 1, 8, 8, (8, 1));
+        check(1, 50, 1, 1, (1, 1));
+        check(1, 1, 8, 8, (1, 1));
+    }
+
+    // a 100000x100000 image, 40 GB as rgba, through a 4x4 thumbnail: only
+    // the thumbnail and one row of sums are allocated
+    #[test]
+    fn memory_follows_the_thumbnail_not_the_image() {
+        for filter in ThumbnailFilter::ALL {
+            let mut sampler = Sampler::new(100_000, 100_000, 4, 4, filter);
+            assert_eq!(sampler.thumb.pixels.len(), 16);
+            assert!(sampler.sums.len() <= 4);
+            for _ in 0..100_000 {
+                sampler.push([10, 20, 30, 255], 100_000);
+            }
+            let thumb = sampler.finish();
+            assert_eq!((thumb.width, thumb.height), (4, 4));
+            assert!(thumb.pixels.iter().all(|px| *px == [10, 20, 30, 255]));
+        }
+    }
+}
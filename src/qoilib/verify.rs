@@ -0,0 +1,250 @@
+use std::io::BufWriter;
+
+use super::disasm::Disassembler;
+use super::encoder::Encoder;
+use super::error::QoiError;
+use super::header::{
+    qoi_channels, qoi_header, size_allowed, QoiColorspace, QOI_MAGIC, QOI_MAX_RUN,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    // None when the header itself is unusable
+    pub header: Option<qoi_header>,
+    pub errors: Vec<QoiError>,
+    // whether re-encoding gives the same bytes, None when it was not asked
+    // for or the pixels could not be decoded
+    pub canonical: Option<bool>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+// check a whole file against the spec, collecting every violation instead
+// of stopping at the first one that decoding can get past
+//
+// with `roundtrip` the decoded pixels are encoded again and the file has to
+// match the result byte for byte
+pub fn verify(data: &[u8], roundtrip: bool) -> VerifyReport {
+    let mut report = VerifyReport {
+        header: None,
+        errors: Vec::new(),
+        canonical: None,
+    };
+
+    if data.len() < 14 {
+        report.errors.push(QoiError::Truncated {
+            offset: data.len() as u64,
+        });
+        return report;
+    }
+    let magic = [data[0], data[1], data[2], data[3]];
+    if &magic != QOI_MAGIC {
+        report.errors.push(QoiError::BadMagic(magic));
+        return report;
+    }
+    let width = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    let height = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+    let pixels = u64::from(width) * u64::from(height);
+    if pixels == 0 || !size_allowed(width, height) {
+        report
+            .errors
+            .push(QoiError::BadDimensions { width, height });
+        return report;
+    }
+    // keep going with an unknown channels or colorspace byte, neither
+    // changes decoding
+    let channels = qoi_channels::try_from(data[12]).unwrap_or_else(|_| {
+        report.errors.push(QoiError::BadChannels(data[12]));
+        qoi_channels::Rgba
+    });
+    let colorspace = QoiColorspace::try_from(data[13]).unwrap_or_else(|_| {
+        report.errors.push(QoiError::BadColorspace(data[13]));
+        QoiColorspace::Srgb
+    });
+    let header = qoi_header::new(width, height, channels, colorspace);
+    report.header = Some(header);
+
+    let mut disasm = Disassembler::with_header(&data[14..], header);
+    // no more pixels than the stream could hold, even all in runs
+    let most = (data.len() as u64 - 14) * u64::from(QOI_MAX_RUN);
+    let mut decoded: Vec<[u8; 4]> = Vec::with_capacity(pixels.min(most) as usize);
+    for chunk in disasm.by_ref() {
+        let Ok(chunk) = chunk else {
+            report.errors.push(QoiError::Truncated {
+                offset: data.len() as u64,
+            });
+            return report;
+        };
+
+        let end = chunk.pixel + u64::from(chunk.op.pixels());
+        if end > pixels {
+            report.errors.push(QoiError::RunOverrun {
+                offset: chunk.offset,
+                pixels: end - pixels,
+            });
+        }
+        for _ in chunk.pixel..end.min(pixels) {
+            decoded.push(chunk.color.to_array());
+        }
+    }
+
+    // reading from a slice cannot fail
+    let end = disasm.end_marker().unwrap();
+    if !end.is_valid() {
+        report
+            .errors
+            .push(QoiError::MissingPadding { offset: end.offset });
+    }
+    if end.trailing > 0 {
+        report.errors.push(QoiError::TrailingBytes {
+            offset: end.offset + end.bytes.len() as u64,
+            count: end.trailing,
+        });
+    }
+
+    if roundtrip {
+        let mut buffer = BufWriter::new(Vec::new());
+        let encoded = Encoder::new(&decoded, width, height, channels, colorspace)
+            .encode_to_buffer(&mut buffer)
+            .ok()
+            .and_then(|_| buffer.into_inner().ok());
+
+        if let Some(encoded) = encoded {
+            let differs = encoded
+                .iter()
+                .zip(data.iter())
+                .position(|(a, b)| a != b)
+                .or_else(|| (encoded.len() != data.len()).then(|| encoded.len().min(data.len())));
+            if let Some(offset) = differs {
+                report.errors.push(QoiError::NotCanonical {
+                    offset: offset as u64,
+                });
+            }
+            report.canonical = Some(differs.is_none());
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2x2 rgb: an RGB chunk at 14, a run of 3 at 18 and the end marker at 19
+    fn valid() -> Vec<u8> {
+        let mut data = qoi_header::new(2, 2, qoi_channels::Rgb, QoiColorspace::Srgb)
+            .to_bytes()
+            .to_vec();
+        data.extend_from_slice(&[0xfe, 10, 20, 30, 0xc2]);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        data
+    }
+
+    // kind and offset of every error found
+    fn errors(data: &[u8], roundtrip: bool) -> Vec<(&'static str, u64)> {
+        verify(data, roundtrip)
+            .errors
+            .iter()
+            .map(|e| (e.kind(), e.offset()))
+            .collect()
+    }
+
+    #[test]
+    fn valid_canonical_file() {
+        let report = verify(&valid(), true);
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(report.canonical, Some(true));
+        assert_eq!(verify(&valid(), false).canonical, None);
+    }
+
+    #[test]
+    fn header_violations() {
+        let mut data = valid();
+        data[0] = b'x';
+        assert_eq!(verify(&data, false).errors, [QoiError::BadMagic(*b"xoif")]);
+        assert_eq!(errors(&data, false), [("bad_magic", 0)]);
+
+        let mut data = valid();
+        data[12] = 5;
+        let report = verify(&data, false);
+        assert_eq!(report.errors, [QoiError::BadChannels(5)]);
+        assert!(report.header.is_some());
+        assert_eq!(errors(&data, false), [("bad_channels", 12)]);
+
+        let mut data = valid();
+        data[13] = 2;
+        assert_eq!(verify(&data, false).errors, [QoiError::BadColorspace(2)]);
+        assert_eq!(errors(&data, false), [("bad_colorspace", 13)]);
+
+        for (width, height) in [(0, 2), (2, 0), (100_000, 100_000)] {
+            let mut data = valid();
+            data[4..8].copy_from_slice(&u32::to_be_bytes(width));
+            data[8..12].copy_from_slice(&u32::to_be_bytes(height));
+            let report = verify(&data, false);
+            assert_eq!(report.errors, [QoiError::BadDimensions { width, height }]);
+            assert_eq!(report.header, None);
+            assert_eq!(errors(&data, false), [("bad_dimensions", 4)]);
+        }
+
+        assert_eq!(errors(&valid()[..10], false), [("truncated", 10)]);
+    }
+
+    #[test]
+    fn run_past_the_last_pixel() {
+        let mut data = valid();
+        // a run of 5 where 3 pixels are left
+        data[18] = 0xc4;
+        assert_eq!(
+            verify(&data, false).errors,
+            [QoiError::RunOverrun {
+                offset: 18,
+                pixels: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn truncated_op() {
+        // the file ends inside the RGB chunk
+        assert_eq!(errors(&valid()[..16], false), [("truncated", 16)]);
+        assert_eq!(errors(&valid()[..14], false), [("truncated", 14)]);
+    }
+
+    #[test]
+    fn end_marker_and_trailing_bytes() {
+        let data = valid();
+        assert_eq!(errors(&data[..23], false), [("missing_padding", 19)]);
+        let mut wrong = data.clone();
+        wrong[26] = 2;
+        assert_eq!(errors(&wrong, false), [("missing_padding", 19)]);
+
+        let mut trailing = data.clone();
+        trailing.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(
+            verify(&trailing, false).errors,
+            [QoiError::TrailingBytes {
+                offset: 27,
+                count: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn valid_but_not_canonical() {
+        let mut data = valid();
+        // (1, 1, 1) is a DIFF away from the start pixel, stored as RGB
+        data[15..18].copy_from_slice(&[1, 1, 1]);
+        let report = verify(&data, false);
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(report.canonical, None);
+
+        let report = verify(&data, true);
+        assert_eq!(report.errors, [QoiError::NotCanonical { offset: 14 }]);
+        assert_eq!(report.canonical, Some(false));
+    }
+}
@@ -0,0 +1,60 @@
+use std::io::Write;
+
+// every terminal cell shows two pixel rows: the upper one as the foreground
+// of `▀` and the lower one as the background, alpha is ignored
+pub fn encode(data: &[[u8; 4]], width: u32, height: u32) -> Vec<u8> {
+    super::check_size(data, width, height);
+    let width = width as usize;
+    let height = height as usize;
+    let mut out = Vec::new();
+
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let [r, g, b, _] = data[y * width + x];
+            write!(out, "\x1b[38;2;{};{};{}m", r, g, b).unwrap();
+            if y + 1 < height {
+                let [r, g, b, _] = data[(y + 1) * width + x];
+                write!(out, "\x1b[48;2;{};{};{}m", r, g, b).unwrap();
+            } else {
+                // odd height, the last row has nothing below it
+                out.extend_from_slice(b"\x1b[49m");
+            }
+            out.extend_from_slice("▀".as_bytes());
+        }
+        out.extend_from_slice(b"\x1b[0m\n");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one column of three rows: two pixels in the first cell, the last one
+    // alone over the default background
+    #[test]
+    fn odd_height() {
+        let data = [[1, 2, 3, 255], [4, 5, 6, 255], [7, 8, 9, 0]];
+        assert_eq!(
+            String::from_utf8(encode(&data, 1, 3)).unwrap(),
+            "\x1b[38;2;1;2;3m\x1b[48;2;4;5;6m▀\x1b[0m\n\
+             \x1b[38;2;7;8;9m\x1b[49m▀\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn two_columns() {
+        let data = [
+            [10, 0, 0, 255],
+            [0, 20, 0, 255],
+            [0, 0, 30, 255],
+            [40, 40, 40, 255],
+        ];
+        assert_eq!(
+            String::from_utf8(encode(&data, 2, 2)).unwrap(),
+            "\x1b[38;2;10;0;0m\x1b[48;2;0;0;30m▀\
+             \x1b[38;2;0;20;0m\x1b[48;2;40;40;40m▀\x1b[0m\n"
+        );
+    }
+}
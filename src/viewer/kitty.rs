@@ -0,0 +1,126 @@
+use std::io::Write;
+
+// the protocol limits the base64 payload of one escape code to 4096 bytes
+const CHUNK_SIZE: usize = 4096;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// transmit and display the pixels as 32 bits RGBA with the kitty graphics
+// protocol, the payload is split over as many escape codes as needed
+pub fn encode(data: &[[u8; 4]], width: u32, height: u32) -> Vec<u8> {
+    super::check_size(data, width, height);
+    let raw: Vec<u8> = data[..width as usize * height as usize]
+        .iter()
+        .flatten()
+        .copied()
+        .collect();
+    let payload = base64(&raw);
+    let mut out = Vec::new();
+
+    let mut chunks = payload.chunks(CHUNK_SIZE).peekable();
+    let mut first = true;
+    // an empty image still needs one escape code
+    if chunks.peek().is_none() {
+        write!(
+            out,
+            "\x1b_Ga=T,f=32,s={},v={},q=2,m=0;\x1b\\",
+            width, height
+        )
+        .unwrap();
+    }
+    while let Some(chunk) = chunks.next() {
+        let more = u8::from(chunks.peek().is_some());
+        if first {
+            write!(
+                out,
+                "\x1b_Ga=T,f=32,s={},v={},q=2,m={};",
+                width, height, more
+            )
+            .unwrap();
+            first = false;
+        } else {
+            write!(out, "\x1b_Gm={};", more).unwrap();
+        }
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(b"\x1b\\");
+    }
+    out.push(b'\n');
+
+    out
+}
+
+pub fn base64(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+
+        out.push(BASE64[(n >> 18) as usize & 0x3f]);
+        out.push(BASE64[(n >> 12) as usize & 0x3f]);
+        if chunk.len() > 1 {
+            out.push(BASE64[(n >> 6) as usize & 0x3f]);
+        } else {
+            out.push(b'=');
+        }
+        if chunk.len() > 2 {
+            out.push(BASE64[n as usize & 0x3f]);
+        } else {
+            out.push(b'=');
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_pixels() {
+        let data = [[255, 0, 0, 255], [0, 0, 255, 128]];
+        assert_eq!(
+            encode(&data, 2, 1),
+            b"\x1b_Ga=T,f=32,s=2,v=1,q=2,m=0;/wAA/wAA/4A=\x1b\\\n"
+        );
+    }
+
+    #[test]
+    fn empty_image() {
+        assert_eq!(
+            encode(&[], 0, 0),
+            b"\x1b_Ga=T,f=32,s=0,v=0,q=2,m=0;\x1b\\\n"
+        );
+    }
+
+    // 769 pixels are 3076 bytes, 4104 in base64 with the padding of the
+    // last byte: one full chunk and 8 more
+    #[test]
+    fn splits_the_payload_into_chunks() {
+        let data = vec![[0, 0, 0, 0]; 769];
+        let mut expected = b"\x1b_Ga=T,f=32,s=769,v=1,q=2,m=1;".to_vec();
+        expected.extend_from_slice(&[b'A'; 4096]);
+        expected.extend_from_slice(b"\x1b\\\x1b_Gm=0;AAAAAA==\x1b\\\n");
+        assert_eq!(encode(&data, 769, 1), expected);
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), b"");
+        assert_eq!(base64(b"f"), b"Zg==");
+        assert_eq!(base64(b"fo"), b"Zm8=");
+        assert_eq!(base64(b"foo"), b"Zm9v");
+        assert_eq!(base64(b"foob"), b"Zm9vYg==");
+    }
+
+    #[test]
+    #[should_panic(expected = "3 pixels given for a 2x2 image, 4 needed")]
+    fn too_few_pixels() {
+        encode(&[[0, 0, 0, 255]; 3], 2, 2);
+    }
+}
@@ -0,0 +1,55 @@
+pub mod halfblock;
+pub mod kitty;
+pub mod sixel;
+
+use std::str::FromStr;
+
+// how decoded pixels are drawn into the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewMode {
+    // two pixels per cell with `▀`, works in every truecolor terminal
+    HalfBlock,
+    // DEC sixel graphics, colours are quantised into a palette
+    Sixel,
+    // kitty graphics protocol, raw RGBA sent in base64 chunks
+    Kitty,
+}
+
+impl FromStr for ViewMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "halfblock" => Ok(Self::HalfBlock),
+            "sixel" => Ok(Self::Sixel),
+            "kitty" => Ok(Self::Kitty),
+            _ => Err(format!(
+                "unknown view mode `{}`, expected halfblock, sixel or kitty",
+                s
+            )),
+        }
+    }
+}
+
+// turn decoded pixels into the escape sequences of the given mode
+pub fn render(mode: ViewMode, data: &[[u8; 4]], width: u32, height: u32) -> Vec<u8> {
+    match mode {
+        ViewMode::HalfBlock => halfblock::encode(data, width, height),
+        ViewMode::Sixel => sixel::encode(data, width, height),
+        ViewMode::Kitty => kitty::encode(data, width, height),
+    }
+}
+
+// every mode reads the first width * height pixels of `data`, a shorter
+// slice is a bug in the caller
+fn check_size(data: &[[u8; 4]], width: u32, height: u32) {
+    let needed = u64::from(width) * u64::from(height);
+    assert!(
+        data.len() as u64 >= needed,
+        "{} pixels given for a {}x{} image, {} needed",
+        data.len(),
+        width,
+        height,
+        needed
+    );
+}
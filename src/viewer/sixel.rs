@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+// sixel terminals usually have 256 colour registers
+const MAX_COLORS: usize = 256;
+// levels per channel of the fallback palette, 6 * 6 * 6 = 216 colours
+const CUBE_LEVELS: u32 = 6;
+// pixels less opaque than this are left transparent
+const ALPHA_THRESHOLD: u8 = 128;
+
+// draw the pixels as a DEC sixel image
+//
+// images with at most 256 distinct colours keep their exact colours, anything
+// else is quantised into a 6x6x6 colour cube. Transparent pixels are never
+// drawn so the terminal background shows through.
+pub fn encode(data: &[[u8; 4]], width: u32, height: u32) -> Vec<u8> {
+    super::check_size(data, width, height);
+    let width = width as usize;
+    let height = height as usize;
+    let (palette, indices) = quantise(&data[..width * height]);
+    let mut out = Vec::new();
+
+    // P2 = 1 keeps the unset bits transparent, "1;1 asks for square pixels
+    write!(out, "\x1bP0;1q\"1;1;{};{}", width, height).unwrap();
+    for (i, [r, g, b]) in palette.iter().enumerate() {
+        write!(
+            out,
+            "#{};2;{};{};{}",
+            i,
+            percent(*r),
+            percent(*g),
+            percent(*b)
+        )
+        .unwrap();
+    }
+
+    let mut sixels = vec![0u8; width];
+    for band in (0..height).step_by(6) {
+        let rows = 6.min(height - band);
+        if band != 0 {
+            out.push(b'-');
+        }
+
+        for color in 0..palette.len() {
+            let mut used = false;
+            for (x, sixel) in sixels.iter_mut().enumerate() {
+                *sixel = 0;
+                for row in 0..rows {
+                    if indices[(band + row) * width + x] == Some(color) {
+                        *sixel |= 1 << row;
+                        used = true;
+                    }
+                }
+            }
+            if !used {
+                continue;
+            }
+
+            write!(out, "#{}", color).unwrap();
+            // nothing to draw after the last set sixel of this colour
+            let len = sixels.iter().rposition(|s| *s != 0).map_or(0, |p| p + 1);
+            write_rle(&mut out, &sixels[..len]);
+            out.push(b'$');
+        }
+    }
+
+    out.extend_from_slice(b"\x1b\\\n");
+
+    out
+}
+
+// map every pixel to a palette entry, or None when it is transparent
+fn quantise(data: &[[u8; 4]]) -> (Vec<[u8; 3]>, Vec<Option<usize>>) {
+    let mut palette = Vec::new();
+    let mut lookup: HashMap<[u8; 3], usize> = HashMap::new();
+
+    for [r, g, b, a] in data {
+        if *a < ALPHA_THRESHOLD || lookup.contains_key(&[*r, *g, *b]) {
+            continue;
+        }
+        if palette.len() == MAX_COLORS {
+            return quantise_cube(data);
+        }
+        lookup.insert([*r, *g, *b], palette.len());
+        palette.push([*r, *g, *b]);
+    }
+
+    let indices = data
+        .iter()
+        .map(|[r, g, b, a]| (*a >= ALPHA_THRESHOLD).then(|| lookup[&[*r, *g, *b]]))
+        .collect();
+    (palette, indices)
+}
+
+fn quantise_cube(data: &[[u8; 4]]) -> (Vec<[u8; 3]>, Vec<Option<usize>>) {
+    let level = |c: u8| (u32::from(c) * (CUBE_LEVELS - 1) + 127) / 255;
+    let value = |l: u32| (l * 255 / (CUBE_LEVELS - 1)) as u8;
+
+    let mut palette = Vec::new();
+    for r in 0..CUBE_LEVELS {
+        for g in 0..CUBE_LEVELS {
+            for b in 0..CUBE_LEVELS {
+                palette.push([value(r), value(g), value(b)]);
+            }
+        }
+    }
+
+    let indices = data
+        .iter()
+        .map(|[r, g, b, a]| {
+            (*a >= ALPHA_THRESHOLD)
+                .then(|| ((level(*r) * CUBE_LEVELS + level(*g)) * CUBE_LEVELS + level(*b)) as usize)
+        })
+        .collect();
+    (palette, indices)
+}
+
+// sixel colour registers take percentages instead of bytes
+fn percent(c: u8) -> u32 {
+    (u32::from(c) * 100 + 127) / 255
+}
+
+// a sixel is sent as 63 + its six bits, `!n` repeats the next sixel n times
+fn write_rle(out: &mut Vec<u8>, sixels: &[u8]) {
+    let mut i = 0;
+    while i < sixels.len() {
+        let sixel = sixels[i];
+        let count = sixels[i..].iter().take_while(|s| **s == sixel).count();
+        if count > 3 {
+            write!(out, "!{}", count).unwrap();
+            out.push(63 + sixel);
+        } else {
+            out.extend(std::iter::repeat_n(63 + sixel, count));
+        }
+        i += count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const CLEAR: [u8; 4] = [0, 255, 0, 0];
+
+    fn sixel(data: &[[u8; 4]], width: u32, height: u32) -> String {
+        String::from_utf8(encode(data, width, height)).unwrap()
+    }
+
+    // red sets bit 0 in the first column and bit 1 in the second, blue bit
+    // 1 in the first. the transparent pixel is in no colour
+    #[test]
+    fn exact_palette_and_transparency() {
+        assert_eq!(
+            sixel(&[RED, CLEAR, BLUE, RED], 2, 2),
+            "\x1bP0;1q\"1;1;2;2#0;2;100;0;0#1;2;0;0;100#0@A$#1A$\x1b\\\n"
+        );
+    }
+
+    #[test]
+    fn run_length() {
+        assert_eq!(
+            sixel(&[RED; 5], 5, 1),
+            "\x1bP0;1q\"1;1;5;1#0;2;100;0;0#0!5@$\x1b\\\n"
+        );
+        // three repeats are shorter written out
+        assert_eq!(
+            sixel(&[RED; 3], 3, 1),
+            "\x1bP0;1q\"1;1;3;1#0;2;100;0;0#0@@@$\x1b\\\n"
+        );
+    }
+
+    // seven rows are a full band of six and one more after the `-`
+    #[test]
+    fn bands() {
+        assert_eq!(
+            sixel(&[RED; 7], 1, 7),
+            "\x1bP0;1q\"1;1;1;7#0;2;100;0;0#0~$-#0@$\x1b\\\n"
+        );
+    }
+
+    #[test]
+    fn more_than_256_colours_use_the_cube() {
+        let data: Vec<[u8; 4]> = (0..300u32)
+            .map(|i| [i as u8, (i >> 8) as u8, 0, 255])
+            .collect();
+        let out = sixel(&data, 300, 1);
+        assert!(out.starts_with("\x1bP0;1q\"1;1;300;1#0;2;0;0;0#1;2;0;0;20#"));
+        assert!(out.contains("#215;2;100;100;100#"));
+        assert!(!out.contains("#216;"));
+    }
+
+    #[test]
+    #[should_panic(expected = "3 pixels given for a 2x2 image, 4 needed")]
+    fn too_few_pixels() {
+        encode(&[[0, 0, 0, 255]; 3], 2, 2);
+    }
+}